@@ -0,0 +1,73 @@
+// Compares the enum `Value` with its NaN-boxed counterpart on the kind of
+// stack traffic the VM does: pushing numbers and objects, adding the top two
+// and cloning values back onto the stack. Run with
+// `cargo bench --bench nanbox --features nan-boxing`.
+
+use std::{
+    hint::black_box,
+    mem,
+    time::{Duration, Instant},
+};
+
+use rlox::{nanbox::NanBox, value::Value};
+
+const RUNS: u32 = 100;
+const OPS: usize = 100_000;
+
+fn main() {
+    let string = Value::from_string("a".to_string());
+
+    let enum_time = median(|| {
+        let mut stack: Vec<Value> = Vec::with_capacity(256);
+        for i in 0..OPS {
+            stack.push(Value::Number(i as f64));
+            stack.push(Value::Number(1.0));
+            let (Some(Value::Number(b)), Some(Value::Number(a))) = (stack.pop(), stack.pop())
+            else {
+                unreachable!()
+            };
+            stack.push(Value::Number(a + b));
+            stack.push(string.clone());
+            stack.push(stack[stack.len() - 2].clone());
+            stack.truncate(stack.len() - 3);
+        }
+        black_box(stack);
+    });
+
+    let string = NanBox::from(string);
+    let nanbox_time = median(|| {
+        let mut stack: Vec<NanBox> = Vec::with_capacity(256);
+        for i in 0..OPS {
+            stack.push(NanBox::from_number(i as f64));
+            stack.push(NanBox::from_number(1.0));
+            let b = stack.pop().and_then(|b| b.as_number()).unwrap();
+            let a = stack.pop().and_then(|a| a.as_number()).unwrap();
+            stack.push(NanBox::from_number(a + b));
+            stack.push(string.clone());
+            stack.push(stack[stack.len() - 2].clone());
+            stack.truncate(stack.len() - 3);
+        }
+        black_box(stack);
+    });
+
+    println!(
+        "Value:  {:2} bytes, {enum_time:?} per {OPS} steps",
+        mem::size_of::<Value>()
+    );
+    println!(
+        "NanBox: {:2} bytes, {nanbox_time:?} per {OPS} steps",
+        mem::size_of::<NanBox>()
+    );
+}
+
+fn median(mut run: impl FnMut()) -> Duration {
+    let mut times: Vec<_> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[times.len() / 2]
+}
@@ -0,0 +1,50 @@
+// Measures the fixed cost of running a tiny script, both in-process and as a
+// whole `rlox -e` invocation, and fails if the in-process overhead regresses
+// past BUDGET. Run with `cargo bench --bench startup`.
+
+use std::{
+    io,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use rlox::vm::{InterpretResult, VM};
+
+const RUNS: u32 = 1000;
+const BUDGET: Duration = Duration::from_micros(100);
+
+fn main() {
+    let in_process = median(|| {
+        let mut vm = VM::with_output(Default::default(), io::sink(), io::sink());
+        assert_eq!(vm.interpret("print 1;", None), InterpretResult::Ok);
+    });
+    let process = median(|| {
+        let status = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .args(["-e", "print 1;"])
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success());
+    });
+
+    println!("in-process: {in_process:?} per script (budget {BUDGET:?})");
+    println!("rlox -e:    {process:?} per process");
+    if in_process > BUDGET {
+        eprintln!("Startup overhead is over budget");
+        std::process::exit(1);
+    }
+}
+
+// The median is much less affected than the mean by the odd slow run on a
+// busy machine
+fn median(mut run: impl FnMut()) -> Duration {
+    let mut times: Vec<_> = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            run();
+            start.elapsed()
+        })
+        .collect();
+    times.sort();
+    times[times.len() / 2]
+}
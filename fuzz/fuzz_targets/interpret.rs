@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Compiling and running any input may fail, but must never panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(source) = std::str::from_utf8(data) {
+        let _ = rlox::vm::interpret(source, None, Default::default());
+    }
+    if rlox::bytecode::is_bytecode(data) {
+        if let Ok((chunk, _)) = rlox::bytecode::read(data) {
+            let program = rlox::program::Program::new(chunk, None);
+            let _ = rlox::vm::interpret_program(&program, Default::default());
+        }
+    }
+});
@@ -0,0 +1,62 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+// A bundled executable is the interpreter binary followed by a bytecode
+// payload and a trailer holding the payload length and this magic.
+const MAGIC: &[u8; 8] = b"RLOXBNDL";
+const TRAILER_LEN: u64 = 16;
+
+/// Appends `payload` to the interpreter executable `exe`, replacing any payload
+/// it already carries.
+pub fn build(exe: &[u8], payload: &[u8]) -> Vec<u8> {
+    let mut out = interpreter(exe).to_vec();
+    out.extend_from_slice(payload);
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(MAGIC);
+    out
+}
+
+/// Reads the payload from the end of the executable at `path`, if it has one.
+pub fn read_payload(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut f = File::open(path)?;
+    let len = f.metadata()?.len();
+    if len < TRAILER_LEN {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; TRAILER_LEN as usize];
+    f.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    f.read_exact(&mut trailer)?;
+    let Some(payload_len) = payload_len(&trailer) else {
+        return Ok(None);
+    };
+    if payload_len > len - TRAILER_LEN {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0; payload_len as usize];
+    f.seek(SeekFrom::Start(len - TRAILER_LEN - payload_len))?;
+    f.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+fn interpreter(exe: &[u8]) -> &[u8] {
+    let trailer_start = exe.len().saturating_sub(TRAILER_LEN as usize);
+    match payload_len(&exe[trailer_start..]) {
+        Some(payload_len) if payload_len as usize <= trailer_start => {
+            &exe[..trailer_start - payload_len as usize]
+        }
+        _ => exe,
+    }
+}
+
+fn payload_len(trailer: &[u8]) -> Option<u64> {
+    let (len, magic) = trailer.split_at_checked(8)?;
+    if magic != MAGIC {
+        return None;
+    }
+    Some(u64::from_le_bytes(len.try_into().ok()?))
+}
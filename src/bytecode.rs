@@ -0,0 +1,245 @@
+use std::{ops::Range, sync::Arc};
+
+use crate::{
+    chunk::Chunk,
+    value::{Function, Obj, Value},
+};
+use anyhow::{bail, Result};
+
+// Layout of a .loxb file, all integers little-endian:
+//
+//   magic "LOXB", version u8, flags u8
+//   source path (only when FLAG_DEBUG is set): u32 length, UTF-8 bytes
+//   the script's chunk, where each chunk is:
+//     code: u32 length, bytes
+//     constants: u32 count, each a tag byte followed by its payload, which
+//       for a function is its name (u32 length, UTF-8 bytes), doc (u32
+//       length, UTF-8 bytes, empty for none), arity u8, upvalue count u32
+//       and chunk
+//     docs: u32 count, each the offset u32 of the Class instruction declaring
+//       the class it documents and its text (u32 length, UTF-8 bytes)
+//     lines (only when FLAG_DEBUG is set): u32 count of runs, each a line u32
+//       and how many consecutive code bytes are on it u32
+//     spans (only when FLAG_DEBUG is set): u32 count of runs, each a source
+//       byte range as start u32 and end u32 (both u32::MAX for none) and how
+//       many consecutive code bytes it covers u32
+const MAGIC: &[u8; 4] = b"LOXB";
+pub const VERSION: u8 = 20;
+
+const FLAG_DEBUG: u8 = 1;
+
+const NO_SPAN: Range<usize> = u32::MAX as usize..u32::MAX as usize;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_FUNCTION: u8 = 5;
+
+// The compiler's limit, since upvalues are indexed by a byte
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+// Bounds recursion through nested function constants in untrusted files
+const MAX_FUNCTION_DEPTH: usize = 64;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Serializes `chunk`. Passing no source path strips the debug section, after
+/// which runtime errors can no longer report line numbers.
+pub fn write(chunk: &Chunk, source_path: Option<&str>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.push(if source_path.is_some() { FLAG_DEBUG } else { 0 });
+    if let Some(path) = source_path {
+        write_bytes(&mut out, path.as_bytes());
+    }
+    write_chunk(&mut out, chunk, source_path.is_some());
+    out
+}
+
+fn write_chunk(out: &mut Vec<u8>, chunk: &Chunk, debug: bool) {
+    write_bytes(out, &chunk.code);
+    write_u32(out, chunk.constants.len() as u32);
+    for constant in &chunk.constants {
+        match constant {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Bool(false) => out.push(TAG_FALSE),
+            Value::Bool(true) => out.push(TAG_TRUE),
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Obj(o) => match o.as_ref() {
+                Obj::String(s) => {
+                    out.push(TAG_STRING);
+                    write_bytes(out, s.as_bytes());
+                }
+                Obj::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    let name = function.name.as_deref().unwrap_or_default();
+                    write_bytes(out, name.as_bytes());
+                    let doc = function.doc.as_deref().unwrap_or_default();
+                    write_bytes(out, doc.as_bytes());
+                    out.push(function.arity as u8);
+                    write_u32(out, function.upvalue_count as u32);
+                    write_chunk(out, &function.chunk, debug);
+                }
+                // Natives only exist as globals the VM defines, and the rest
+                // are only created by running code, since bytes have no
+                // literal
+                Obj::Bytes(_)
+                | Obj::Native(_)
+                | Obj::Closure(_)
+                | Obj::Class(_)
+                | Obj::Instance(_)
+                | Obj::BoundMethod(_) => unreachable!("runtime object in constants"),
+            },
+        }
+    }
+    write_u32(out, chunk.docs.len() as u32);
+    for (offset, doc) in &chunk.docs {
+        write_u32(out, *offset as u32);
+        write_bytes(out, doc.as_bytes());
+    }
+
+    if debug {
+        write_u32(out, chunk.lines.runs().count() as u32);
+        for (&line, count) in chunk.lines.runs() {
+            write_u32(out, line);
+            write_u32(out, count as u32);
+        }
+        write_u32(out, chunk.spans.runs().count() as u32);
+        for (span, count) in chunk.spans.runs() {
+            let span = span.clone().unwrap_or(NO_SPAN);
+            write_u32(out, span.start as u32);
+            write_u32(out, span.end as u32);
+            write_u32(out, count as u32);
+        }
+    }
+}
+
+/// Deserializes a chunk along with the source path from its debug section, if
+/// it has one.
+pub fn read(bytes: &[u8]) -> Result<(Chunk, Option<String>)> {
+    let mut reader = Reader { bytes, offset: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("Not a bytecode file");
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        bail!("Unsupported bytecode version {version}");
+    }
+    let flags = reader.u8()?;
+
+    let debug = flags & FLAG_DEBUG != 0;
+    let mut source_path = None;
+    if debug {
+        source_path = Some(String::from_utf8(reader.bytes()?.to_vec())?);
+    }
+    let chunk = reader.chunk(debug, 0)?;
+    chunk.verify()?;
+    Ok((chunk, source_path))
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        match self.bytes.get(self.offset..self.offset + len) {
+            Some(bytes) => {
+                self.offset += len;
+                Ok(bytes)
+            }
+            None => bail!("Unexpected end of bytecode"),
+        }
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn chunk(&mut self, debug: bool, depth: usize) -> Result<Chunk> {
+        let mut chunk = Chunk::new();
+        chunk.code = self.bytes()?.to_vec();
+        for _ in 0..self.u32()? {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Bool(false),
+                TAG_TRUE => Value::Bool(true),
+                TAG_NUMBER => {
+                    let bytes = self.take(8)?.try_into()?;
+                    Value::Number(f64::from_le_bytes(bytes))
+                }
+                TAG_STRING => {
+                    let s = String::from_utf8(self.bytes()?.to_vec())?;
+                    Value::from_string(s)
+                }
+                TAG_FUNCTION if depth == MAX_FUNCTION_DEPTH => bail!("Functions nest too deeply"),
+                TAG_FUNCTION => {
+                    let name = String::from_utf8(self.bytes()?.to_vec())?;
+                    let doc = String::from_utf8(self.bytes()?.to_vec())?;
+                    let arity = self.u8()? as usize;
+                    let upvalue_count = self.u32()? as usize;
+                    if upvalue_count > MAX_UPVALUES {
+                        bail!("Function {name} captures too many variables");
+                    }
+                    let chunk = self.chunk(debug, depth + 1)?;
+                    Value::from_function(Arc::new(Function {
+                        arity,
+                        upvalue_count,
+                        chunk,
+                        name: Some(name),
+                        doc: (!doc.is_empty()).then_some(doc),
+                    }))
+                }
+                tag => bail!("Unknown constant tag {tag}"),
+            };
+            chunk.constants.push(constant);
+        }
+        for _ in 0..self.u32()? {
+            let offset = self.u32()? as usize;
+            if offset >= chunk.code.len() {
+                bail!("Doc comment for offset {offset} past the end of the code");
+            }
+            let doc = String::from_utf8(self.bytes()?.to_vec())?;
+            chunk.add_doc(offset, doc);
+        }
+
+        if debug {
+            for _ in 0..self.u32()? {
+                let line = self.u32()?;
+                chunk.lines.push(line, self.u32()? as usize);
+            }
+            for _ in 0..self.u32()? {
+                let span = self.u32()? as usize..self.u32()? as usize;
+                let span = (span != NO_SPAN).then_some(span);
+                chunk.spans.push(span, self.u32()? as usize);
+            }
+        }
+        Ok(chunk)
+    }
+}
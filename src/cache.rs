@@ -0,0 +1,75 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{bytecode, compiler, include::SourceMap, program::Program};
+
+/// Where compiled programs are cached, `$XDG_CACHE_HOME/rlox` or else
+/// `~/.cache/rlox`.
+pub fn dir() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(cache) if !cache.is_empty() => Some(Path::new(&cache).join("rlox")),
+        _ => Some(Path::new(&env::var_os("HOME")?).join(".cache").join("rlox")),
+    }
+}
+
+/// Identifies the program compiled from `source`, with its includes already
+/// expanded, by this version of rlox and its compiler. Programs with includes
+/// keep their source map in the cache too, so it's part of the key.
+pub fn key(source: &str, source_map: Option<&SourceMap>) -> String {
+    let source_map = source_map.map(SourceMap::to_string);
+    let parts: [&[u8]; 5] = [
+        env!("CARGO_PKG_VERSION").as_bytes(),
+        &compiler::VERSION.to_le_bytes(),
+        &[bytecode::VERSION],
+        source.as_bytes(),
+        source_map.as_deref().unwrap_or_default().as_bytes(),
+    ];
+    // Each part's length comes first so that bytes can't move between them
+    let hash = parts.iter().fold(FNV_OFFSET_BASIS, |hash, part| {
+        fnv1a(fnv1a(hash, &(part.len() as u64).to_le_bytes()), part)
+    });
+    format!("{hash:016x}")
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+// The key has to be the same from one build of rlox to the next, which
+// std's hashers don't promise, so it's FNV-1a
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Reads the program cached under `key`. Anything wrong with the entry is
+/// treated as it not being cached, so it gets compiled and stored again.
+pub fn load(dir: &Path, key: &str) -> Option<Program> {
+    let bytes = fs::read(dir.join(format!("{key}.loxb"))).ok()?;
+    let (chunk, _) = bytecode::read(&bytes).ok()?;
+    let source_map = match fs::read_to_string(dir.join(format!("{key}.map"))) {
+        Ok(text) => Some(SourceMap::parse(key, &text).ok()?),
+        Err(_) => None,
+    };
+    Some(Program::new(chunk, source_map))
+}
+
+pub fn store(dir: &Path, key: &str, path: &str, program: &Program) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    if let Some(source_map) = &program.source_map {
+        fs::write(dir.join(format!("{key}.map")), source_map.to_string())?;
+    }
+    fs::write(
+        dir.join(format!("{key}.loxb")),
+        bytecode::write(program.chunk(), Some(path)),
+    )
+}
+
+/// Deletes every cached program.
+pub fn clear(dir: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -1,22 +1,74 @@
-use crate::value::Value;
-use anyhow::{bail, Error, Result};
+use crate::{
+    number,
+    value::{Function, Value},
+};
+use anyhow::{anyhow, bail, Error, Result};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    ops::Range,
+    sync::Arc,
+};
+
+/// How many constants a chunk can have, which is as many as a `ConstantLong`
+/// can index.
+pub const MAX_LONG_CONSTANTS: usize = 1 << 24;
 
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     Nil,
     True,
     False,
     Equal,
     Greater,
     Less,
+    Is,
     Add,
     Subtract,
     Multiply,
     Divide,
     Not,
     Negate,
+    Pop,
+    Dup,
+    Swap,
+    DefineGlobal,
+    DefineGlobalLong,
+    GetGlobal,
+    GetGlobalLong,
+    SetGlobal,
+    SetGlobalLong,
+    GetLocal,
+    SetLocal,
+    GetUpvalue,
+    SetUpvalue,
+    GetProperty,
+    GetPropertyLong,
+    SetProperty,
+    SetPropertyLong,
+    GetIndex,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    PushHandler,
+    PopHandler,
+    Throw,
+    Call,
+    Closure,
+    ClosureLong,
+    CloseUpvalue,
+    Class,
+    ClassLong,
+    Method,
+    MethodLong,
+    Import,
+    ImportLong,
+    ImportAll,
+    Print,
     Return,
 }
 
@@ -31,24 +83,211 @@ impl TryFrom<u8> for OpCode {
     }
 }
 
+impl OpCode {
+    // Closure is followed by a pair of bytes per upvalue too, see
+    // `Chunk::operand_len`
+    pub(crate) fn operand_len(self) -> usize {
+        match self {
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::ClosureLong
+            | OpCode::ClassLong
+            | OpCode::MethodLong
+            | OpCode::ImportLong => 3,
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Call
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Import => 1,
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler => 2,
+            _ => 0,
+        }
+    }
+
+    /// The form of an instruction taking a constant that takes a 24-bit
+    /// index instead of a byte, for constants past the first 256.
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::Import => Some(OpCode::ImportLong),
+            _ => None,
+        }
+    }
+
+    /// The form of an instruction taking a byte index, for one of the long
+    /// forms.
+    pub fn short(self) -> OpCode {
+        match self {
+            OpCode::ConstantLong => OpCode::Constant,
+            OpCode::DefineGlobalLong => OpCode::DefineGlobal,
+            OpCode::GetGlobalLong => OpCode::GetGlobal,
+            OpCode::SetGlobalLong => OpCode::SetGlobal,
+            OpCode::GetPropertyLong => OpCode::GetProperty,
+            OpCode::SetPropertyLong => OpCode::SetProperty,
+            OpCode::ClosureLong => OpCode::Closure,
+            OpCode::ClassLong => OpCode::Class,
+            OpCode::MethodLong => OpCode::Method,
+            OpCode::ImportLong => OpCode::Import,
+            op_code => op_code,
+        }
+    }
+
+    fn takes_constant(self) -> bool {
+        self.short().long().is_some()
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
 pub struct Chunk {
     pub code: Vec<u8>,
-    pub lines: Vec<u32>,
+    // The source line of each byte of code
+    pub(crate) lines: Runs<u32>,
+    // The byte range of the source each byte of code was compiled from, for
+    // the bytes that have one
+    pub(crate) spans: Runs<Option<Range<usize>>>,
+    // The doc comment or docstring of each class declaration that had one,
+    // by the offset of the Class instruction creating the class. Functions
+    // keep their own.
+    pub(crate) docs: Vec<(usize, String)>,
     pub constants: Vec<Value>,
 }
 
+// Debug information for each byte of code. A line or an expression usually
+// compiles to several bytes, so it's stored as runs of bytes with the same
+// value.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Runs<T> {
+    // Each run's value and the offset just past its last byte
+    runs: Vec<(T, usize)>,
+}
+
+impl<T: Clone + PartialEq> Runs<T> {
+    /// Adds `count` more bytes with `value`.
+    pub(crate) fn push(&mut self, value: T, count: usize) {
+        let end = self.len() + count;
+        match self.runs.last_mut() {
+            Some((last, last_end)) if *last == value => *last_end = end,
+            _ if count == 0 => (),
+            _ => self.runs.push((value, end)),
+        }
+    }
+
+    fn get(&self, offset: usize) -> Option<&T> {
+        let run = self.runs.partition_point(|(_, end)| *end <= offset);
+        self.runs.get(run).map(|(value, _)| value)
+    }
+
+    /// Each value and how many consecutive bytes have it.
+    pub(crate) fn runs(&self) -> impl Iterator<Item = (&T, usize)> + '_ {
+        let starts = [0].into_iter().chain(self.runs.iter().map(|(_, end)| *end));
+        self.runs
+            .iter()
+            .zip(starts)
+            .map(|((value, end), start)| (value, end - start))
+    }
+
+    fn len(&self) -> usize {
+        self.runs.last().map_or(0, |(_, end)| *end)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        // Keeps the run holding the last byte kept, cut short
+        let keep = self.runs.partition_point(|(_, end)| *end < len);
+        self.runs.truncate(keep + 1);
+        if len == 0 {
+            self.runs.clear();
+        } else if let Some((_, end)) = self.runs.last_mut() {
+            *end = (*end).min(len);
+        }
+    }
+}
+
+// Derived, it would need `T: Default`
+impl<T> Default for Runs<T> {
+    fn default() -> Self {
+        Runs { runs: vec![] }
+    }
+}
+
 impl Chunk {
     pub fn new() -> Chunk {
         Chunk {
             code: vec![],
-            lines: vec![],
+            lines: Runs::default(),
+            spans: Runs::default(),
+            docs: vec![],
             constants: vec![],
         }
     }
 
     pub fn write(&mut self, byte: u8, line: u32) {
         self.code.push(byte);
-        self.lines.push(line);
+        self.lines.push(line, 1);
+        self.spans.push(None, 1);
+    }
+
+    /// Writes a byte compiled from the `span` of the source.
+    pub fn write_spanned(&mut self, byte: u8, line: u32, span: Range<usize>) {
+        self.code.push(byte);
+        self.lines.push(line, 1);
+        self.spans.push(Some(span), 1);
+    }
+
+    /// The source line the byte at `offset` was compiled from. Chunks loaded
+    /// from stripped bytecode have no line information.
+    pub fn get_line(&self, offset: usize) -> Option<u32> {
+        self.lines.get(offset).copied()
+    }
+
+    /// The byte range of the source the byte at `offset` was compiled from,
+    /// if the compiler recorded one.
+    pub fn get_span(&self, offset: usize) -> Option<Range<usize>> {
+        self.spans.get(offset).cloned().flatten()
+    }
+
+    /// Records `doc` as the doc comment of what the instruction at `offset`
+    /// declares.
+    pub fn add_doc(&mut self, offset: usize, doc: String) {
+        self.docs.push((offset, doc));
+    }
+
+    /// The doc comment of what the instruction at `offset` declares, if it
+    /// had one.
+    pub fn get_doc(&self, offset: usize) -> Option<&str> {
+        self.docs
+            .iter()
+            .find(|(at, _)| *at == offset)
+            .map(|(_, doc)| doc.as_str())
+    }
+
+    /// Drops the code from `len` on, along with its debug information.
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.spans.truncate(len);
+        self.docs.retain(|(offset, _)| *offset < len);
     }
 
     pub fn add_constant(&mut self, value: Value) -> Result<u8> {
@@ -56,53 +295,835 @@ impl Chunk {
         Ok(<usize as TryInto<u8>>::try_into(self.constants.len())? - 1)
     }
 
+    /// Adds a constant to be loaded with `Constant`, or with `ConstantLong`
+    /// once the index doesn't fit in a byte.
+    pub fn add_long_constant(&mut self, value: Value) -> Result<usize> {
+        if self.constants.len() >= MAX_LONG_CONSTANTS {
+            bail!("Too many constants");
+        }
+        self.constants.push(value);
+        Ok(self.constants.len() - 1)
+    }
+
+    /// Checks that the code only holds known instructions with in-bounds
+    /// operands and jumps, never pops an empty stack and can't run off its
+    /// end, so that chunks which didn't come from the compiler are safe to run.
+    /// The chunks of any functions in the constants are checked too.
+    pub fn verify(&self) -> Result<()> {
+        // Slot 0 holds the script itself
+        self.verify_frame(1, 0)
+    }
+
+    // `slots` is how many values are on the frame's stack when it starts, and
+    // `upvalues` how many variables its closure captures
+    fn verify_frame(&self, slots: usize, upvalues: usize) -> Result<()> {
+        for function in self.constants.iter().filter_map(Value::as_function) {
+            function
+                .chunk
+                .verify_frame(function.arity + 1, function.upvalue_count)
+                .map_err(|e| anyhow!("In {function}: {e}"))?;
+        }
+
+        // Decode every instruction first so jumps can be checked to land on one
+        let mut starts = vec![false; self.code.len()];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let op_code: OpCode = self.code[offset].try_into()?;
+            starts[offset] = true;
+            offset += 1 + self.operand_len(op_code, offset);
+        }
+        if offset > self.code.len() {
+            bail!("Missing operand at end of chunk");
+        }
+
+        // Then follow every path through the code, checking that the stack
+        // depth agrees wherever paths meet
+        let mut depths = vec![None; self.code.len()];
+        let mut pending: Vec<(usize, usize)> = vec![(0, slots)];
+        while let Some((offset, depth)) = pending.pop() {
+            if offset >= self.code.len() {
+                bail!("Execution runs past the end of the chunk");
+            }
+            if !starts[offset] {
+                bail!("Jump into the middle of an instruction at {offset}");
+            }
+            match depths[offset] {
+                Some(d) if d == depth => continue,
+                Some(_) => bail!("Inconsistent stack depth at {offset}"),
+                None => depths[offset] = Some(depth),
+            }
+
+            let op_code: OpCode = self.code[offset].try_into()?;
+            if let Some(index) = self.constant_index(op_code, offset) {
+                let named = !matches!(op_code.short(), OpCode::Constant | OpCode::Closure);
+                match self.constants.get(index) {
+                    None => bail!("Constant {index} out of range at {offset}"),
+                    Some(name) if named && !name.is_string() => {
+                        bail!("Name {index} isn't a string at {offset}")
+                    }
+                    Some(_) => (),
+                }
+            }
+            if let OpCode::GetLocal | OpCode::SetLocal = op_code {
+                let slot = self.code[offset + 1];
+                if slot as usize >= depth {
+                    bail!("Local slot {slot} out of range at {offset}");
+                }
+            }
+            if let OpCode::GetUpvalue | OpCode::SetUpvalue = op_code {
+                let slot = self.code[offset + 1];
+                if slot as usize >= upvalues {
+                    bail!("Upvalue {slot} out of range at {offset}");
+                }
+            }
+            if let OpCode::Closure | OpCode::ClosureLong = op_code {
+                self.verify_closure(op_code, offset, depth, upvalues)?;
+            }
+            let (pops, pushes) = match op_code {
+                OpCode::Constant
+                | OpCode::ConstantLong
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::GetGlobal
+                | OpCode::GetGlobalLong
+                | OpCode::GetLocal
+                | OpCode::GetUpvalue
+                | OpCode::Closure
+                | OpCode::ClosureLong
+                | OpCode::Class
+                | OpCode::ClassLong
+                | OpCode::Import
+                | OpCode::ImportLong => (0, 1),
+                OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::Is
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::SetProperty
+                | OpCode::SetPropertyLong
+                | OpCode::GetIndex => (2, 1),
+                // Pops the method and leaves its class
+                OpCode::Method | OpCode::MethodLong => (2, 1),
+                OpCode::Not
+                | OpCode::Negate
+                | OpCode::SetGlobal
+                | OpCode::SetGlobalLong
+                | OpCode::SetLocal
+                | OpCode::SetUpvalue
+                | OpCode::GetProperty
+                | OpCode::GetPropertyLong => (1, 1),
+                OpCode::Pop
+                | OpCode::CloseUpvalue
+                | OpCode::Print
+                | OpCode::DefineGlobal
+                | OpCode::DefineGlobalLong
+                | OpCode::ImportAll
+                | OpCode::Throw
+                | OpCode::Return => (1, 0),
+                OpCode::Call => (self.code[offset + 1] as usize + 1, 1),
+                OpCode::Dup => (1, 2),
+                OpCode::Swap => (2, 2),
+                OpCode::Jump | OpCode::Loop | OpCode::PushHandler | OpCode::PopHandler => (0, 0),
+                OpCode::JumpIfFalse => (1, 1),
+            };
+            let depth = match depth.checked_sub(pops) {
+                Some(depth) => depth + pushes,
+                None => bail!("Stack underflow at {offset}"),
+            };
+
+            let next = offset + 1 + self.operand_len(op_code, offset);
+            match op_code {
+                OpCode::Return | OpCode::Throw => (),
+                // The handler starts with the exception pushed
+                OpCode::PushHandler => {
+                    pending.push((next, depth));
+                    pending.push((next + self.read_short(offset + 1), depth + 1));
+                }
+                OpCode::Jump => pending.push((next + self.read_short(offset + 1), depth)),
+                OpCode::Loop => match next.checked_sub(self.read_short(offset + 1)) {
+                    Some(target) => pending.push((target, depth)),
+                    None => bail!("Loop before the start of the chunk at {offset}"),
+                },
+                OpCode::JumpIfFalse => {
+                    pending.push((next, depth));
+                    pending.push((next + self.read_short(offset + 1), depth));
+                }
+                _ => pending.push((next, depth)),
+            }
+        }
+        Ok(())
+    }
+
+    // Each captured variable is an is-local flag and then either a stack slot
+    // in the enclosing frame or one of the enclosing closure's upvalues. A
+    // local function captures itself from the slot the closure is pushed to.
+    fn verify_closure(
+        &self,
+        op_code: OpCode,
+        offset: usize,
+        depth: usize,
+        upvalues: usize,
+    ) -> Result<()> {
+        let index = self.constant_index(op_code, offset).unwrap();
+        let Some(function) = self.constants.get(index).and_then(Value::as_function) else {
+            bail!("Constant {index} isn't a function at {offset}");
+        };
+        let captures = offset + 1 + op_code.operand_len();
+        for i in 0..function.upvalue_count {
+            let is_local = self.code[captures + i * 2];
+            let index = self.code[captures + 1 + i * 2] as usize;
+            match is_local {
+                1 if index > depth => bail!("Local slot {index} out of range at {offset}"),
+                0 if index >= upvalues => bail!("Upvalue {index} out of range at {offset}"),
+                0 | 1 => (),
+                _ => bail!("Invalid upvalue flag {is_local} at {offset}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Each instruction's offset and op code, stopping at the first invalid
+    /// one.
+    pub(crate) fn op_codes(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            let start = offset;
+            let op_code = OpCode::try_from(*self.code.get(start)?).ok()?;
+            offset += 1 + self.operand_len(op_code, start);
+            Some((start, op_code))
+        })
+    }
+
+    // Unlike the other instructions, how many operands Closure has depends on
+    // its function, which is treated as capturing nothing when the constant
+    // isn't a function
+    fn operand_len(&self, op_code: OpCode, offset: usize) -> usize {
+        let upvalues = match op_code {
+            OpCode::Closure | OpCode::ClosureLong
+                if offset + op_code.operand_len() < self.code.len() =>
+            {
+                self.constant_index(op_code, offset)
+                    .and_then(|index| self.constants.get(index))
+                    .and_then(Value::as_function)
+                    .map_or(0, |function| function.upvalue_count)
+            }
+            _ => 0,
+        };
+        op_code.operand_len() + upvalues * 2
+    }
+
+    /// The index of the constant the instruction at `offset` takes, if it
+    /// takes one.
+    pub(crate) fn constant_index(&self, op_code: OpCode, offset: usize) -> Option<usize> {
+        match op_code.operand_len() {
+            _ if !op_code.takes_constant() => None,
+            3 => Some(self.read_long(offset + 1)),
+            _ => Some(self.code[offset + 1] as usize),
+        }
+    }
+
+    pub fn read_short(&self, offset: usize) -> usize {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]]) as usize
+    }
+
+    /// Reads the 24-bit operand of a `ConstantLong` or another long form.
+    pub fn read_long(&self, offset: usize) -> usize {
+        u32::from_be_bytes([
+            0,
+            self.code[offset],
+            self.code[offset + 1],
+            self.code[offset + 2],
+        ]) as usize
+    }
+
     pub fn disassemble(&self, name: &str) {
-        println!("== {name} ==");
+        print!("{}", self.listing(name));
+    }
+
+    /// Renders the chunk in the disassembler's format, which `assemble` can
+    /// parse back.
+    pub fn listing(&self, name: &str) -> String {
+        let mut out = format!("== {name} ==\n");
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.write_instruction(&mut out, offset);
         }
+        out
     }
 
     pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{offset:4} ");
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
-        } else {
-            print!("{:4} ", self.lines[offset]);
+        let mut out = String::new();
+        let next = self.write_instruction(&mut out, offset);
+        print!("{out}");
+        next
+    }
+
+    fn write_instruction(&self, out: &mut String, offset: usize) -> usize {
+        write!(out, "{offset:4} ").unwrap();
+        match self.get_line(offset) {
+            None => out.push_str("   ? "),
+            Some(line) if offset > 0 && self.get_line(offset - 1) == Some(line) => {
+                out.push_str("   | ")
+            }
+            Some(line) => write!(out, "{line:4} ").unwrap(),
         }
+        self.write_op(out, offset)
+    }
+
+    fn write_op(&self, out: &mut String, offset: usize) -> usize {
         let instruction = self.code[offset];
         let op_code: Result<OpCode> = instruction.try_into();
         match op_code {
-            Ok(OpCode::Constant) => self.constant_instruction("Constant", offset),
-            Ok(OpCode::Nil) => self.simple_instruction("Nil", offset),
-            Ok(OpCode::True) => self.simple_instruction("True", offset),
-            Ok(OpCode::False) => self.simple_instruction("False", offset),
-            Ok(OpCode::Equal) => self.simple_instruction("Equal", offset),
-            Ok(OpCode::Greater) => self.simple_instruction("Greater", offset),
-            Ok(OpCode::Less) => self.simple_instruction("Less", offset),
-            Ok(OpCode::Add) => self.simple_instruction("Add", offset),
-            Ok(OpCode::Subtract) => self.simple_instruction("Subtract", offset),
-            Ok(OpCode::Multiply) => self.simple_instruction("Multiply", offset),
-            Ok(OpCode::Divide) => self.simple_instruction("Divide", offset),
-            Ok(OpCode::Not) => self.simple_instruction("Not", offset),
-            Ok(OpCode::Negate) => self.simple_instruction("Negate", offset),
-            Ok(OpCode::Return) => self.simple_instruction("Return", offset),
+            Ok(OpCode::Constant) => self.constant_instruction(out, "Constant", offset),
+            Ok(OpCode::ConstantLong) => self.constant_instruction(out, "ConstantLong", offset),
+            Ok(OpCode::Nil) => self.simple_instruction(out, "Nil", offset),
+            Ok(OpCode::True) => self.simple_instruction(out, "True", offset),
+            Ok(OpCode::False) => self.simple_instruction(out, "False", offset),
+            Ok(OpCode::Equal) => self.simple_instruction(out, "Equal", offset),
+            Ok(OpCode::Greater) => self.simple_instruction(out, "Greater", offset),
+            Ok(OpCode::Less) => self.simple_instruction(out, "Less", offset),
+            Ok(OpCode::Is) => self.simple_instruction(out, "Is", offset),
+            Ok(OpCode::Add) => self.simple_instruction(out, "Add", offset),
+            Ok(OpCode::Subtract) => self.simple_instruction(out, "Subtract", offset),
+            Ok(OpCode::Multiply) => self.simple_instruction(out, "Multiply", offset),
+            Ok(OpCode::Divide) => self.simple_instruction(out, "Divide", offset),
+            Ok(OpCode::Not) => self.simple_instruction(out, "Not", offset),
+            Ok(OpCode::Negate) => self.simple_instruction(out, "Negate", offset),
+            Ok(OpCode::Pop) => self.simple_instruction(out, "Pop", offset),
+            Ok(OpCode::Dup) => self.simple_instruction(out, "Dup", offset),
+            Ok(OpCode::Swap) => self.simple_instruction(out, "Swap", offset),
+            Ok(OpCode::DefineGlobal) => self.constant_instruction(out, "DefineGlobal", offset),
+            Ok(OpCode::DefineGlobalLong) => {
+                self.constant_instruction(out, "DefineGlobalLong", offset)
+            }
+            Ok(OpCode::GetGlobal) => self.constant_instruction(out, "GetGlobal", offset),
+            Ok(OpCode::GetGlobalLong) => self.constant_instruction(out, "GetGlobalLong", offset),
+            Ok(OpCode::SetGlobal) => self.constant_instruction(out, "SetGlobal", offset),
+            Ok(OpCode::SetGlobalLong) => self.constant_instruction(out, "SetGlobalLong", offset),
+            Ok(OpCode::GetLocal) => self.byte_instruction(out, "GetLocal", offset),
+            Ok(OpCode::SetLocal) => self.byte_instruction(out, "SetLocal", offset),
+            Ok(OpCode::GetUpvalue) => self.byte_instruction(out, "GetUpvalue", offset),
+            Ok(OpCode::SetUpvalue) => self.byte_instruction(out, "SetUpvalue", offset),
+            Ok(OpCode::GetProperty) => self.constant_instruction(out, "GetProperty", offset),
+            Ok(OpCode::GetPropertyLong) => {
+                self.constant_instruction(out, "GetPropertyLong", offset)
+            }
+            Ok(OpCode::SetProperty) => self.constant_instruction(out, "SetProperty", offset),
+            Ok(OpCode::SetPropertyLong) => {
+                self.constant_instruction(out, "SetPropertyLong", offset)
+            }
+            Ok(OpCode::GetIndex) => self.simple_instruction(out, "GetIndex", offset),
+            Ok(OpCode::Jump) => self.jump_instruction(out, "Jump", offset),
+            Ok(OpCode::JumpIfFalse) => self.jump_instruction(out, "JumpIfFalse", offset),
+            Ok(OpCode::Loop) => self.loop_instruction(out, "Loop", offset),
+            Ok(OpCode::PushHandler) => self.jump_instruction(out, "PushHandler", offset),
+            Ok(OpCode::PopHandler) => self.simple_instruction(out, "PopHandler", offset),
+            Ok(OpCode::Throw) => self.simple_instruction(out, "Throw", offset),
+            Ok(OpCode::Call) => self.byte_instruction(out, "Call", offset),
+            Ok(OpCode::Closure) => self.closure_instruction(out, "Closure", offset),
+            Ok(OpCode::ClosureLong) => self.closure_instruction(out, "ClosureLong", offset),
+            Ok(OpCode::CloseUpvalue) => self.simple_instruction(out, "CloseUpvalue", offset),
+            Ok(OpCode::Class) => self.constant_instruction(out, "Class", offset),
+            Ok(OpCode::ClassLong) => self.constant_instruction(out, "ClassLong", offset),
+            Ok(OpCode::Method) => self.constant_instruction(out, "Method", offset),
+            Ok(OpCode::MethodLong) => self.constant_instruction(out, "MethodLong", offset),
+            Ok(OpCode::Import) => self.constant_instruction(out, "Import", offset),
+            Ok(OpCode::ImportLong) => self.constant_instruction(out, "ImportLong", offset),
+            Ok(OpCode::ImportAll) => self.simple_instruction(out, "ImportAll", offset),
+            Ok(OpCode::Print) => self.simple_instruction(out, "Print", offset),
+            Ok(OpCode::Return) => self.simple_instruction(out, "Return", offset),
             Err(_) => {
-                println!("Unknown opcode {instruction}");
+                writeln!(out, "Unknown opcode {instruction}").unwrap();
                 offset + 1
             }
         }
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{name}");
+    fn simple_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        writeln!(out, "{name}").unwrap();
         offset + 1
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let index = self.code[offset + 1];
-        println!("{name} {:4} '{}'", index, self.constants[index as usize]);
+    fn byte_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let operand = self.code[offset + 1];
+        writeln!(out, "{name} {operand:4}").unwrap();
         offset + 2
     }
+
+    fn jump_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let target = offset + 3 + self.read_short(offset + 1);
+        writeln!(out, "{name} {offset:4} -> {target}").unwrap();
+        offset + 3
+    }
+
+    fn loop_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        // Unverified chunks can loop back past the start
+        match (offset + 3).checked_sub(self.read_short(offset + 1)) {
+            Some(target) => writeln!(out, "{name} {offset:4} -> {target}").unwrap(),
+            None => writeln!(out, "{name} {offset:4} -> ?").unwrap(),
+        }
+        offset + 3
+    }
+
+    fn constant_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let op_code = OpCode::try_from(self.code[offset]).unwrap();
+        let index = self.constant_index(op_code, offset).unwrap();
+        let constant = constant_literal(&self.constants[index]);
+        writeln!(out, "{name} {index:4} '{constant}'").unwrap();
+        offset + 1 + op_code.operand_len()
+    }
+
+    // Lists the captured variables on the same line, e.g.
+    // `Closure    1 '<fn f>' local 1, upvalue 0`
+    fn closure_instruction(&self, out: &mut String, name: &str, offset: usize) -> usize {
+        let op_code = OpCode::try_from(self.code[offset]).unwrap();
+        let index = self.constant_index(op_code, offset).unwrap();
+        let function = &self.constants[index];
+        write!(out, "{name} {index:4} '{function}'").unwrap();
+        let upvalue_count = function.as_function().map_or(0, |f| f.upvalue_count);
+        let captures = offset + 1 + op_code.operand_len();
+        for i in 0..upvalue_count {
+            let is_local = self.code[captures + i * 2];
+            let index = self.code[captures + 1 + i * 2];
+            let separator = if i == 0 { " " } else { ", " };
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            write!(out, "{separator}{kind} {index}").unwrap();
+        }
+        out.push('\n');
+        captures + upvalue_count * 2
+    }
+
+    // One entry per instruction, without the offset and line columns so that
+    // an inserted instruction doesn't make every later one differ
+    fn instructions(&self) -> Vec<String> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let mut out = String::new();
+            offset = self.write_op(&mut out, offset);
+            instructions.push(out.trim_end().to_string());
+        }
+        instructions
+    }
+}
+
+/// Describes how `actual` differs from `expected` instruction by instruction,
+/// marking removed instructions with `-` and added ones with `+`. Returns an
+/// empty string when the chunks are equal.
+pub fn diff(expected: &Chunk, actual: &Chunk) -> String {
+    if expected == actual {
+        return String::new();
+    }
+    let a = expected.instructions();
+    let b = actual.instructions();
+
+    // Longest common subsequence table, lcs[i][j] covering a[i..] and b[j..]
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            writeln!(out, "  {}", a[i]).unwrap();
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            writeln!(out, "- {}", a[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, "+ {}", b[j]).unwrap();
+            j += 1;
+        }
+    }
+    if a == b {
+        out.push_str("Instructions match, but line information or constants differ\n");
+    }
+    out
+}
+
+// Strings are quoted so they can't be mistaken for other constants when the
+// listing is assembled again
+fn constant_literal(value: &Value) -> String {
+    if value.is_string() {
+        format!("\"{value}\"")
+    } else {
+        value.to_string()
+    }
+}
+
+/// A position in the code being built, which jumps can target before it's
+/// known.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Label(usize);
+
+/// Builds a chunk an instruction at a time, so code can be generated without
+/// writing raw bytes. Instructions are recorded at the current line, and jumps
+/// to labels are patched once every label is bound.
+///
+/// Emitting an instruction with the wrong kind of operand is a bug in the
+/// caller, so it panics.
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    line: u32,
+    // Where each label is bound, if it is yet
+    labels: Vec<Option<usize>>,
+    // Operand offset and target of each jump
+    jumps: Vec<(usize, Label)>,
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        ChunkBuilder::new()
+    }
+}
+
+impl ChunkBuilder {
+    pub fn new() -> ChunkBuilder {
+        ChunkBuilder {
+            chunk: Chunk::new(),
+            line: 1,
+            labels: vec![],
+            jumps: vec![],
+        }
+    }
+
+    /// Sets the source line recorded for the instructions that follow.
+    pub fn set_line(&mut self, line: u32) {
+        self.line = line;
+    }
+
+    /// Emits an instruction without operands.
+    pub fn emit(&mut self, op_code: OpCode) {
+        assert_eq!(op_code.operand_len(), 0, "{op_code:?} takes an operand");
+        self.write(op_code as u8);
+    }
+
+    /// Emits an instruction with a byte operand, such as a local's slot or an
+    /// index into the constant table.
+    pub fn emit_byte(&mut self, op_code: OpCode, operand: u8) {
+        assert!(
+            op_code.operand_len() == 1 && op_code != OpCode::Closure,
+            "{op_code:?} doesn't take a byte"
+        );
+        self.write(op_code as u8);
+        self.write(operand);
+    }
+
+    /// Emits an instruction loading `value`, which is a `ConstantLong` once
+    /// there are too many constants for a `Constant`.
+    pub fn emit_constant(&mut self, value: Value) -> Result<()> {
+        let index = self.chunk.add_long_constant(value)?;
+        self.emit_load(index);
+        Ok(())
+    }
+
+    fn emit_load(&mut self, index: usize) {
+        self.emit_index(OpCode::Constant, index);
+    }
+
+    // The instruction's long form once the index doesn't fit in a byte
+    fn emit_index(&mut self, op_code: OpCode, index: usize) {
+        match u8::try_from(index) {
+            Ok(index) => {
+                self.write(op_code as u8);
+                self.write(index);
+            }
+            Err(_) => self.emit_long(op_code.long().unwrap(), index),
+        }
+    }
+
+    fn emit_long(&mut self, op_code: OpCode, index: usize) {
+        self.write(op_code as u8);
+        for &byte in &(index as u32).to_be_bytes()[1..] {
+            self.write(byte);
+        }
+    }
+
+    /// Adds `value` to the constant table and emits an instruction taking its
+    /// index, e.g. `GetGlobal` with the variable's name.
+    pub fn emit_with_constant(&mut self, op_code: OpCode, value: Value) -> Result<()> {
+        assert!(
+            op_code.long().is_some() && op_code != OpCode::Closure,
+            "{op_code:?} doesn't take a constant"
+        );
+        let index = self.chunk.add_long_constant(value)?;
+        self.emit_index(op_code, index);
+        Ok(())
+    }
+
+    /// Emits a closure over `function`, capturing each of `upvalues`, given as
+    /// whether it's a local of the enclosing function and its index there.
+    pub fn emit_closure(&mut self, function: Function, upvalues: &[(bool, u8)]) -> Result<()> {
+        assert_eq!(
+            function.upvalue_count,
+            upvalues.len(),
+            "Closure captures the wrong number of upvalues"
+        );
+        let index = self
+            .chunk
+            .add_long_constant(Value::from_function(Arc::new(function)))?;
+        self.emit_index(OpCode::Closure, index);
+        for &(is_local, index) in upvalues {
+            self.write(is_local as u8);
+            self.write(index);
+        }
+        Ok(())
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds `label` to the next instruction emitted.
+    pub fn bind_label(&mut self, label: Label) {
+        let bound = self.labels[label.0].replace(self.chunk.code.len());
+        assert!(bound.is_none(), "{label:?} is already bound");
+    }
+
+    /// Emits a `Jump`, `JumpIfFalse`, `Loop` or `PushHandler` to `label`,
+    /// which for a loop has to be at or before it.
+    pub fn emit_jump(&mut self, op_code: OpCode, label: Label) {
+        assert!(
+            matches!(
+                op_code,
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler
+            ),
+            "{op_code:?} isn't a jump"
+        );
+        self.write(op_code as u8);
+        self.jumps.push((self.chunk.code.len(), label));
+        self.write(0xff);
+        self.write(0xff);
+    }
+
+    /// Patches every jump, failing if one targets a label that was never
+    /// bound or is out of its reach.
+    pub fn build(self) -> Result<Chunk> {
+        let mut chunk = self.chunk;
+        for (operand, label) in self.jumps {
+            let Some(target) = self.labels[label.0] else {
+                bail!("Jump at {} targets an unbound label", operand - 1);
+            };
+            let next = operand + 2;
+            let jump = if chunk.code[operand - 1] == OpCode::Loop as u8 {
+                next.checked_sub(target)
+            } else {
+                target.checked_sub(next)
+            };
+            let jump = jump
+                .and_then(|jump| u16::try_from(jump).ok())
+                .ok_or_else(|| anyhow!("Jump at {} can't reach {target}", operand - 1))?;
+            chunk.code[operand..operand + 2].copy_from_slice(&jump.to_be_bytes());
+        }
+        Ok(chunk)
+    }
+
+    // A label for a known offset, which the assembler's jumps can target
+    fn label_at(&mut self, offset: usize) -> Label {
+        self.labels.push(Some(offset));
+        Label(self.labels.len() - 1)
+    }
+
+    fn write(&mut self, byte: u8) {
+        self.chunk.write(byte, self.line);
+    }
+}
+
+/// Parses a listing in the disassembler's format back into a chunk. The
+/// offset and line columns are optional, so instructions can also be written
+/// by hand one per line, e.g. `Constant '1.5'` followed by `Return`. Constants
+/// without an explicit index are appended to the constant table, while those
+/// with one can come in any order, and jumps can target either an offset or a
+/// label declared on its own line as `name:`.
+pub fn assemble(text: &str) -> Result<Chunk> {
+    let mut assembler = Assembler {
+        builder: ChunkBuilder::new(),
+        labels: HashMap::new(),
+        skipped: HashSet::new(),
+    };
+    for (number, text) in text.lines().enumerate() {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with("==") || text.starts_with(';') {
+            continue;
+        }
+        assembler
+            .line(number + 1, text)
+            .map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+    }
+
+    let Assembler {
+        builder,
+        labels,
+        skipped,
+    } = assembler;
+    for (name, (label, number)) in &labels {
+        if builder.labels[label.0].is_none() {
+            bail!("line {number}: Unknown label '{name}'");
+        }
+    }
+    if let Some(index) = skipped.iter().min() {
+        bail!("Constant {index} is never given");
+    }
+    builder.build()
+}
+
+struct Assembler {
+    builder: ChunkBuilder,
+    // Each named label, with the listing line that first mentioned it
+    labels: HashMap<String, (Label, usize)>,
+    // Indexes a constant with a higher one was given before, e.g. a global's
+    // name that the compiler added before the value assigned to it
+    skipped: HashSet<usize>,
+}
+
+impl Assembler {
+    fn line(&mut self, number: usize, text: &str) -> Result<()> {
+        if let Some(name) = text.strip_suffix(':') {
+            let label = self.label(name.trim(), number);
+            self.builder.bind_label(label);
+            return Ok(());
+        }
+
+        let (mut name, mut operands) = split_word(text);
+        if name.parse::<usize>().is_ok() {
+            // The offset and line columns of a disassembler listing
+            let (column, rest) = split_word(operands);
+            match column {
+                "|" | "?" => (),
+                column => self.builder.set_line(column.parse()?),
+            }
+            (name, operands) = split_word(rest);
+        }
+
+        let op_code = (0..=u8::MAX)
+            .filter_map(|b| OpCode::try_from(b).ok())
+            .find(|op| format!("{op:?}") == name)
+            .ok_or_else(|| anyhow!("Unknown instruction '{name}'"))?;
+
+        match op_code {
+            // Function constants have no literal form to write them in
+            OpCode::Closure | OpCode::ClosureLong => bail!("Closures can't be assembled"),
+            _ if op_code.operand_len() == 3 => {
+                let index = self.constant(operands)?;
+                self.builder.emit_long(op_code, index);
+            }
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Class
+            | OpCode::Method
+            | OpCode::Import => {
+                let index = self.constant(operands)?;
+                let index = u8::try_from(index).map_err(|_| {
+                    anyhow!("Constant {index} needs a {:?}", op_code.long().unwrap())
+                })?;
+                self.builder.emit_byte(op_code, index);
+            }
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => {
+                let operand = operands
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid operand '{operands}'"))?;
+                self.builder.emit_byte(op_code, operand);
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop | OpCode::PushHandler => {
+                let Some((_, target)) = operands.split_once("->") else {
+                    bail!("Expected '-> target', found '{operands}'");
+                };
+                let target = target.trim();
+                let label = match target.parse::<usize>() {
+                    Ok(offset) => self.builder.label_at(offset),
+                    Err(_) => self.label(target, number),
+                };
+                self.builder.emit_jump(op_code, label);
+            }
+            _ if !operands.is_empty() => bail!("Unexpected operand '{operands}'"),
+            _ => self.builder.emit(op_code),
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, name: &str, number: usize) -> Label {
+        if let Some((label, _)) = self.labels.get(name) {
+            return *label;
+        }
+        let label = self.builder.new_label();
+        self.labels.insert(name.to_string(), (label, number));
+        label
+    }
+
+    fn constant(&mut self, operands: &str) -> Result<usize> {
+        let (index, literal) = match operands.split_once(char::is_whitespace) {
+            Some((index, literal)) if !index.starts_with('\'') => {
+                (Some(index.parse()?), literal.trim())
+            }
+            _ => (None, operands),
+        };
+        let Some(literal) = literal
+            .strip_prefix('\'')
+            .and_then(|l| l.strip_suffix('\''))
+        else {
+            bail!("Expected a quoted constant, found '{literal}'");
+        };
+        let value = if let Some(s) = literal.strip_prefix('"').and_then(|l| l.strip_suffix('"')) {
+            Value::from_string(s.to_string())
+        } else {
+            match literal {
+                "nil" => Value::Nil,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                n => Value::Number(
+                    number::parse(n).ok_or_else(|| anyhow!("Invalid constant '{n}'"))?,
+                ),
+            }
+        };
+
+        let chunk = &mut self.builder.chunk;
+        let Some(index) = index else {
+            return chunk.add_long_constant(value);
+        };
+        if index >= MAX_LONG_CONSTANTS {
+            bail!("Constant {index} is past the last one a chunk can have");
+        }
+        if self.skipped.remove(&index) {
+            chunk.constants[index] = value;
+            return Ok(index);
+        }
+        match index.cmp(&chunk.constants.len()) {
+            Ordering::Equal => chunk.add_long_constant(value),
+            Ordering::Greater => {
+                self.skipped.extend(chunk.constants.len()..index);
+                chunk.constants.resize(index, Value::Nil);
+                chunk.add_long_constant(value)
+            }
+            Ordering::Less if chunk.constants[index] == value => Ok(index),
+            Ordering::Less => bail!("Constant {index} doesn't match the constant table"),
+        }
+    }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    text.split_once(char::is_whitespace)
+        .map_or((text, ""), |(word, rest)| (word, rest.trim()))
 }
@@ -1,183 +1,1920 @@
-use std::mem;
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    iter, mem,
+    ops::Range,
+    sync::Arc,
+};
 
 use crate::{
     chunk::{Chunk, OpCode},
+    doc,
+    include::SourceMap,
+    natives, number,
+    program::Program,
     scanner::{Scanner, Token, TokenType},
-    value::Value,
+    source,
+    value::{Function, Value},
 };
-use anyhow::{bail, Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
+
+/// Changes whenever the same source compiles to different code, so that
+/// programs cached by an older compiler aren't run.
+pub const VERSION: u32 = 1;
+
+/// The names of the warnings, which `#pragma allow` takes.
+pub const WARNINGS: &[&str] = &["dead-code", "shadow"];
+
+const MAX_NESTING: usize = 200;
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+const MAX_ARGS: usize = u8::MAX as usize;
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+
+/// A compile error or warning.
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Byte range of the source it's about. Warnings about a whole
+    /// statement don't have one.
+    pub span: Option<Range<usize>>,
+    /// What kind of warning it is, e.g. `shadow`. Errors don't have one.
+    pub name: Option<&'static str>,
+    // Where it is, as in `[line 1] Error at 'x'`
+    header: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    // None until the initializer has been compiled, so that it can't refer
+    // to the variable it's initializing
+    depth: Option<usize>,
+    // Whether a closure captures it, in which case it has to be moved off the
+    // stack when it goes out of scope
+    is_captured: bool,
+}
+
+// A variable a function captures, either a local of the enclosing function
+// or one of the enclosing function's own upvalues
+#[derive(Clone, Copy, PartialEq)]
+struct Upvalue {
+    index: u8,
+    is_local: bool,
+}
+
+// A loop being compiled, which `break` and `continue` jump out of and back to
+struct Loop<'a> {
+    // The name `break` and `continue` can give to leave it from a nested loop
+    label: Option<&'a str>,
+    // Where `continue` jumps to, which in a for loop is the increment
+    start: usize,
+    // The scope enclosing the body, whose locals are still on the stack at
+    // `start` and once the loop exits
+    scope_depth: usize,
+    // Jumps from `break` statements, patched to the end of the loop
+    breaks: Vec<usize>,
+    // How many handlers and try statements enclosed the loop, so that a jump
+    // out of it knows which ones it leaves
+    handlers: usize,
+    tries: usize,
+}
+
+// A try statement being compiled
+struct Try {
+    // Whether it has a finally block, which a `break`, `continue` or `return`
+    // leaving the statement has to run first
+    finally: bool,
+    // With a finally block, the first of its two hidden locals: the value to
+    // rethrow or return once it has run, and what to do then
+    slot: u8,
+    // The scope holding those locals, and how many handlers are installed
+    // outside the statement, which an exit unwinds to before running the
+    // finally block
+    scope_depth: usize,
+    handlers: usize,
+    // Each exit waiting for the finally block, and its jump there
+    exits: Vec<(Exit, usize)>,
+}
+
+#[derive(Clone, Copy)]
+enum Exit {
+    // With the returned value on the stack
+    Return,
+    // Of the loop at the index
+    Break(usize),
+    Continue(usize),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    // The body of a block expression, which runs as a function of its own so
+    // that its locals don't land among the temporaries of the expression
+    // around it
+    Block,
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// The function being compiled, and the scopes within it
+struct FunctionCompiler<'a> {
+    function: Function,
+    ty: FunctionType,
+    locals: Vec<Local<'a>>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    // Loops enclosing the code being compiled, innermost last
+    loops: Vec<Loop<'a>>,
+    // How many exception handlers are installed around the code being
+    // compiled, which a `break` or `continue` has to remove
+    handlers: usize,
+    // The try statements being compiled, innermost last
+    tries: Vec<Try>,
+}
+
+impl<'a> FunctionCompiler<'a> {
+    fn new(ty: FunctionType, name: Option<String>) -> FunctionCompiler<'a> {
+        FunctionCompiler {
+            function: Function {
+                name,
+                ..Default::default()
+            },
+            ty,
+            // Slot 0 holds the function being called, under a name no
+            // variable can have. For methods it's the receiver instead.
+            locals: vec![Local {
+                name: match ty {
+                    FunctionType::Initializer | FunctionType::Method => "this",
+                    FunctionType::Block | FunctionType::Function | FunctionType::Script => "",
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: vec![],
+            scope_depth: 0,
+            loops: vec![],
+            handlers: 0,
+            tries: vec![],
+        }
+    }
+}
 
 struct Parser<'a> {
     scanner: Scanner<'a>,
+    source: &'a str,
     current: Token<'a>,
     previous: Token<'a>,
+    // Where the token before `previous` ended, so that the doc comment
+    // between the two can be found
+    before_previous: usize,
     had_error: bool,
     panic_mode: bool,
-    chunk: &'a mut Chunk,
+    depth: usize,
+    // One per function declaration being compiled, innermost last
+    compilers: Vec<FunctionCompiler<'a>>,
+    // How many class declarations enclose the code being compiled, which is
+    // where `this` can be used
+    class_depth: usize,
+    source_map: Option<&'a SourceMap>,
+    diagnostics: Vec<Diagnostic>,
+    // Globals the script has defined so far, which later declarations are
+    // warned about shadowing
+    globals: HashSet<&'a str>,
+    // Warnings that aren't reported, either because a pragma allows them or,
+    // for clox's dialect, because clox has none
+    allowed: HashSet<&'static str>,
+    // Whether `#pragma strict` makes the warnings that aren't allowed errors
+    strict: bool,
+    // Whether a missing `;` at the end of the source is filled in rather
+    // than reported, as it is for input typed at a prompt
+    implicit_semicolon: bool,
+    // Where it was filled in, if it was
+    filled_semicolon: Option<usize>,
+    // The code and constant counts before the left operand of the infix
+    // operator being compiled, so that constant operands can be folded
+    left_operand: (usize, usize),
+    // The source the code being emitted is recorded as coming from, when it
+    // isn't just the previous token, e.g. an operator after its operands
+    emit_span: Option<Range<usize>>,
 }
 
 impl<'a> Parser<'a> {
-    fn new(scanner: Scanner<'a>, chunk: &'a mut Chunk) -> Parser<'a> {
+    fn new(source: &'a str, source_map: Option<&'a SourceMap>) -> Parser<'a> {
         Parser {
-            scanner: scanner,
+            scanner: Scanner::new(source),
+            source,
             current: Token::default(),
             previous: Token::default(),
+            before_previous: 0,
             had_error: false,
             panic_mode: false,
-            chunk,
+            depth: 0,
+            compilers: vec![FunctionCompiler::new(FunctionType::Script, None)],
+            class_depth: 0,
+            source_map,
+            diagnostics: vec![],
+            globals: HashSet::new(),
+            allowed: HashSet::new(),
+            strict: false,
+            implicit_semicolon: false,
+            filled_semicolon: None,
+            left_operand: (0, 0),
+            emit_span: None,
+        }
+    }
+
+    fn compiler(&mut self) -> &mut FunctionCompiler<'a> {
+        self.compilers.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler().function.chunk
+    }
+
+    fn advance(&mut self) {
+        self.before_previous = self.previous.span.end;
+        self.previous = mem::take(&mut self.current);
+
+        loop {
+            self.current = match self.scanner.next() {
+                Some(token) => token,
+                None => {
+                    let end = self.previous.span.end;
+                    let column = self.previous.column + self.previous.str.chars().count() as u32;
+                    Token::new(TokenType::Eof, "", self.previous.line, column, end..end)
+                }
+            };
+            match self.current.ty {
+                TokenType::Pragma => self.pragma(),
+                TokenType::Error => self.error_at_current(self.current.str),
+                _ => break,
+            }
+        }
+    }
+
+    fn consume(&mut self, ty: TokenType, message: &str) {
+        if self.current.ty == ty {
+            self.advance()
+        } else if ty == TokenType::Semicolon
+            && self.implicit_semicolon
+            && self.check(TokenType::Eof)
+        {
+            self.filled_semicolon = Some(self.previous.span.end);
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    // `#pragma allow shadow dead-code` turns those warnings off for the whole
+    // source, wherever it is
+    fn pragma(&mut self) {
+        let pragma = self.current.clone();
+        let mut words = pragma.str.split_whitespace().map(|word| {
+            let offset = word.as_ptr() as usize - pragma.str.as_ptr() as usize;
+            let column = pragma.column + pragma.str[..offset].chars().count() as u32;
+            let start = pragma.span.start + offset;
+            Token::new(
+                pragma.ty,
+                word,
+                pragma.line,
+                column,
+                start..start + word.len(),
+            )
+        });
+        // The `#pragma` itself
+        words.next();
+        match words.next() {
+            Some(name) if name.str == "allow" => (),
+            Some(name) if name.str == "strict" => {
+                self.strict = true;
+                if let Some(word) = words.next() {
+                    self.pragma_error(&word, "Expect end of pragma after 'strict'.");
+                }
+                return;
+            }
+            Some(name) => {
+                return self.pragma_error(&name, &format!("Unknown pragma '{}'.", name.str));
+            }
+            None => return self.pragma_error(&pragma, "Expect pragma name."),
+        }
+        let mut named = false;
+        for word in words {
+            named = true;
+            match WARNINGS.iter().find(|&&name| name == word.str) {
+                Some(name) => {
+                    self.allowed.insert(name);
+                }
+                None => self.pragma_error(&word, &format!("Unknown warning '{}'.", word.str)),
+            }
+        }
+        if !named {
+            self.pragma_error(&pragma, "Expect warning names after 'allow'.");
+        }
+    }
+
+    // Pragmas aren't statements, so an error in one doesn't hide errors in
+    // the code after it
+    fn pragma_error(&mut self, token: &Token, message: &str) {
+        self.error_at(token, message);
+        self.panic_mode = false;
+    }
+
+    fn check(&self, ty: TokenType) -> bool {
+        self.current.ty == ty
+    }
+
+    fn match_token(&mut self, ty: TokenType) -> bool {
+        if !self.check(ty) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = &self.current;
+        self.error_at(&token.clone(), message);
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = &self.previous;
+        self.error_at(&token.clone(), message);
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+        let mut header = format!("[{}] Error", self.describe(token.line));
+        match token.ty {
+            TokenType::Eof => header.push_str(" at end"),
+            TokenType::Error => (),
+            _ => header.push_str(&format!(" at '{}'", token.str)),
+        }
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            span: Some(token.span.clone()),
+            name: None,
+            header,
+        });
+        self.had_error = true;
+    }
+
+    fn describe(&self, line: u32) -> String {
+        match self.source_map {
+            Some(source_map) => source_map.describe(line),
+            None => format!("line {line}"),
+        }
+    }
+
+    fn warning(
+        &mut self,
+        name: &'static str,
+        line: u32,
+        span: Option<Range<usize>>,
+        message: &str,
+    ) {
+        if self.had_error {
+            return;
+        }
+        self.diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            message: message.to_string(),
+            span,
+            name: Some(name),
+            header: format!("[{}] Warning ({name})", self.describe(line)),
+        });
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous.line;
+        let span = self
+            .emit_span
+            .clone()
+            .unwrap_or_else(|| self.previous.span.clone());
+        self.chunk().write_spanned(byte, line, span);
+    }
+
+    // Emits code recorded as coming from `span` of the source
+    fn emit_at(&mut self, span: Range<usize>, emit: impl FnOnce(&mut Self)) {
+        let enclosing = self.emit_span.replace(span);
+        emit(self);
+        self.emit_span = enclosing;
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself
+        let jump = self.chunk().code.len() - offset - 2;
+        match u16::try_from(jump) {
+            Ok(jump) => self.chunk().code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes()),
+            Err(_) => self.error("Too much code to jump over."),
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop as u8);
+
+        // +2 to jump back over the operand itself too
+        let offset = self.chunk().code.len() - loop_start + 2;
+        match u16::try_from(offset) {
+            Ok(offset) => {
+                let [high, low] = offset.to_be_bytes();
+                self.emit_bytes(high, low);
+            }
+            Err(_) => {
+                self.error("Loop body too large.");
+                self.emit_bytes(0xff, 0xff);
+            }
+        }
+    }
+
+    // Functions return nil unless they return something else explicitly, and
+    // initializers always return the instance
+    fn emit_return(&mut self) {
+        self.emit_implicit_return_value();
+        self.emit_byte(OpCode::Return as u8);
+    }
+
+    // What `return;` returns: the instance in an initializer, otherwise nil
+    fn emit_implicit_return_value(&mut self) {
+        if self.compiler().ty == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal as u8, 0);
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+    }
+
+    fn end(&mut self) -> (Function, Vec<Upvalue>) {
+        self.emit_return();
+        let FunctionCompiler {
+            mut function,
+            upvalues,
+            ..
+        } = self.compilers.pop().unwrap();
+        function.upvalue_count = upvalues.len();
+        if cfg!(feature = "debug_print_code") && !self.had_error {
+            function.chunk.disassemble(&function.to_string());
+        }
+        (function, upvalues)
+    }
+
+    pub fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment);
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        let doc = self.doc_comment(self.before_previous..self.previous.span.start);
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous.str;
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        let class = self.chunk().code.len();
+        self.emit_index(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        // Load the class again so methods can be attached to it
+        self.class_depth += 1;
+        self.named_variable(class_name, false);
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        if let Some(doc) = self.docstring().or(doc) {
+            self.chunk().add_doc(class, doc);
+        }
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop as u8);
+        self.class_depth -= 1;
+    }
+
+    fn method(&mut self) {
+        let doc = self.doc_comment(self.previous.span.end..self.current.span.start);
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let constant = self.identifier_constant(self.previous.str);
+        let ty = if self.previous.str == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(self.previous.str, ty, doc);
+        self.emit_index(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let doc = self.doc_comment(self.before_previous..self.previous.span.start);
+        let global = self.parse_variable("Expect function name.");
+        // A local function can refer to itself before its body is done
+        self.mark_initialized();
+        if self.check(TokenType::LeftBracket) {
+            self.capturing_function(doc);
+        } else {
+            self.function(self.previous.str, FunctionType::Function, doc);
+        }
+        self.define_variable(global);
+    }
+
+    // The `///` lines directly above a declaration, which are in the source
+    // the scanner skipped over before its first token
+    fn doc_comment(&self, gap: Range<usize>) -> Option<String> {
+        let lines = doc::doc_comment(&self.source[gap]);
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    // `"...";` at the start of a function or class body documents it like a
+    // doc comment, and compiles to nothing
+    fn docstring(&mut self) -> Option<String> {
+        let next = self.scanner.clone().next().map(|token| token.ty);
+        if !self.check(TokenType::String) || next != Some(TokenType::Semicolon) {
+            return None;
+        }
+        self.advance();
+        let literal = self.previous.str;
+        self.advance();
+        // Without the quotes
+        Some(literal[1..literal.len() - 1].trim().to_string())
+    }
+
+    fn function(&mut self, name: &str, ty: FunctionType, doc: Option<String>) {
+        self.compilers
+            .push(FunctionCompiler::new(ty, Some(name.to_string())));
+        // Never ended, since returning discards the whole frame
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        self.parameters();
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        let doc = self.docstring().or(doc);
+        self.block();
+        self.closure(doc);
+    }
+
+    // `fun f[x, y](a) { ... }` copies x and y into the function when it's
+    // declared, where it would otherwise share them with the code around it.
+    // A hidden function of the same name takes the copies as parameters and
+    // returns the declared one, which captures those instead.
+    fn capturing_function(&mut self, doc: Option<String>) {
+        let name = self.previous.str;
+        self.advance();
+        self.compilers.push(FunctionCompiler::new(
+            FunctionType::Function,
+            Some(name.to_string()),
+        ));
+        self.begin_scope();
+        let mut captures = vec![];
+        loop {
+            self.consume(TokenType::Identifier, "Expect captured variable name.");
+            let captured = self.previous.str;
+            if captures.contains(&captured) {
+                self.error("Already captured this variable.");
+            }
+            if captures.len() == MAX_ARGS {
+                self.error("Can't capture more than 255 variables.");
+            }
+            captures.push(captured);
+            self.add_local(captured);
+            self.mark_initialized();
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(
+            TokenType::RightBracket,
+            "Expect ']' after captured variables.",
+        );
+        self.compiler().function.arity = captures.len();
+        // Where it can refer to itself, as the declaration's own variable
+        // only holds it once the hidden function has returned
+        self.add_local(name);
+        self.mark_initialized();
+        self.function(name, FunctionType::Function, doc);
+        // Returning a copy, since the local itself has to stay on the stack
+        // until returning moves it into the function's upvalue
+        self.emit_bytes(OpCode::GetLocal as u8, (captures.len() + 1) as u8);
+        self.emit_byte(OpCode::Return as u8);
+        self.closure(None);
+
+        for &captured in &captures {
+            let (arg, get_op, _) = self.resolve_variable(captured);
+            self.emit_index(get_op, arg);
+        }
+        self.emit_bytes(OpCode::Call as u8, captures.len() as u8);
+    }
+
+    // `(a, b) => a + b`, a function returning the expression after the arrow
+    fn arrow_function(&mut self) {
+        self.compilers.push(FunctionCompiler::new(
+            FunctionType::Function,
+            Some("lambda".to_string()),
+        ));
+        self.begin_scope();
+        self.parameters();
+        self.consume(TokenType::Arrow, "Expect '=>' after parameters.");
+        self.expression();
+        self.emit_byte(OpCode::Return as u8);
+        self.closure(None);
+    }
+
+    // `{ var t = f(); t * 2 }` runs its declarations and statements and has
+    // the value of the expression ending it, or nil without one
+    fn block_expression(&mut self) {
+        self.compilers.push(FunctionCompiler::new(
+            FunctionType::Block,
+            Some("block".to_string()),
+        ));
+        self.begin_scope();
+        loop {
+            if self.check(TokenType::RightBrace) || self.check(TokenType::Eof) {
+                self.emit_byte(OpCode::Nil as u8);
+                break;
+            }
+            if self.at_statement() {
+                self.declaration();
+                continue;
+            }
+            self.expression();
+            if !self.match_token(TokenType::Semicolon) {
+                break;
+            }
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        self.emit_byte(OpCode::Return as u8);
+        self.closure(None);
+        self.emit_bytes(OpCode::Call as u8, 0);
+    }
+
+    // Whether the current token starts a declaration or a statement other
+    // than an expression statement, which can't be a block's value. Like in
+    // statement position, `if` starts a statement rather than an expression.
+    fn at_statement(&self) -> bool {
+        let next = self.scanner.clone().next().map(|token| token.ty);
+        match self.current.ty {
+            TokenType::Class
+            | TokenType::Fun
+            | TokenType::Var
+            | TokenType::Import
+            | TokenType::Print
+            | TokenType::If
+            | TokenType::While
+            | TokenType::For
+            | TokenType::Return
+            | TokenType::Break
+            | TokenType::Continue
+            | TokenType::Try
+            | TokenType::Throw
+            | TokenType::LeftBrace => true,
+            TokenType::Identifier => {
+                matches!(next, Some(TokenType::Colon | TokenType::Comma))
+            }
+            _ => false,
+        }
+    }
+
+    fn parameters(&mut self) {
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler().function.arity += 1;
+                if self.compiler().function.arity > MAX_ARGS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+    }
+
+    // Ends the function being compiled and creates a closure over it in the
+    // enclosing one
+    fn closure(&mut self, doc: Option<String>) {
+        let (mut function, upvalues) = self.end();
+        function.doc = doc;
+        let constant = self.make_constant(Value::from_function(Arc::new(function)));
+        self.emit_index(OpCode::Closure, constant);
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::Equal) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::Nil as u8);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    // `import "path";` defines each of the module's globals here, while
+    // `import name from "path";` defines one variable holding all of them
+    fn import_declaration(&mut self) {
+        if self.match_token(TokenType::String) {
+            self.import_path();
+            self.consume(TokenType::Semicolon, "Expect ';' after import.");
+            self.emit_byte(OpCode::ImportAll as u8);
+            return;
+        }
+
+        let global = self.parse_variable("Expect module name or path.");
+        // `from` is only a keyword here, so it can still name variables
+        if self.check(TokenType::Identifier) && self.current.str == "from" {
+            self.advance();
+        } else {
+            self.error_at_current("Expect 'from' after import name.");
+        }
+        self.consume(TokenType::String, "Expect module path.");
+        self.import_path();
+        self.consume(TokenType::Semicolon, "Expect ';' after import.");
+        self.define_variable(global);
+    }
+
+    fn import_path(&mut self) {
+        let path = &self.previous.str[1..self.previous.str.len() - 1];
+        let constant = self.make_constant(Value::from_string(path.to_string()));
+        self.emit_index(OpCode::Import, constant);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.consume(TokenType::Identifier, message);
+
+        self.declare_variable();
+        if self.compiler().scope_depth > 0 {
+            return 0;
+        }
+
+        self.identifier_constant(self.previous.str)
+    }
+
+    // Locals live in the stack slot their initializer's value was left in
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_index(OpCode::DefineGlobal, global);
+    }
+
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler();
+        if compiler.scope_depth == 0 {
+            return;
+        }
+        compiler.locals.last_mut().unwrap().depth = Some(compiler.scope_depth);
+    }
+
+    fn declare_variable(&mut self) {
+        self.check_shadowing();
+        if self.compiler().scope_depth == 0 {
+            return;
+        }
+
+        let name = self.previous.str;
+        let compiler = self.compiler();
+        let redeclared = compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.is_none_or(|d| d == compiler.scope_depth))
+            .any(|l| l.name == name);
+        if redeclared {
+            self.error("Already a variable with this name in this scope.");
+        }
+        self.add_local(name);
+    }
+
+    // Warns about a declaration hiding a native or a global, since code
+    // meaning the original would silently get the new variable instead
+    fn check_shadowing(&mut self) {
+        let token = self.previous.clone();
+        let name = token.str;
+        let is_native = natives::GLOBALS.iter().any(|(native, _)| *native == name);
+        let message = if self.compilers.len() == 1 && self.compiler().scope_depth == 0 {
+            let redefined = !self.globals.insert(name);
+            if is_native {
+                format!("Global '{name}' shadows a native function.")
+            } else if redefined {
+                format!("Global '{name}' is already defined.")
+            } else {
+                return;
+            }
+        } else if is_native {
+            format!("Local '{name}' shadows a native function.")
+        } else if self.globals.contains(name) {
+            format!("Local '{name}' shadows a global.")
+        } else {
+            return;
+        };
+        self.warning("shadow", token.line, Some(token.span), &message);
+    }
+
+    fn add_local(&mut self, name: &'a str) {
+        if self.compiler().locals.len() == MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+        self.compiler().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    // `compiler` indexes `self.compilers`, so enclosing functions can be
+    // searched too
+    fn resolve_local(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.compilers[compiler]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, l)| l.name == name)?;
+        if local.depth.is_none() {
+            self.error("Can't read local variable in its own initializer.");
+        }
+        Some(slot as u8)
+    }
+
+    // Looks for the variable in each enclosing function in turn, threading
+    // it through the upvalues of every function in between
+    fn resolve_upvalue(&mut self, compiler: usize, name: &str) -> Option<u8> {
+        let enclosing = compiler.checked_sub(1)?;
+        if let Some(slot) = self.resolve_local(enclosing, name) {
+            self.compilers[enclosing].locals[slot as usize].is_captured = true;
+            return Some(self.add_upvalue(compiler, slot, true));
+        }
+        let index = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(compiler, index, false))
+    }
+
+    fn add_upvalue(&mut self, compiler: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.compilers[compiler].upvalues;
+        if let Some(existing) = upvalues.iter().position(|&u| u == upvalue) {
+            return existing as u8;
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let upvalues = &mut self.compilers[compiler].upvalues;
+        upvalues.push(upvalue);
+        (upvalues.len() - 1) as u8
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::Print) {
+            self.print_statement();
+        } else if self.match_token(TokenType::If) {
+            self.if_statement();
+        } else if self.match_token(TokenType::While) {
+            self.while_statement(None);
+        } else if self.match_token(TokenType::For) {
+            self.for_statement(None);
+        } else if self.check(TokenType::Identifier)
+            && self.scanner.clone().next().map(|token| token.ty) == Some(TokenType::Colon)
+        {
+            self.labeled_statement();
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
+        } else if self.match_token(TokenType::Try) {
+            self.try_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn return_statement(&mut self) {
+        match self.compiler().ty {
+            FunctionType::Script => self.error("Can't return from top-level code."),
+            FunctionType::Block => self.error("Can't return from a block expression."),
+            _ => (),
+        }
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_implicit_return_value();
+        } else {
+            let keyword = self.previous.clone();
+            let start = (self.chunk().code.len(), self.chunk().constants.len());
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            // Returning nil from an initializer is the same as a bare return
+            if self.compiler().ty == FunctionType::Initializer {
+                if self.literal_at(start.0) != Some(Value::Nil) {
+                    self.error_at(&keyword, "Can't return a value from an initializer.");
+                }
+                self.truncate(start.0, start.1);
+                self.emit_implicit_return_value();
+            }
+        }
+        self.exit(Exit::Return);
+    }
+
+    // `outer: while (...)` names the loop for `break outer;` and
+    // `continue outer;` in loops nested in it
+    fn labeled_statement(&mut self) {
+        self.advance();
+        let label = self.previous.str;
+        self.advance();
+        if self.match_token(TokenType::While) {
+            self.while_statement(Some(label));
+        } else if self.match_token(TokenType::For) {
+            self.for_statement(Some(label));
+        } else {
+            self.error_at_current("Expect a loop after a label.");
+        }
+    }
+
+    fn break_statement(&mut self) {
+        if let Some(target) = self.exited_loop("break") {
+            self.exit(Exit::Break(target));
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
+    }
+
+    fn continue_statement(&mut self) {
+        if let Some(target) = self.exited_loop("continue") {
+            self.exit(Exit::Continue(target));
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+    }
+
+    // The index of the loop a `break` or `continue` leaves: the innermost
+    // one, or the one with the label after the keyword
+    fn exited_loop(&mut self, keyword: &str) -> Option<usize> {
+        if self.compiler().loops.is_empty() {
+            self.error(&format!("Can't use '{keyword}' outside of a loop."));
+            return None;
+        }
+        if !self.match_token(TokenType::Identifier) {
+            return Some(self.compiler().loops.len() - 1);
+        }
+        let label = self.previous.str;
+        let target = self
+            .compiler()
+            .loops
+            .iter()
+            .rposition(|l| l.label == Some(label));
+        if target.is_none() {
+            self.error(&format!("Undefined loop label '{label}'."));
+        }
+        target
+    }
+
+    // Leaves the function or the innermost loop. If that leaves a try
+    // statement with a finally block, it goes to the innermost such block
+    // instead, which carries on with the exit once it has run.
+    fn exit(&mut self, exit: Exit) {
+        let compiler = self.compiler();
+        // Returning leaves every try statement in the function, and the VM
+        // removes their handlers
+        let left = match exit {
+            Exit::Return => 0,
+            Exit::Break(target) | Exit::Continue(target) => compiler.loops[target].tries,
+        };
+        let finally = compiler.tries[left..].iter().rposition(|t| t.finally);
+        let Some(index) = finally.map(|i| left + i) else {
+            match exit {
+                Exit::Return => self.emit_byte(OpCode::Return as u8),
+                Exit::Break(target) => {
+                    self.discard_loop_locals(target);
+                    let jump = self.emit_jump(OpCode::Jump);
+                    self.compiler().loops[target].breaks.push(jump);
+                }
+                Exit::Continue(target) => {
+                    let start = self.compiler().loops[target].start;
+                    self.discard_loop_locals(target);
+                    self.emit_loop(start);
+                }
+            }
+            return;
+        };
+
+        let exited = &compiler.tries[index];
+        let (slot, depth, handlers) = (exited.slot, exited.scope_depth, exited.handlers);
+        if let Exit::Return = exit {
+            self.emit_bytes(OpCode::SetLocal as u8, slot);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        for _ in handlers..self.compiler().handlers {
+            self.emit_byte(OpCode::PopHandler as u8);
+        }
+        self.pop_locals(depth);
+        let code = self.compiler().tries[index].exits.len();
+        self.emit_constant(Value::Number(code as f64));
+        self.emit_bytes(OpCode::SetLocal as u8, slot + 1);
+        self.emit_byte(OpCode::Pop as u8);
+        let jump = self.emit_jump(OpCode::Jump);
+        self.compiler().tries[index].exits.push((exit, jump));
+    }
+
+    // Pops the locals declared in a loop's body, without forgetting them
+    // since the code after the jump is still in their scope, and removes the
+    // handlers of try statements in it
+    fn discard_loop_locals(&mut self, target: usize) {
+        let compiler = self.compiler();
+        let exited = &compiler.loops[target];
+        let (depth, handlers) = (exited.scope_depth, exited.handlers);
+        for _ in handlers..compiler.handlers {
+            self.emit_byte(OpCode::PopHandler as u8);
+        }
+        self.pop_locals(depth);
+    }
+
+    // Compiles a loop body that `continue` jumps back to `start` from
+    fn loop_body(&mut self, label: Option<&'a str>, start: usize) {
+        let compiler = self.compiler();
+        let scope_depth = compiler.scope_depth;
+        let (handlers, tries) = (compiler.handlers, compiler.tries.len());
+        compiler.loops.push(Loop {
+            label,
+            start,
+            scope_depth,
+            breaks: vec![],
+            handlers,
+            tries,
+        });
+        self.statement();
+    }
+
+    // Sends the innermost loop's `break` statements here
+    fn end_loop(&mut self) {
+        let exit = self.compiler().loops.pop().unwrap();
+        for jump in exit.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    // The try block runs with a handler installed, which the VM jumps to with
+    // the exception pushed when anything in it throws. So does the catch
+    // block, whose handler rethrows after running the finally block. The
+    // exception it caught stays below its handler in a hidden local, and its
+    // variable is a copy, so that throwing closes any closure's hold on it.
+    //
+    // The finally block runs after either, and before any `break`, `continue`
+    // or `return` leaving them. Two hidden locals say what to do once it has
+    // run: carry on, rethrow an exception, or finish one of those exits,
+    // which are numbered in the order they appear.
+    fn try_statement(&mut self) {
+        let finally = self.has_finally();
+        self.begin_scope();
+        let slot = self.compiler().locals.len() as u8;
+        if finally {
+            self.emit_byte(OpCode::Nil as u8);
+            self.emit_byte(OpCode::False as u8);
+            for _ in 0..2 {
+                self.add_local("");
+                self.mark_initialized();
+            }
+        }
+        let compiler = self.compiler();
+        let (scope_depth, handlers) = (compiler.scope_depth, compiler.handlers);
+        compiler.tries.push(Try {
+            finally,
+            slot,
+            scope_depth,
+            handlers,
+            exits: vec![],
+        });
+        let try_handler = self.emit_jump(OpCode::PushHandler);
+        self.protected_block("Expect '{' after 'try'.");
+
+        let mut finished = None;
+        let mut catch_handler = None;
+        if self.match_token(TokenType::Catch) {
+            finished = Some(self.emit_jump(OpCode::Jump));
+            self.patch_jump(try_handler);
+            self.begin_scope();
+            self.add_local("");
+            self.mark_initialized();
+            catch_handler = Some(self.emit_jump(OpCode::PushHandler));
+            self.compiler().handlers += 1;
+            self.begin_scope();
+            self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+            self.parse_variable("Expect exception name.");
+            self.emit_byte(OpCode::Dup as u8);
+            self.mark_initialized();
+            self.consume(TokenType::RightParen, "Expect ')' after exception name.");
+            self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+            self.block();
+            self.end_scope();
+            self.compiler().handlers -= 1;
+            self.emit_byte(OpCode::PopHandler as u8);
+            self.end_scope();
+        }
+        if let Some(finished) = finished {
+            self.patch_jump(finished);
+        }
+
+        let exits = self.compiler().tries.pop().unwrap().exits;
+        if !finally {
+            match catch_handler {
+                Some(catch_handler) => {
+                    let end = self.emit_jump(OpCode::Jump);
+                    self.patch_jump(catch_handler);
+                    self.discard_caught();
+                    self.emit_byte(OpCode::Throw as u8);
+                    self.patch_jump(end);
+                }
+                None => self.error_at_current("Expect 'catch' or 'finally' after try block."),
+            }
+            self.end_scope();
+            return;
+        }
+        self.consume(
+            TokenType::Finally,
+            "Expect 'catch' or 'finally' after try block.",
+        );
+
+        let normal = self.emit_jump(OpCode::Jump);
+        match catch_handler {
+            Some(catch_handler) => {
+                self.patch_jump(catch_handler);
+                self.discard_caught();
+            }
+            None => self.patch_jump(try_handler),
+        }
+        self.emit_bytes(OpCode::SetLocal as u8, slot);
+        self.emit_byte(OpCode::Pop as u8);
+        self.emit_byte(OpCode::True as u8);
+        self.emit_bytes(OpCode::SetLocal as u8, slot + 1);
+        self.emit_byte(OpCode::Pop as u8);
+        self.patch_jump(normal);
+        for &(_, jump) in &exits {
+            self.patch_jump(jump);
+        }
+
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'finally'.");
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+
+        self.emit_bytes(OpCode::GetLocal as u8, slot + 1);
+        let done = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        for (code, &(exit, _)) in exits.iter().enumerate() {
+            self.emit_bytes(OpCode::GetLocal as u8, slot + 1);
+            self.emit_constant(Value::Number(code as f64));
+            self.emit_byte(OpCode::Equal as u8);
+            let next = self.emit_jump(OpCode::JumpIfFalse);
+            self.emit_byte(OpCode::Pop as u8);
+            if let Exit::Return = exit {
+                self.emit_bytes(OpCode::GetLocal as u8, slot);
+            }
+            self.exit(exit);
+            self.patch_jump(next);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        self.emit_bytes(OpCode::GetLocal as u8, slot);
+        self.emit_byte(OpCode::Throw as u8);
+        self.patch_jump(done);
+        self.emit_byte(OpCode::Pop as u8);
+        self.end_scope();
+    }
+
+    // Whether the try statement starting at the current token has a finally
+    // block, which exits from the try block have to know about before it's
+    // reached. It looks ahead past the try and catch blocks.
+    fn has_finally(&self) -> bool {
+        // Skips past the end of the next block, returning whether there was
+        // one
+        fn skip_block(tokens: &mut impl Iterator<Item = TokenType>) -> bool {
+            let mut depth = 0;
+            for ty in tokens {
+                match ty {
+                    TokenType::LeftBrace => depth += 1,
+                    TokenType::RightBrace if depth <= 1 => return depth == 1,
+                    TokenType::RightBrace => depth -= 1,
+                    TokenType::Eof => return false,
+                    _ => (),
+                }
+            }
+            false
+        }
+
+        let scanner = self.scanner.clone().map(|token| token.ty);
+        let mut tokens = iter::once(self.current.ty).chain(scanner);
+        if !skip_block(&mut tokens) {
+            return false;
+        }
+        let mut next = tokens.next();
+        if next == Some(TokenType::Catch) {
+            if !skip_block(&mut tokens) {
+                return false;
+            }
+            next = tokens.next();
+        }
+        next == Some(TokenType::Finally)
+    }
+
+    // Whether the `(` just consumed starts an arrow function's parameters
+    // rather than a parenthesized expression, which is only clear once the
+    // `=>` after them is reached
+    fn is_arrow_function(&self) -> bool {
+        let scanner = self.scanner.clone().map(|token| token.ty);
+        let mut tokens = iter::once(self.current.ty).chain(scanner);
+        if self.check(TokenType::RightParen) {
+            tokens.next();
+        } else {
+            loop {
+                if tokens.next() != Some(TokenType::Identifier) {
+                    return false;
+                }
+                match tokens.next() {
+                    Some(TokenType::Comma) => (),
+                    Some(TokenType::RightParen) => break,
+                    _ => return false,
+                }
+            }
+        }
+        tokens.next() == Some(TokenType::Arrow)
+    }
+
+    // Compiles a try block, which runs with the handler just pushed
+    fn protected_block(&mut self, message: &str) {
+        self.consume(TokenType::LeftBrace, message);
+        self.compiler().handlers += 1;
+        self.begin_scope();
+        self.block();
+        self.end_scope();
+        self.compiler().handlers -= 1;
+        self.emit_byte(OpCode::PopHandler as u8);
+    }
+
+    // A catch block that throws leaves the exception it caught on the stack
+    // below the new one
+    fn discard_caught(&mut self) {
+        self.emit_byte(OpCode::Swap as u8);
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    fn throw_statement(&mut self) {
+        let keyword = self.previous.span.clone();
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        self.emit_at(keyword, |p| p.emit_byte(OpCode::Throw as u8));
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler().scope_depth -= 1;
+        let depth = self.compiler().scope_depth;
+        let ending = self.pop_locals(depth);
+        let compiler = self.compiler();
+        compiler.locals.truncate(compiler.locals.len() - ending);
+    }
+
+    // Emits code removing the locals in scopes deeper than `depth` from the
+    // stack, returning how many there are
+    fn pop_locals(&mut self, depth: usize) -> usize {
+        let popped = self
+            .compiler()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|l| l.depth.is_some_and(|d| d > depth))
+            .map(|l| l.is_captured)
+            .collect::<Vec<_>>();
+        for &is_captured in &popped {
+            if is_captured {
+                self.emit_byte(OpCode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(OpCode::Pop as u8);
+            }
+        }
+        popped.len()
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::Print as u8);
+    }
+
+    fn expression_statement(&mut self) {
+        let next = self.scanner.clone().next().map(|token| token.ty);
+        if self.check(TokenType::Identifier) && next == Some(TokenType::Comma) {
+            return self.multiple_assignment();
+        }
+        self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::Pop as u8);
+    }
+
+    // `a, b = 1, 2;` evaluates every value before assigning any, so that
+    // `a, b = b, a;` swaps them
+    fn multiple_assignment(&mut self) {
+        let mut targets = vec![];
+        loop {
+            self.consume(TokenType::Identifier, "Expect variable name.");
+            targets.push(self.previous.str);
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::Equal, "Expect '=' after assignment targets.");
+        let mut values = 0;
+        loop {
+            self.expression();
+            values += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        if values != targets.len() {
+            let targets = targets.len();
+            self.error(&format!(
+                "Expected {targets} values to assign but got {values}."
+            ));
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after assignment.");
+
+        // The last value is on top
+        for target in targets.into_iter().rev() {
+            let (arg, _, set_op) = self.resolve_variable(target);
+            self.emit_index(set_op, arg);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+    }
+
+    // Skips to the next statement boundary after an error, so that the rest
+    // of the source is still checked without reporting errors that follow
+    // from this one
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.ty != TokenType::Eof {
+            if self.previous.ty == TokenType::Semicolon {
+                return;
+            }
+            match self.current.ty {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::Import
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Break
+                | TokenType::Continue
+                | TokenType::Try
+                | TokenType::Throw => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn number(&mut self) {
+        match number::parse(self.previous.str) {
+            Some(value) => self.emit_constant(Value::Number(value)),
+            None => self.error("Invalid number."),
         }
     }
 
-    fn advance(&mut self) {
-        self.previous = mem::take(&mut self.current);
+    // Past the first 256 constants, values are loaded with a 24-bit index
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_index(OpCode::Constant, constant);
+    }
 
-        loop {
-            self.current = self.scanner.next().unwrap();
-            if self.current.ty != TokenType::Error {
-                break;
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.chunk().add_long_constant(value).unwrap_or_else(|_| {
+            self.error("Too many constants in one chunk.");
+            0
+        })
+    }
+
+    // Emits the long form of an instruction taking a constant once the
+    // constant's index doesn't fit in a byte
+    fn emit_index(&mut self, instruction: OpCode, index: usize) {
+        match u8::try_from(index) {
+            Ok(index) => self.emit_bytes(instruction as u8, index),
+            Err(_) => {
+                self.emit_byte(instruction.long().unwrap() as u8);
+                let [_, high, middle, low] = (index as u32).to_be_bytes();
+                self.emit_bytes(high, middle);
+                self.emit_byte(low);
             }
-            self.error_at_current(self.current.str);
         }
     }
 
-    fn consume(&mut self, ty: TokenType, message: &str) {
-        if self.current.ty == ty {
-            self.advance()
-        } else {
-            self.error_at_current(message);
+    fn grouping(&mut self) {
+        if self.is_arrow_function() {
+            return self.arrow_function();
         }
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        let token = &self.current;
-        self.error_at(&token.clone(), message);
+    // `cond ? a : b`, which compiles like an if expression. The else branch
+    // is parsed at the same precedence, making the operator right-associative
+    fn conditional(&mut self) {
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.expression();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        self.consume(TokenType::Colon, "Expect ':' after then branch of '?'.");
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(else_jump);
     }
 
-    fn error(&mut self, message: &str) {
-        let token = &self.previous;
-        self.error_at(&token.clone(), message);
+    fn if_expression(&mut self) {
+        if let Some(condition) = self.condition("if") {
+            return self.constant_if_expression(condition);
+        }
+
+        // Both branches leave exactly one value on the stack
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.expression();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        self.consume(TokenType::Else, "Expect 'else' after if expression branch.");
+        self.expression();
+        self.patch_jump(else_jump);
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
-        if self.panic_mode {
-            return;
+    // Only the taken branch is emitted, but the other still has to be parsed
+    fn constant_if_expression(&mut self, condition: bool) {
+        let line = self.previous.line;
+        self.branch(condition, Self::expression);
+        self.consume(TokenType::Else, "Expect 'else' after if expression branch.");
+        self.branch(!condition, Self::expression);
+        self.dead_branch_warning(line, condition);
+    }
+
+    fn if_statement(&mut self) {
+        if let Some(condition) = self.condition("if") {
+            return self.constant_if_statement(condition);
         }
-        self.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
 
-        match token.ty {
-            TokenType::EOF => eprint!(" at end"),
-            TokenType::Error => (),
-            _ => eprint!(" at '{}'", token.str),
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop as u8);
+        self.statement();
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop as u8);
+        if self.match_token(TokenType::Else) {
+            self.statement();
         }
+        self.patch_jump(else_jump);
+    }
 
-        eprintln!(": {message}");
-        self.had_error = true;
+    fn constant_if_statement(&mut self, condition: bool) {
+        let line = self.previous.line;
+        self.branch(condition, Self::statement);
+        // Without an else there's only dead code when the condition is false
+        if self.match_token(TokenType::Else) {
+            self.branch(!condition, Self::statement);
+            self.dead_branch_warning(line, condition);
+        } else if !condition {
+            self.dead_branch_warning(line, condition);
+        }
     }
 
-    fn emit_byte(&mut self, byte: u8) {
-        self.chunk.write(byte, self.previous.line);
+    fn while_statement(&mut self, label: Option<&'a str>) {
+        let loop_start = self.chunk().code.len();
+        match self.condition("while") {
+            Some(false) => {
+                let line = self.previous.line;
+                self.branch(false, |parser| parser.loop_body(label, loop_start));
+                self.warning(
+                    "dead-code",
+                    line,
+                    None,
+                    "Condition is always false, so the loop never runs.",
+                );
+            }
+            Some(true) => {
+                self.loop_body(label, loop_start);
+                self.emit_loop(loop_start);
+            }
+            None => {
+                let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+                self.emit_byte(OpCode::Pop as u8);
+                self.loop_body(label, loop_start);
+                self.emit_loop(loop_start);
+
+                self.patch_jump(exit_jump);
+                self.emit_byte(OpCode::Pop as u8);
+            }
+        }
+        self.end_loop();
     }
 
-    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    // The increment is compiled before the body but runs after it, so the
+    // body jumps back to it and it loops back to the condition
+    fn for_statement(&mut self, label: Option<&'a str>) {
+        // A variable declared in the initializer is scoped to the loop
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop as u8);
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.loop_body(label, loop_start);
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop as u8);
+        }
+        self.end_loop();
+        self.end_scope();
     }
 
-    fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Return as u8);
+    // Compiles the parenthesized condition after `keyword`. If it's a
+    // constant, nothing is emitted and its truthiness is returned instead.
+    fn condition(&mut self, keyword: &str) -> Option<bool> {
+        self.consume(
+            TokenType::LeftParen,
+            &format!("Expect '(' after '{keyword}'."),
+        );
+        let (start, constants) = (self.chunk().code.len(), self.chunk().constants.len());
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let condition = self.constant_condition(start);
+        if condition.is_some() {
+            self.truncate(start, constants);
+        }
+        condition
     }
 
-    pub fn end(&mut self) {
-        self.emit_return();
-        if cfg!(features = "debug_print_code") && !self.had_error {
-            self.chunk.disassemble("code");
+    // Compiles a branch, then throws its code away if it's never taken
+    fn branch(&mut self, taken: bool, compile: impl FnOnce(&mut Self)) {
+        let (code, constants) = (self.chunk().code.len(), self.chunk().constants.len());
+        compile(self);
+        if !taken {
+            self.truncate(code, constants);
         }
     }
 
-    pub fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
+    fn dead_branch_warning(&mut self, line: u32, condition: bool) {
+        let dead = if condition { "else" } else { "then" };
+        self.warning(
+            "dead-code",
+            line,
+            None,
+            &format!("Condition is always {condition}, so the {dead} branch never runs."),
+        );
     }
 
-    fn number(&mut self) {
-        let value = self.previous.str.parse::<f64>().unwrap();
-        self.emit_constant(Value::Number(value));
+    // The truthiness of the condition compiled from `start`, if it's a literal
+    fn constant_condition(&mut self, start: usize) -> Option<bool> {
+        self.literal_at(start).map(|v| is_truthy(&v))
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as u8, constant);
+    // The value of the code compiled from `start`, if it's a single literal
+    fn literal_at(&mut self, start: usize) -> Option<Value> {
+        let end = self.chunk().code.len();
+        self.literal_at_range(start, end)
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
-        self.chunk.add_constant(value).unwrap_or_else(|_| {
-            self.error("Too many constants in one chunk.");
-            0
-        })
+    fn literal_at_range(&mut self, start: usize, end: usize) -> Option<Value> {
+        let chunk = self.chunk();
+        let code = &chunk.code[start..end];
+        match code.first().map(|&op| OpCode::try_from(op)) {
+            Some(Ok(OpCode::True)) if code.len() == 1 => Some(Value::Bool(true)),
+            Some(Ok(OpCode::False)) if code.len() == 1 => Some(Value::Bool(false)),
+            Some(Ok(OpCode::Nil)) if code.len() == 1 => Some(Value::Nil),
+            Some(Ok(OpCode::Constant)) if code.len() == 2 => {
+                chunk.constants.get(code[1] as usize).cloned()
+            }
+            Some(Ok(OpCode::ConstantLong)) if code.len() == 4 => {
+                chunk.constants.get(chunk.read_long(start + 1)).cloned()
+            }
+            _ => None,
+        }
     }
 
-    fn grouping(&mut self) {
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+    fn emit_literal(&mut self, value: Value) {
+        match value {
+            Value::Bool(true) => self.emit_byte(OpCode::True as u8),
+            Value::Bool(false) => self.emit_byte(OpCode::False as u8),
+            Value::Nil => self.emit_byte(OpCode::Nil as u8),
+            value => self.emit_constant(value),
+        }
+    }
+
+    fn truncate(&mut self, code: usize, constants: usize) {
+        // Breaks and exits in code that's thrown away no longer need patching
+        for enclosing in &mut self.compiler().loops {
+            enclosing.breaks.retain(|&jump| jump < code);
+        }
+        for enclosing in &mut self.compiler().tries {
+            enclosing.exits.retain(|&(_, jump)| jump < code);
+        }
+        self.chunk().truncate(code);
+        self.chunk().constants.truncate(constants);
+    }
+
+    // The left operand is the result when it's falsey, without evaluating
+    // the right one
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop as u8);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    // Likewise, a truthy left operand skips the right one
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop as u8);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
     }
 
     fn unary(&mut self) {
         let operator_type = self.previous.ty;
+        let operator = self.previous.span.clone();
 
         // Compile the operand
+        let start = (self.chunk().code.len(), self.chunk().constants.len());
         self.parse_precedence(Precedence::Unary);
 
+        let folded = match (operator_type, self.literal_at(start.0)) {
+            (TokenType::Bang, Some(v)) => Some(Value::Bool(!is_truthy(&v))),
+            (TokenType::Minus, Some(Value::Number(n))) => Some(Value::Number(-n)),
+            (TokenType::MinusMinus, Some(Value::Number(n))) => Some(Value::Number(n)),
+            _ => None,
+        };
+        if let Some(value) = folded {
+            self.truncate(start.0, start.1);
+            self.emit_literal(value);
+            return;
+        }
+
         // Emit the operator instruction
-        match operator_type {
-            TokenType::Bang => self.emit_byte(OpCode::Not as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Negate as u8),
+        self.emit_at(operator, |p| match operator_type {
+            TokenType::Bang => p.emit_byte(OpCode::Not as u8),
+            TokenType::Minus => p.emit_byte(OpCode::Negate as u8),
+            TokenType::MinusMinus => p.emit_bytes(OpCode::Negate as u8, OpCode::Negate as u8),
             _ => unreachable!(),
-        }
+        });
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        // Each nested expression recurses, so bound the nesting to keep
+        // pathological input from overflowing the native stack
+        if self.depth == MAX_NESTING {
+            self.error_at_current("Expression nests too deeply.");
+            return;
+        }
+        self.depth += 1;
+
         self.advance();
+        // Only a variable parsed at the lowest precedence can be assigned to,
+        // otherwise `a + b = c` would assign to `b`
+        let can_assign = precedence as u8 <= Precedence::Assignment as u8;
+        let start = (self.chunk().code.len(), self.chunk().constants.len());
         let prefix_rule = self.get_rule(self.previous.ty).prefix;
         match prefix_rule {
             None => self.error("Expect expression."),
-            Some(r) => self.invoke_parse_fn(r),
+            Some(r) => self.invoke_parse_fn(r, can_assign),
         }
 
         while precedence as u8 <= self.get_rule(self.current.ty).precedence as u8 {
             self.advance();
-            let infix_rule = self.get_rule(self.previous.ty).infix;
-            self.invoke_parse_fn(infix_rule.unwrap());
+            self.left_operand = start;
+            match self.get_rule(self.previous.ty).infix {
+                Some(r) => self.invoke_parse_fn(r, can_assign),
+                None => self.error("Expect expression."),
+            }
         }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Invalid assignment target.");
+        }
+
+        self.depth -= 1;
     }
 
-    fn invoke_parse_fn(&mut self, parse_fn: ParseFn) {
+    fn invoke_parse_fn(&mut self, parse_fn: ParseFn, can_assign: bool) {
         match parse_fn {
             ParseFn::Grouping => self.grouping(),
+            ParseFn::Block => self.block_expression(),
             ParseFn::Unary => self.unary(),
+            ParseFn::Increment => self.prefix_increment(),
             ParseFn::Binary => self.binary(),
             ParseFn::Number => self.number(),
             ParseFn::Literal => self.literal(),
             ParseFn::String => self.string(),
+            ParseFn::If => self.if_expression(),
+            ParseFn::Conditional => self.conditional(),
+            ParseFn::Dot => self.dot(can_assign),
+            ParseFn::Variable => self.variable(can_assign),
+            ParseFn::And => self.and(),
+            ParseFn::Or => self.or(),
+            ParseFn::Call => self.call(),
+            ParseFn::This => self.this(),
+            ParseFn::Index => self.index(),
         }
     }
 
     fn binary(&mut self) {
         let operator_type = self.previous.ty;
+        let operator = self.previous.span.clone();
+        let start = self.left_operand;
+        let right = self.chunk().code.len();
         let rule = self.get_rule(operator_type);
-        self.parse_precedence((rule.precedence as u8 + 1).try_into().unwrap());
-        match operator_type {
-            TokenType::BangEqual => self.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal as u8),
-            TokenType::Greater => self.emit_byte(OpCode::Greater as u8),
-            TokenType::GreaterEqual => self.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
-            TokenType::Less => self.emit_byte(OpCode::Less as u8),
-            TokenType::LessEqual => self.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
-            TokenType::Plus => self.emit_byte(OpCode::Add as u8),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as u8),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as u8),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as u8),
+        let precedence = (rule.precedence as u8 + 1)
+            .try_into()
+            .unwrap_or(Precedence::Primary);
+        self.parse_precedence(precedence);
+
+        // Literal operands are worked out now instead of at runtime
+        let operands = self
+            .literal_at_range(start.0, right)
+            .zip(self.literal_at(right));
+        if let Some(value) = operands.and_then(|(a, b)| fold(operator_type, a, b)) {
+            self.truncate(start.0, start.1);
+            self.emit_literal(value);
+            return;
+        }
+
+        self.emit_at(operator, |p| match operator_type {
+            TokenType::BangEqual => p.emit_bytes(OpCode::Equal as u8, OpCode::Not as u8),
+            TokenType::EqualEqual => p.emit_byte(OpCode::Equal as u8),
+            TokenType::Greater => p.emit_byte(OpCode::Greater as u8),
+            TokenType::GreaterEqual => p.emit_bytes(OpCode::Less as u8, OpCode::Not as u8),
+            TokenType::Less => p.emit_byte(OpCode::Less as u8),
+            TokenType::LessEqual => p.emit_bytes(OpCode::Greater as u8, OpCode::Not as u8),
+            TokenType::Is => p.emit_byte(OpCode::Is as u8),
+            TokenType::Plus => p.emit_byte(OpCode::Add as u8),
+            TokenType::Minus => p.emit_byte(OpCode::Subtract as u8),
+            TokenType::Star => p.emit_byte(OpCode::Multiply as u8),
+            TokenType::Slash => p.emit_byte(OpCode::Divide as u8),
             _ => unreachable!(),
+        });
+    }
+
+    // A call's code covers its parenthesized arguments
+    fn call(&mut self) {
+        let start = self.previous.span.start;
+        let arg_count = self.argument_list();
+        let end = self.previous.span.end;
+        self.emit_at(start..end, |p| p.emit_bytes(OpCode::Call as u8, arg_count));
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARGS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
         }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous.str);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_index(OpCode::SetProperty, name);
+        } else if let Some(step) = self.match_increment() {
+            // Loads the old value under the instance, which is still needed
+            // to set the new one
+            self.emit_byte(OpCode::Dup as u8);
+            self.emit_index(OpCode::GetProperty, name);
+            self.emit_byte(OpCode::Swap as u8);
+            self.increment_property(name, step);
+            self.emit_byte(OpCode::Pop as u8);
+        } else {
+            self.emit_index(OpCode::GetProperty, name);
+        }
+    }
+
+    fn index(&mut self) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+        self.emit_byte(OpCode::GetIndex as u8);
+    }
+
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.make_constant(Value::from_string(name.to_string()))
     }
 
     fn literal(&mut self) {
@@ -189,16 +1926,154 @@ impl<'a> Parser<'a> {
         }
     }
 
+    fn variable(&mut self, can_assign: bool) {
+        self.named_variable(self.previous.str, can_assign);
+    }
+
+    fn this(&mut self) {
+        if self.class_depth == 0 {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // Methods keep the receiver in a local named `this`
+        self.variable(false);
+    }
+
+    // The operand and instructions that get and set the variable `name`
+    fn resolve_variable(&mut self, name: &'a str) -> (usize, OpCode, OpCode) {
+        let current = self.compilers.len() - 1;
+        if let Some(slot) = self.resolve_local(current, name) {
+            (slot as usize, OpCode::GetLocal, OpCode::SetLocal)
+        } else if let Some(index) = self.resolve_upvalue(current, name) {
+            (index as usize, OpCode::GetUpvalue, OpCode::SetUpvalue)
+        } else {
+            (
+                self.identifier_constant(name),
+                OpCode::GetGlobal,
+                OpCode::SetGlobal,
+            )
+        }
+    }
+
+    fn named_variable(&mut self, name: &'a str, can_assign: bool) {
+        let (arg, get_op, set_op) = self.resolve_variable(name);
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_index(set_op, arg);
+        } else if name == "this" {
+            // Which can't be incremented either
+            self.emit_index(get_op, arg);
+        } else if let Some(step) = self.match_increment() {
+            // The old value stays on the stack as the result
+            self.emit_index(get_op, arg);
+            self.emit_byte(OpCode::Dup as u8);
+            self.emit_step(step);
+            self.emit_index(set_op, arg);
+            self.emit_byte(OpCode::Pop as u8);
+        } else {
+            self.emit_index(get_op, arg);
+        }
+    }
+
+    // `++` or `--` before a variable or a chain of properties, which results
+    // in the new value. Postfix ones are compiled along with their target.
+    fn prefix_increment(&mut self) {
+        // Before anything that can't be decremented, `--` is two negations,
+        // as it is in clox
+        if self.previous.ty == TokenType::MinusMinus
+            && !self.check(TokenType::Identifier)
+            && !self.check(TokenType::This)
+        {
+            return self.unary();
+        }
+        let operator = self.previous.str;
+        let step = match self.previous.ty {
+            TokenType::PlusPlus => OpCode::Add,
+            _ => OpCode::Subtract,
+        };
+        let message = format!("Expect variable or property after '{operator}'.");
+        if self.match_token(TokenType::This) {
+            self.this();
+            if !self.check(TokenType::Dot) {
+                self.error_at_current(&message);
+                return;
+            }
+        } else {
+            self.consume(TokenType::Identifier, &message);
+            let name = self.previous.str;
+            if !self.check(TokenType::Dot) {
+                self.increment_variable(name, step);
+                return self.end_increment_target();
+            }
+            self.named_variable(name, false);
+        }
+
+        // Every property but the last is just loaded
+        loop {
+            self.consume(TokenType::Dot, &message);
+            self.consume(TokenType::Identifier, "Expect property name after '.'.");
+            let name = self.identifier_constant(self.previous.str);
+            if !self.check(TokenType::Dot) {
+                self.increment_property(name, step);
+                return self.end_increment_target();
+            }
+            self.emit_index(OpCode::GetProperty, name);
+        }
+    }
+
+    // Calls and indexing can't follow, since `++f()` would otherwise
+    // increment `f` and call the result
+    fn end_increment_target(&mut self) {
+        if self.check(TokenType::LeftParen) || self.check(TokenType::LeftBracket) {
+            self.error_at_current("Invalid increment target.");
+        }
+    }
+
+    fn increment_variable(&mut self, name: &'a str, step: OpCode) {
+        let (arg, get_op, set_op) = self.resolve_variable(name);
+        self.emit_index(get_op, arg);
+        self.emit_step(step);
+        self.emit_index(set_op, arg);
+    }
+
+    // Increments the property `name` of the instance on top of the stack,
+    // replacing the instance with the new value
+    fn increment_property(&mut self, name: usize, step: OpCode) {
+        self.emit_byte(OpCode::Dup as u8);
+        self.emit_index(OpCode::GetProperty, name);
+        self.emit_step(step);
+        self.emit_index(OpCode::SetProperty, name);
+    }
+
+    fn match_increment(&mut self) -> Option<OpCode> {
+        if self.match_token(TokenType::PlusPlus) {
+            Some(OpCode::Add)
+        } else if self.match_token(TokenType::MinusMinus) {
+            Some(OpCode::Subtract)
+        } else {
+            None
+        }
+    }
+
+    // Adds or subtracts 1, with `step` being Add or Subtract
+    fn emit_step(&mut self, step: OpCode) {
+        self.emit_constant(Value::Number(1.0));
+        self.emit_byte(step as u8);
+    }
+
     fn string(&mut self) {
-        self.emit_constant(Value::from_string(self.previous.str.to_string()))
+        // Trim the surrounding quotes
+        let s = &self.previous.str[1..self.previous.str.len() - 1];
+        self.emit_constant(Value::from_string(s.to_string()))
     }
 
     fn get_rule(&mut self, token_type: TokenType) -> ParseRule {
         match token_type {
             TokenType::LeftParen => ParseRule {
                 prefix: Some(ParseFn::Grouping),
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(ParseFn::Call),
+                precedence: Precedence::Call,
             },
             TokenType::RightParen => ParseRule {
                 prefix: None,
@@ -206,6 +2081,16 @@ impl<'a> Parser<'a> {
                 precedence: Precedence::None,
             },
             TokenType::LeftBrace => ParseRule {
+                prefix: Some(ParseFn::Block),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::LeftBracket => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Index),
+                precedence: Precedence::Call,
+            },
+            TokenType::RightBracket => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -215,26 +2100,41 @@ impl<'a> Parser<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::Comma => ParseRule {
+            TokenType::Colon => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::Dot => ParseRule {
+            TokenType::Comma => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Dot => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Dot),
+                precedence: Precedence::Call,
+            },
             TokenType::Minus => ParseRule {
                 prefix: Some(ParseFn::Unary),
                 infix: Some(ParseFn::Binary),
                 precedence: Precedence::Term,
             },
+            TokenType::MinusMinus | TokenType::PlusPlus => ParseRule {
+                prefix: Some(ParseFn::Increment),
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Plus => ParseRule {
-                prefix: Some(ParseFn::Unary),
+                prefix: None,
                 infix: Some(ParseFn::Binary),
                 precedence: Precedence::Term,
             },
+            TokenType::Question => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Conditional),
+                precedence: Precedence::Conditional,
+            },
             TokenType::Semicolon => ParseRule {
                 prefix: None,
                 infix: None,
@@ -265,6 +2165,11 @@ impl<'a> Parser<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Arrow => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::EqualEqual => ParseRule {
                 prefix: None,
                 infix: Some(ParseFn::Binary),
@@ -291,7 +2196,7 @@ impl<'a> Parser<'a> {
                 precedence: Precedence::Comparison,
             },
             TokenType::Identifier => ParseRule {
-                prefix: None,
+                prefix: Some(ParseFn::Variable),
                 infix: None,
                 precedence: Precedence::None,
             },
@@ -306,6 +2211,16 @@ impl<'a> Parser<'a> {
                 precedence: Precedence::None,
             },
             TokenType::And => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::And),
+                precedence: Precedence::And,
+            },
+            TokenType::Break => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Catch => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -315,6 +2230,11 @@ impl<'a> Parser<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Continue => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::Else => ParseRule {
                 prefix: None,
                 infix: None,
@@ -325,6 +2245,11 @@ impl<'a> Parser<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Finally => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
             TokenType::For => ParseRule {
                 prefix: None,
                 infix: None,
@@ -336,10 +2261,15 @@ impl<'a> Parser<'a> {
                 precedence: Precedence::None,
             },
             TokenType::If => ParseRule {
-                prefix: None,
+                prefix: Some(ParseFn::If),
                 infix: None,
                 precedence: Precedence::None,
             },
+            TokenType::Is => ParseRule {
+                prefix: None,
+                infix: Some(ParseFn::Binary),
+                precedence: Precedence::Comparison,
+            },
             TokenType::Nil => ParseRule {
                 prefix: Some(ParseFn::Literal),
                 infix: None,
@@ -347,8 +2277,8 @@ impl<'a> Parser<'a> {
             },
             TokenType::Or => ParseRule {
                 prefix: None,
-                infix: None,
-                precedence: Precedence::None,
+                infix: Some(ParseFn::Or),
+                precedence: Precedence::Or,
             },
             TokenType::Print => ParseRule {
                 prefix: None,
@@ -366,6 +2296,21 @@ impl<'a> Parser<'a> {
                 precedence: Precedence::None,
             },
             TokenType::This => ParseRule {
+                prefix: Some(ParseFn::This),
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Import => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Throw => ParseRule {
+                prefix: None,
+                infix: None,
+                precedence: Precedence::None,
+            },
+            TokenType::Try => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -385,12 +2330,12 @@ impl<'a> Parser<'a> {
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::Error => ParseRule {
+            TokenType::Pragma | TokenType::Error => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
             },
-            TokenType::EOF => ParseRule {
+            TokenType::Eof => ParseRule {
                 prefix: None,
                 infix: None,
                 precedence: Precedence::None,
@@ -399,22 +2344,152 @@ impl<'a> Parser<'a> {
     }
 }
 
-pub fn compile(source: &str) -> Result<Chunk> {
-    let scanner = Scanner::new(&source);
-    let mut chunk = Chunk::new();
-    let mut parser = Parser::new(scanner, &mut chunk);
+/// Compiles `source`, reporting errors and warnings on stderr. Its lines
+/// map back to their files through `source_map` when it had includes
+/// expanded.
+pub fn compile(source: &str, source_map: Option<SourceMap>) -> Result<Program> {
+    let (program, diagnostics) = compile_with_diagnostics(source, source_map);
+    for diagnostic in diagnostics {
+        eprintln!("{}", diagnostic.with_snippet(source));
+    }
+    program
+}
+
+/// Compiles `source`, returning its errors and warnings rather than
+/// reporting them.
+pub fn compile_with_diagnostics(
+    source: &str,
+    source_map: Option<SourceMap>,
+) -> (Result<Program>, Vec<Diagnostic>) {
+    let (program, diagnostics, _) = compile_source(source, source_map, false, false);
+    (program, diagnostics)
+}
+
+/// Like `compile_with_diagnostics`, but where clox and rlox read the same
+/// source differently, it's read the way clox does, e.g. `--x` is `-(-x)`.
+pub fn compile_clox(
+    source: &str,
+    source_map: Option<SourceMap>,
+) -> (Result<Program>, Vec<Diagnostic>) {
+    let (program, diagnostics, _) = compile_source(source, source_map, false, true);
+    (program, diagnostics)
+}
+
+/// Like `compile_with_diagnostics`, but for input typed at a prompt, where
+/// the `;` ending the last statement can be left off. If it was, it's
+/// added to `input`, so that the input still compiles as part of a script.
+pub fn compile_interactive(input: &mut String) -> (Result<Program>, Vec<Diagnostic>) {
+    let (program, diagnostics, filled_semicolon) = compile_source(input, None, true, false);
+    if let (Ok(_), Some(offset)) = (&program, filled_semicolon) {
+        input.insert(offset, ';');
+    }
+    (program, diagnostics)
+}
+
+fn compile_source(
+    source: &str,
+    source_map: Option<SourceMap>,
+    implicit_semicolon: bool,
+    clox_compat: bool,
+) -> (Result<Program>, Vec<Diagnostic>, Option<usize>) {
+    let mut parser = Parser::new(source, source_map.as_ref());
+    parser.implicit_semicolon = implicit_semicolon;
+    if clox_compat {
+        parser.scanner = Scanner::without_increments(source);
+        parser.allowed.extend(WARNINGS);
+    }
 
     parser.had_error = false;
     parser.panic_mode = false;
 
     parser.advance();
-    parser.expression();
-    parser.consume(TokenType::EOF, "Expect end of expression");
-    parser.end();
-    if parser.had_error {
-        bail!("Parser had error");
+    while !parser.match_token(TokenType::Eof) {
+        parser.declaration();
+    }
+    let (script, _) = parser.end();
+    let mut diagnostics = mem::take(&mut parser.diagnostics);
+    diagnostics.retain(|d| !d.name.is_some_and(|name| parser.allowed.contains(name)));
+    if parser.strict {
+        for diagnostic in &mut diagnostics {
+            if diagnostic.severity == Severity::Warning {
+                diagnostic.severity = Severity::Error;
+                diagnostic.header = diagnostic.header.replacen("Warning", "Error", 1);
+                parser.had_error = true;
+            }
+        }
+    }
+    let filled_semicolon = parser.filled_semicolon;
+    let program = if parser.had_error {
+        Err(anyhow!("Parser had error"))
     } else {
-        Ok(chunk)
+        let mut program = Program::new(script.chunk, source_map);
+        program.source = Some(source.into());
+        Ok(program)
+    };
+    (program, diagnostics, filled_semicolon)
+}
+
+// What `a operator b` evaluates to, for the operators and operands that
+// can't fail at runtime. Comparisons are worked out the way the VM does them,
+// so that NaN behaves the same either way.
+fn fold(operator: TokenType, a: Value, b: Value) -> Option<Value> {
+    let value = match (operator, &a, &b) {
+        (TokenType::EqualEqual, _, _) => Value::Bool(a == b),
+        (TokenType::BangEqual, _, _) => Value::Bool(a != b),
+        (TokenType::Plus, Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+        (TokenType::Minus, Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+        (TokenType::Star, Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+        (TokenType::Slash, Value::Number(a), Value::Number(b)) => Value::Number(a / b),
+        (TokenType::Greater, Value::Number(a), Value::Number(b)) => Value::Bool(a > b),
+        (TokenType::GreaterEqual, Value::Number(a), Value::Number(b)) => {
+            Value::Bool(a.partial_cmp(b) != Some(Ordering::Less))
+        }
+        (TokenType::Less, Value::Number(a), Value::Number(b)) => Value::Bool(a < b),
+        (TokenType::LessEqual, Value::Number(a), Value::Number(b)) => {
+            Value::Bool(a.partial_cmp(b) != Some(Ordering::Greater))
+        }
+        (TokenType::Plus, _, _) if a.is_string() && b.is_string() => {
+            Value::from_string(format!("{}{}", a.as_str().unwrap(), b.as_str().unwrap()))
+        }
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn is_truthy(value: &Value) -> bool {
+    !matches!(value, Value::Nil | Value::Bool(false))
+}
+
+impl Diagnostic {
+    /// The line of `source` the diagnostic is on, and a line to go under it
+    /// marking its span with carets followed by the message. There's nothing
+    /// to mark for a diagnostic without a span.
+    pub fn underline<'s>(&self, source: &'s str) -> Option<(&'s str, String)> {
+        let (line, carets) = source::underline(source, self.span.clone()?)?;
+        let severity = match self.severity {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+        };
+        Some((line, format!("{carets} {severity}: {}", self.message)))
+    }
+
+    /// The diagnostic as it's reported for a file: its usual line, followed
+    /// by the line of `source` it's on with its span underlined.
+    pub fn with_snippet(&self, source: &str) -> String {
+        let snippet = self
+            .span
+            .clone()
+            .and_then(|span| source::underline(source, span));
+        match snippet {
+            Some((line, carets)) => format!("{self}\n{line}\n{carets}"),
+            None => self.to_string(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.header, self.message)
     }
 }
 
@@ -422,15 +2497,16 @@ pub fn compile(source: &str) -> Result<Chunk> {
 #[derive(Clone, Copy, Debug, Eq, PartialEq, FromPrimitive, ToPrimitive)]
 enum Precedence {
     None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
+    Assignment,  // =
+    Conditional, // ?:
+    Or,          // or
+    And,         // and
+    Equality,    // == !=
+    Comparison,  // < > <= >=
+    Term,        // + -
+    Factor,      // * /
+    Unary,       // ! -
+    Call,        // . ()
     Primary,
 }
 
@@ -447,11 +2523,22 @@ impl TryFrom<u8> for Precedence {
 
 enum ParseFn {
     Grouping,
+    Block,
     Unary,
+    Increment,
     Binary,
     Number,
     Literal,
     String,
+    If,
+    Conditional,
+    Dot,
+    Variable,
+    And,
+    Or,
+    Call,
+    This,
+    Index,
 }
 
 struct ParseRule {
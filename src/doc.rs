@@ -0,0 +1,172 @@
+use std::fmt::Write;
+
+use crate::scanner::{Scanner, Token, TokenType};
+
+pub enum Format {
+    Markdown,
+    Html,
+}
+
+enum Kind {
+    Function,
+    Class,
+    Method,
+}
+
+struct Item {
+    kind: Kind,
+    signature: String,
+    doc: Vec<String>,
+}
+
+pub fn generate(source: &str, title: &str, format: Format) -> String {
+    let items = collect(source);
+    match format {
+        Format::Markdown => markdown(title, &items),
+        Format::Html => html(title, &items),
+    }
+}
+
+// Doc comments aren't tokens, so they are recovered from the text the scanner
+// skips between two tokens. Only the `///` lines directly above a declaration
+// are attached to it.
+fn collect(source: &str) -> Vec<Item> {
+    let tokens: Vec<Token> = Scanner::new(source)
+        .take_while(|t| t.ty != TokenType::Eof)
+        .filter(|t| t.ty != TokenType::Error)
+        .collect();
+
+    let mut items = vec![];
+    let mut offset = 0;
+    let mut depth = 0;
+    let mut class_depth = None;
+    for (i, token) in tokens.iter().enumerate() {
+        let doc = doc_comment(&source[offset..token.span.start]);
+        offset = token.span.end;
+
+        match token.ty {
+            TokenType::LeftBrace => depth += 1,
+            TokenType::RightBrace => {
+                depth -= 1;
+                if class_depth == Some(depth) {
+                    class_depth = None;
+                }
+            }
+            TokenType::Fun if depth == 0 => {
+                if let Some(signature) = signature(&tokens[i + 1..]) {
+                    items.push(Item {
+                        kind: Kind::Function,
+                        signature,
+                        doc: docstring(&tokens[i..]).unwrap_or(doc),
+                    });
+                }
+            }
+            TokenType::Class if depth == 0 => {
+                if let Some(name) = tokens.get(i + 1).filter(|t| is_public(t)) {
+                    items.push(Item {
+                        kind: Kind::Class,
+                        signature: name.str.to_string(),
+                        doc: docstring(&tokens[i..]).unwrap_or(doc),
+                    });
+                    class_depth = Some(depth);
+                }
+            }
+            TokenType::Identifier if class_depth.map(|d| d + 1) == Some(depth) => {
+                if let Some(signature) = signature(&tokens[i..]) {
+                    items.push(Item {
+                        kind: Kind::Method,
+                        signature,
+                        doc: docstring(&tokens[i..]).unwrap_or(doc),
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+    items
+}
+
+pub(crate) fn doc_comment(gap: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for line in gap.lines() {
+        let line = line.trim();
+        if let Some(doc) = line.strip_prefix("///") {
+            lines.push(doc.strip_prefix(' ').unwrap_or(doc).to_string());
+        } else if !line.is_empty() {
+            lines.clear();
+        }
+    }
+    lines
+}
+
+// The `"...";` starting the body of the declaration at the start of
+// `tokens`, which the compiler takes as its doc like a doc comment
+fn docstring(tokens: &[Token]) -> Option<Vec<String>> {
+    let body = tokens.iter().position(|t| t.ty == TokenType::LeftBrace)?;
+    match &tokens[body + 1..] {
+        [string, semicolon, ..]
+            if string.ty == TokenType::String && semicolon.ty == TokenType::Semicolon =>
+        {
+            let text = string.str[1..string.str.len() - 1].trim();
+            Some(text.lines().map(String::from).collect())
+        }
+        _ => None,
+    }
+}
+
+// Reads `name(a, b)` from the start of `tokens`.
+fn signature(tokens: &[Token]) -> Option<String> {
+    let (name, rest) = tokens.split_first()?;
+    if !is_public(name) || rest.first()?.ty != TokenType::LeftParen {
+        return None;
+    }
+    let params: Vec<&str> = rest[1..]
+        .iter()
+        .take_while(|t| t.ty != TokenType::RightParen)
+        .filter(|t| t.ty == TokenType::Identifier)
+        .map(|t| t.str)
+        .collect();
+    Some(format!("{}({})", name.str, params.join(", ")))
+}
+
+fn is_public(token: &Token) -> bool {
+    token.ty == TokenType::Identifier && !token.str.starts_with('_')
+}
+
+fn markdown(title: &str, items: &[Item]) -> String {
+    let mut out = format!("# {title}\n");
+    for item in items {
+        let heading = match item.kind {
+            Kind::Function => format!("\n## `fun {}`\n", item.signature),
+            Kind::Class => format!("\n## `class {}`\n", item.signature),
+            Kind::Method => format!("\n### `{}`\n", item.signature),
+        };
+        out.push_str(&heading);
+        if !item.doc.is_empty() {
+            writeln!(out, "\n{}", item.doc.join("\n")).unwrap();
+        }
+    }
+    out
+}
+
+fn html(title: &str, items: &[Item]) -> String {
+    let mut out = format!("<h1>{}</h1>\n", escape(title));
+    for item in items {
+        let heading = match item.kind {
+            Kind::Function => format!("<h2><code>fun {}</code></h2>", escape(&item.signature)),
+            Kind::Class => format!("<h2><code>class {}</code></h2>", escape(&item.signature)),
+            Kind::Method => format!("<h3><code>{}</code></h3>", escape(&item.signature)),
+        };
+        writeln!(out, "{heading}").unwrap();
+        if !item.doc.is_empty() {
+            writeln!(out, "<p>{}</p>", escape(&item.doc.join("\n"))).unwrap();
+        }
+    }
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,86 @@
+use std::{collections::HashMap, mem, sync::Mutex};
+
+use libloading::{Library, Symbol};
+
+use crate::value::{NativeFn, Value};
+
+/// The version of the interface below. An extension exports the version it
+/// was built against as `RLOX_EXTENSION_ABI`, and is only loaded when it
+/// matches. Natives are Rust functions taking `Value`s, so an extension also
+/// has to be built with the same compiler and version of this crate.
+pub const ABI_VERSION: u32 = 1;
+
+/// An extension's natives, which its exported `rlox_register` function
+/// defines:
+///
+/// ```ignore
+/// #[no_mangle]
+/// pub static RLOX_EXTENSION_ABI: u32 = rlox::extension::ABI_VERSION;
+///
+/// #[no_mangle]
+/// pub fn rlox_register(registry: &mut rlox::extension::Registry) {
+///     registry.define("sha256", sha256);
+/// }
+/// ```
+#[derive(Default)]
+pub struct Registry {
+    natives: Natives,
+}
+
+impl Registry {
+    pub fn define(&mut self, name: &str, function: NativeFn) {
+        self.natives.push((name.to_string(), function));
+    }
+}
+
+type Register = fn(&mut Registry);
+type Natives = Vec<(String, NativeFn)>;
+
+// Natives loaded from each path, so loading an extension again gives the
+// same ones without running its registration again
+static LOADED: Mutex<Option<HashMap<String, Natives>>> = Mutex::new(None);
+
+/// Loads the extension at a path, returning an instance with its natives as
+/// fields.
+pub fn load(args: &[Value]) -> Result<Value, String> {
+    let [path] = args else {
+        return Err(format!("Expected 1 arguments but got {}.", args.len()));
+    };
+    let path = path.as_str().ok_or("loadExtension() takes a path.")?;
+    let mut loaded = LOADED.lock().unwrap();
+    let loaded = loaded.get_or_insert_with(HashMap::new);
+    if !loaded.contains_key(path) {
+        loaded.insert(path.to_string(), register(path)?);
+    }
+
+    let class = Value::from_class("extension".to_string(), None);
+    let extension = Value::from_instance(class.as_class().unwrap().clone());
+    let mut fields = extension.as_instance().unwrap().fields.lock().unwrap();
+    for (name, function) in &loaded[path] {
+        fields.insert(name.clone(), Value::from_native(name, *function));
+    }
+    drop(fields);
+    Ok(extension)
+}
+
+fn register(path: &str) -> Result<Natives, String> {
+    let error = |e: libloading::Error| format!("Could not load extension \"{path}\": {e}");
+    // SAFETY: this runs the library's initializers, and trusts its exports
+    // to have the types the interface above gives them
+    unsafe {
+        let library = Library::new(path).map_err(error)?;
+        let abi: Symbol<*const u32> = library.get(b"RLOX_EXTENSION_ABI\0").map_err(error)?;
+        if **abi != ABI_VERSION {
+            return Err(format!(
+                "Extension \"{path}\" was built for version {} of the interface, not {ABI_VERSION}.",
+                **abi
+            ));
+        }
+        let register: Symbol<Register> = library.get(b"rlox_register\0").map_err(error)?;
+        let mut registry = Registry::default();
+        register(&mut registry);
+        // Unloading it would leave its natives pointing at unmapped code
+        mem::forget(library);
+        Ok(registry.natives)
+    }
+}
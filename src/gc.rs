@@ -0,0 +1,249 @@
+use std::{
+    collections::HashSet,
+    mem,
+    sync::{Arc, Mutex, Weak},
+};
+
+use crate::value::{Class, Closure, Obj, Upvalue, Value};
+
+// Collections start once this many objects are tracked, unless configured
+// otherwise, and then whenever the number tracked doubles since the last one
+pub(crate) const FIRST_GC: usize = 1024;
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// Finds the objects a VM can no longer reach and frees them.
+///
+/// Objects are reference counted, which frees everything except cycles.
+/// Those can only be made through something mutable: a closed upvalue, an
+/// instance's fields or a class's methods. The heap tracks each of those,
+/// marks the ones reachable from the roots, and clears the rest, which
+/// breaks any cycle running through them so reference counting can free it.
+pub(crate) struct Heap {
+    tracked: Vec<Tracked>,
+    next_gc: usize,
+    // The fewest objects that are tracked before a collection
+    threshold: usize,
+    // Collect on every allocation, to shake out objects that aren't rooted
+    stress: bool,
+}
+
+enum Tracked {
+    Upvalue(Weak<Mutex<Upvalue>>),
+    Instance(Weak<Obj>),
+    Class(Weak<Class>),
+}
+
+/// Where the VM keeps the objects it can reach.
+pub(crate) enum Root<'a> {
+    Value(&'a Value),
+    Closure(&'a Arc<Closure>),
+    Upvalue(&'a Arc<Mutex<Upvalue>>),
+}
+
+// Objects that have been found but whose references haven't been followed
+enum Gray {
+    Value(Value),
+    Closure(Arc<Closure>),
+    Upvalue(Arc<Mutex<Upvalue>>),
+    Class(Arc<Class>),
+}
+
+impl Heap {
+    pub(crate) fn new(stress: bool, threshold: usize) -> Heap {
+        Heap {
+            tracked: vec![],
+            next_gc: if stress { 1 } else { threshold },
+            threshold,
+            stress,
+        }
+    }
+
+    /// How many objects are tracked, including any not yet found to be
+    /// garbage.
+    pub(crate) fn len(&self) -> usize {
+        self.tracked.len()
+    }
+
+    /// Tracks an instance or class. Other values are ignored, since they
+    /// can't be part of a cycle on their own.
+    pub(crate) fn track(&mut self, value: &Value) {
+        let Value::Obj(obj) = value else {
+            return;
+        };
+        match obj.as_ref() {
+            Obj::Instance(_) => self.tracked.push(Tracked::Instance(Arc::downgrade(obj))),
+            Obj::Class(class) => self.tracked.push(Tracked::Class(Arc::downgrade(class))),
+            _ => (),
+        }
+    }
+
+    pub(crate) fn track_upvalue(&mut self, upvalue: &Arc<Mutex<Upvalue>>) {
+        self.tracked.push(Tracked::Upvalue(Arc::downgrade(upvalue)));
+    }
+
+    pub(crate) fn should_collect(&self) -> bool {
+        self.tracked.len() >= self.next_gc
+    }
+
+    /// Frees everything that can't be reached from `roots`, returning how
+    /// many tracked objects that was and roughly how many bytes they took up.
+    pub(crate) fn collect<'a>(&mut self, roots: impl Iterator<Item = Root<'a>>) -> (usize, usize) {
+        let marked = mark(roots);
+        let before = self.tracked.len();
+        let mut garbage = vec![];
+        let mut bytes = 0;
+        self.tracked.retain(|tracked| match tracked.address() {
+            // Already freed by reference counting
+            None => false,
+            Some(address) if marked.contains(&address) => true,
+            Some(_) => {
+                bytes += tracked.size();
+                garbage.push(tracked.clear());
+                false
+            }
+        });
+        let freed = garbage.len();
+        // Dropped only once every object is cleared, so nothing is freed
+        // while it's still being looked at
+        drop(garbage);
+
+        self.next_gc = if self.stress {
+            self.tracked.len() + 1
+        } else {
+            (self.tracked.len() * HEAP_GROW_FACTOR).max(self.threshold)
+        };
+        debug_assert!(self.tracked.len() <= before);
+        (freed, bytes)
+    }
+
+    /// Clears every tracked object, for when the VM that could reach them
+    /// goes away.
+    pub(crate) fn free_all(&mut self) {
+        let garbage: Vec<_> = self.tracked.drain(..).map(|t| t.clear()).collect();
+        drop(garbage);
+    }
+}
+
+impl Tracked {
+    // Identifies the object the same way marking does, if it's still alive
+    fn address(&self) -> Option<usize> {
+        match self {
+            Tracked::Upvalue(upvalue) => upvalue.upgrade().map(|u| address(&u)),
+            Tracked::Instance(instance) => instance.upgrade().map(|i| address(&i)),
+            Tracked::Class(class) => class.upgrade().map(|c| address(&c)),
+        }
+    }
+
+    // An estimate of the memory the object takes up, which is its own size
+    // plus that of its entries. Strings and functions it refers to are left
+    // out, since they may well be shared.
+    fn size(&self) -> usize {
+        const ENTRY: usize = mem::size_of::<(String, Value)>();
+        match self {
+            Tracked::Upvalue(_) => mem::size_of::<Mutex<Upvalue>>(),
+            Tracked::Instance(instance) => match instance.upgrade().as_deref() {
+                Some(Obj::Instance(instance)) => {
+                    mem::size_of::<Obj>() + instance.fields.lock().unwrap().len() * ENTRY
+                }
+                _ => 0,
+            },
+            Tracked::Class(class) => match class.upgrade() {
+                Some(class) => {
+                    let methods = class.methods.lock().unwrap().len();
+                    mem::size_of::<Obj>() + methods * mem::size_of::<(String, Arc<Closure>)>()
+                }
+                None => 0,
+            },
+        }
+    }
+
+    // Takes out everything the object refers to, returning it to be dropped
+    fn clear(&self) -> Vec<Value> {
+        match self {
+            Tracked::Upvalue(upvalue) => match upvalue.upgrade() {
+                Some(upvalue) => {
+                    let mut upvalue = upvalue.lock().unwrap();
+                    match mem::replace(&mut *upvalue, Upvalue::Closed(Value::Nil)) {
+                        Upvalue::Closed(value) => vec![value],
+                        Upvalue::Open(_) => vec![],
+                    }
+                }
+                None => vec![],
+            },
+            Tracked::Instance(instance) => match instance.upgrade().as_deref() {
+                Some(Obj::Instance(instance)) => {
+                    let fields = mem::take(&mut *instance.fields.lock().unwrap());
+                    fields.into_values().collect()
+                }
+                _ => vec![],
+            },
+            Tracked::Class(class) => match class.upgrade() {
+                Some(class) => {
+                    let methods = mem::take(&mut *class.methods.lock().unwrap());
+                    methods.into_values().map(Value::from_closure).collect()
+                }
+                None => vec![],
+            },
+        }
+    }
+}
+
+fn address<T>(object: &Arc<T>) -> usize {
+    Arc::as_ptr(object) as *const () as usize
+}
+
+// Follows references from the roots, returning the address of every object
+// reached
+fn mark<'a>(roots: impl Iterator<Item = Root<'a>>) -> HashSet<usize> {
+    let mut marked = HashSet::new();
+    let mut gray: Vec<Gray> = roots
+        .map(|root| match root {
+            Root::Value(value) => Gray::Value(value.clone()),
+            Root::Closure(closure) => Gray::Closure(closure.clone()),
+            Root::Upvalue(upvalue) => Gray::Upvalue(upvalue.clone()),
+        })
+        .collect();
+
+    while let Some(object) = gray.pop() {
+        match object {
+            Gray::Value(Value::Obj(obj)) => match obj.as_ref() {
+                Obj::Closure(closure) => gray.push(Gray::Closure(closure.clone())),
+                Obj::Class(class) => gray.push(Gray::Class(class.clone())),
+                Obj::Instance(instance) => {
+                    if marked.insert(address(&obj)) {
+                        gray.push(Gray::Class(instance.class.clone()));
+                        let fields = instance.fields.lock().unwrap();
+                        gray.extend(fields.values().cloned().map(Gray::Value));
+                    }
+                }
+                Obj::BoundMethod(bound) => {
+                    gray.push(Gray::Value(bound.receiver.clone()));
+                    gray.push(Gray::Closure(bound.method.clone()));
+                }
+                Obj::Native(native) => gray.extend(native.receiver.clone().map(Gray::Value)),
+                Obj::String(_) | Obj::Bytes(_) | Obj::Function(_) => (),
+            },
+            Gray::Value(_) => (),
+            Gray::Closure(closure) => {
+                if marked.insert(address(&closure)) {
+                    gray.extend(closure.upvalues.iter().cloned().map(Gray::Upvalue));
+                }
+            }
+            Gray::Upvalue(upvalue) => {
+                if marked.insert(address(&upvalue)) {
+                    // Open upvalues point into the stack, which is a root
+                    if let Upvalue::Closed(value) = &*upvalue.lock().unwrap() {
+                        gray.push(Gray::Value(value.clone()));
+                    }
+                }
+            }
+            Gray::Class(class) => {
+                if marked.insert(address(&class)) {
+                    let methods = class.methods.lock().unwrap();
+                    gray.extend(methods.values().cloned().map(Gray::Closure));
+                }
+            }
+        }
+    }
+    marked
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Write,
+    fs,
+    path::Path,
+};
+
+use crate::scanner::{Scanner, TokenType};
+
+// A module found by following imports from the entry file
+struct Module {
+    name: String,
+    // Whether its file could be found and read
+    found: bool,
+    imports: Vec<usize>,
+}
+
+/// The modules that `source`, the file at `path`, imports directly or through
+/// other modules, as a DOT digraph. Modules are named by their paths relative
+/// to the entry file's directory. Imports in a cycle, which fails at runtime,
+/// are drawn in red, and modules that couldn't be read are dashed.
+pub fn generate(path: &Path, source: &str) -> String {
+    let entry = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let root = entry.parent().unwrap_or(Path::new("")).to_path_buf();
+    let name = |path: &Path| {
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        relative.display().to_string()
+    };
+
+    let mut modules = vec![Module {
+        name: name(&entry),
+        found: true,
+        imports: vec![],
+    }];
+    let mut indexes = HashMap::from([(entry.clone(), 0)]);
+    let mut queue = VecDeque::from([(0, entry, Some(source.to_string()))]);
+    while let Some((index, path, source)) = queue.pop_front() {
+        let Some(source) = source else {
+            modules[index].found = false;
+            continue;
+        };
+        // Imports are relative to the module doing the importing
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for target in imports(&source) {
+            let joined = dir.join(target);
+            let target = fs::canonicalize(&joined).unwrap_or(joined);
+            let imported = match indexes.get(&target) {
+                Some(&imported) => imported,
+                None => {
+                    let imported = modules.len();
+                    modules.push(Module {
+                        name: name(&target),
+                        found: true,
+                        imports: vec![],
+                    });
+                    indexes.insert(target.clone(), imported);
+                    let source = fs::read_to_string(&target).ok();
+                    queue.push_back((imported, target, source));
+                    imported
+                }
+            };
+            if !modules[index].imports.contains(&imported) {
+                modules[index].imports.push(imported);
+            }
+        }
+    }
+
+    let mut out = "digraph imports {\n".to_string();
+    for (index, module) in modules.iter().enumerate() {
+        let mut attributes = vec![];
+        if !module.found {
+            attributes.push("style=dashed");
+        }
+        if reaches(&modules, index, index) {
+            attributes.push("color=red");
+        }
+        write!(out, "  {}", quote(&module.name)).unwrap();
+        if !attributes.is_empty() {
+            write!(out, " [{}]", attributes.join(", ")).unwrap();
+        }
+        out.push_str(";\n");
+    }
+    for (index, module) in modules.iter().enumerate() {
+        for &imported in &module.imports {
+            let from = quote(&module.name);
+            let to = quote(&modules[imported].name);
+            // An import is in a cycle if the imported module leads back
+            let color = if reaches(&modules, imported, index) {
+                " [color=red]"
+            } else {
+                ""
+            };
+            writeln!(out, "  {from} -> {to}{color};").unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+// The paths of the modules `source` imports, in the order they appear
+fn imports(source: &str) -> Vec<&str> {
+    let mut tokens = Scanner::new(source).take_while(|t| t.ty != TokenType::Eof);
+    let mut paths = vec![];
+    while let Some(token) = tokens.next() {
+        if token.ty != TokenType::Import {
+            continue;
+        }
+        // Either `import "path";` or `import name from "path";`
+        let path = tokens
+            .by_ref()
+            .take_while(|t| t.ty != TokenType::Semicolon)
+            .find(|t| t.ty == TokenType::String);
+        if let Some(path) = path {
+            paths.push(&path.str[1..path.str.len() - 1]);
+        }
+    }
+    paths
+}
+
+// Whether following imports from the module at `from` leads to the one at
+// `to`, by at least one import
+fn reaches(modules: &[Module], from: usize, to: usize) -> bool {
+    let mut seen = HashSet::new();
+    let mut stack = modules[from].imports.clone();
+    while let Some(index) = stack.pop() {
+        if index == to {
+            return true;
+        }
+        if seen.insert(index) {
+            stack.extend(&modules[index].imports);
+        }
+    }
+    false
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
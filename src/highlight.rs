@@ -0,0 +1,96 @@
+use std::fmt::Write;
+
+use crate::scanner::{Scanner, TokenType};
+
+pub fn highlight(source: &str) -> String {
+    let mut html = String::from("<pre class=\"lox\"><code>");
+    let mut offset = 0;
+
+    // The Eof token's empty span flushes whatever follows the last token
+    for token in Scanner::new(source) {
+        if token.ty == TokenType::Error {
+            // Error tokens carry a message rather than a slice of the source,
+            // so whatever they covered is emitted as part of the next gap.
+            continue;
+        }
+
+        gap(&mut html, &source[offset..token.span.start]);
+        match class(token.ty) {
+            Some(class) => span(&mut html, class, token.str),
+            None => escape(&mut html, token.str),
+        }
+        offset = token.span.end;
+    }
+
+    html.push_str("</code></pre>\n");
+    html
+}
+
+fn class(ty: TokenType) -> Option<&'static str> {
+    match ty {
+        TokenType::String => Some("lox-string"),
+        TokenType::Number => Some("lox-number"),
+        TokenType::And
+        | TokenType::Break
+        | TokenType::Catch
+        | TokenType::Class
+        | TokenType::Continue
+        | TokenType::Else
+        | TokenType::False
+        | TokenType::Finally
+        | TokenType::For
+        | TokenType::Fun
+        | TokenType::If
+        | TokenType::Import
+        | TokenType::Is
+        | TokenType::Nil
+        | TokenType::Or
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::Throw
+        | TokenType::True
+        | TokenType::Try
+        | TokenType::Var
+        | TokenType::While => Some("lox-keyword"),
+        _ => None,
+    }
+}
+
+// The scanner skips whitespace and comments, so they only show up in the text
+// between two tokens.
+fn gap(html: &mut String, text: &str) {
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("//") {
+            let (indent, comment) = line.split_at(line.len() - trimmed.len());
+            let comment = comment.trim_end_matches('\n');
+            escape(html, indent);
+            span(html, "lox-comment", comment);
+            if line.ends_with('\n') {
+                html.push('\n');
+            }
+        } else {
+            escape(html, line);
+        }
+    }
+}
+
+fn span(html: &mut String, class: &str, text: &str) {
+    write!(html, "<span class=\"{class}\">").unwrap();
+    escape(html, text);
+    html.push_str("</span>");
+}
+
+fn escape(html: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '&' => html.push_str("&amp;"),
+            '"' => html.push_str("&quot;"),
+            _ => html.push(c),
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+
+/// Maps lines of a source with its `#include` directives expanded back to the
+/// file and line they came from, following the source maps of any generated
+/// files along the way.
+#[derive(Debug)]
+pub struct SourceMap {
+    files: Vec<String>,
+    segments: Vec<Segment>,
+}
+
+// A run of expanded lines, starting at `start`, copied from `file` beginning
+// at its `line`
+#[derive(Debug)]
+struct Segment {
+    start: u32,
+    file: usize,
+    line: u32,
+}
+
+impl SourceMap {
+    pub fn locate(&self, line: u32) -> (&str, u32) {
+        let index = self.segments.partition_point(|s| s.start <= line).max(1) - 1;
+        let segment = &self.segments[index];
+        (
+            &self.files[segment.file],
+            segment
+                .line
+                .saturating_add(line.saturating_sub(segment.start)),
+        )
+    }
+
+    /// Reads a sidecar map for a generated file, named `name` in errors. Each
+    /// non-empty line holds a generated line, the original line and the
+    /// original file, and maps the generated lines from that one on to the
+    /// original file.
+    pub fn parse(name: &str, text: &str) -> Result<SourceMap> {
+        let mut map = SourceMap {
+            files: vec![],
+            segments: vec![],
+        };
+        for (i, entry) in text.lines().enumerate() {
+            if entry.trim().is_empty() {
+                continue;
+            }
+            let mut fields = entry.trim().splitn(3, char::is_whitespace);
+            let (Some(start), Some(line), Some(file)) = (
+                fields.next().and_then(|f| f.parse().ok()),
+                fields.next().and_then(|f| f.parse().ok()),
+                fields.next().map(str::trim),
+            ) else {
+                bail!("[line {} in {name}] Invalid source map entry.", i + 1);
+            };
+            let file = map.file(file);
+            if map.segments.last().is_some_and(|s| s.start >= start) {
+                bail!(
+                    "[line {} in {name}] Source map entries are out of order.",
+                    i + 1
+                );
+            }
+            map.segments.push(Segment { start, file, line });
+        }
+        if map.segments.is_empty() {
+            bail!("Source map {name} has no entries.");
+        }
+        Ok(map)
+    }
+
+    fn file(&mut self, name: &str) -> usize {
+        match self.files.iter().position(|f| f == name) {
+            Some(index) => index,
+            None => {
+                self.files.push(name.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    /// A map for a source that's all one file, so that errors in it name
+    /// the file.
+    pub fn for_file(name: &str) -> SourceMap {
+        SourceMap {
+            files: vec![name.to_string()],
+            segments: vec![Segment {
+                start: 1,
+                file: 0,
+                line: 1,
+            }],
+        }
+    }
+
+    /// Describes a line of the expanded source for use in error messages.
+    pub fn describe(&self, line: u32) -> String {
+        let (file, line) = self.locate(line);
+        format!("line {line} in {file}")
+    }
+}
+
+// Writes the format `SourceMap::parse` reads
+impl Display for SourceMap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for segment in &self.segments {
+            let file = &self.files[segment.file];
+            writeln!(f, "{} {} {file}", segment.start, segment.line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splices the contents of every `#include "file.lox"` line into `source`,
+/// resolving paths relative to the including file. Files generated from other
+/// sources can point at a sidecar map with a `//# sourceMappingURL=file.map`
+/// comment, so errors in them are reported against their original sources.
+/// Returns `None` when the source has neither.
+pub fn expand(path: &str, source: &str) -> Result<Option<(String, SourceMap)>> {
+    if !source
+        .lines()
+        .any(|l| directive(l).is_some() || annotation(l).is_some())
+    {
+        return Ok(None);
+    }
+
+    let mut expander = Expander {
+        out: String::new(),
+        out_line: 1,
+        map: SourceMap {
+            files: vec![],
+            segments: vec![],
+        },
+        stack: vec![],
+    };
+    expander.expand(Path::new(path), source)?;
+    Ok(Some((expander.out, expander.map)))
+}
+
+fn directive(line: &str) -> Option<&str> {
+    let rest = line.trim().strip_prefix("#include")?;
+    rest.trim().strip_prefix('"')?.strip_suffix('"')
+}
+
+fn annotation(line: &str) -> Option<&str> {
+    let url = line.trim().strip_prefix("//# sourceMappingURL=")?.trim();
+    (!url.is_empty()).then_some(url)
+}
+
+// Loads the source map a generated file points at, resolving it relative to
+// the file
+fn sidecar(path: &Path, source: &str) -> Result<Option<SourceMap>> {
+    let Some((line, url)) = source
+        .lines()
+        .enumerate()
+        .find_map(|(i, l)| Some((i + 1, annotation(l)?)))
+    else {
+        return Ok(None);
+    };
+    let map_path = path.parent().unwrap_or(Path::new("")).join(url);
+    let text = fs::read_to_string(&map_path).map_err(|_| {
+        anyhow!(
+            "[line {line} in {}] Could not read source map \"{url}\".",
+            path.display()
+        )
+    })?;
+    SourceMap::parse(&map_path.display().to_string(), &text).map(Some)
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+struct Expander {
+    out: String,
+    out_line: u32,
+    map: SourceMap,
+    stack: Vec<PathBuf>,
+}
+
+impl Expander {
+    fn expand(&mut self, path: &Path, source: &str) -> Result<()> {
+        let name = path.display().to_string();
+        let sidecar = sidecar(path, source)?;
+        self.stack.push(canonical(path));
+
+        for (i, text) in source.split_inclusive('\n').enumerate() {
+            let line = i as u32 + 1;
+            let Some(target) = directive(text) else {
+                match &sidecar {
+                    Some(sidecar) => {
+                        let (file, line) = sidecar.locate(line);
+                        self.map_line(file, line);
+                    }
+                    None => self.map_line(&name, line),
+                }
+                self.out.push_str(text);
+                if text.ends_with('\n') {
+                    self.out_line += 1;
+                }
+                continue;
+            };
+
+            let included = path.parent().unwrap_or(Path::new("")).join(target);
+            if self.stack.contains(&canonical(&included)) {
+                bail!(
+                    "[line {line} in {}] Recursive include of \"{target}\".",
+                    path.display()
+                );
+            }
+            let included_source = fs::read_to_string(&included).map_err(|_| {
+                anyhow!(
+                    "[line {line} in {}] Could not include \"{target}\".",
+                    path.display()
+                )
+            })?;
+            self.expand(&included, &included_source)?;
+            if !self.out.is_empty() && !self.out.ends_with('\n') {
+                self.out.push('\n');
+                self.out_line += 1;
+            }
+        }
+
+        self.stack.pop();
+        Ok(())
+    }
+
+    // Records that the next expanded line came from `line` of `file`, which
+    // only needs a new segment when it doesn't follow on from the last one
+    fn map_line(&mut self, file: &str, line: u32) {
+        let file = self.map.file(file);
+        let continues = self.map.segments.last().is_some_and(|s| {
+            s.file == file && s.line.saturating_add(self.out_line - s.start) == line
+        });
+        if !continues {
+            self.begin_segment(file, line);
+        }
+    }
+
+    fn begin_segment(&mut self, file: usize, line: u32) {
+        self.map.segments.push(Segment {
+            start: self.out_line,
+            file,
+            line,
+        });
+    }
+}
@@ -0,0 +1,30 @@
+pub mod bundle;
+pub mod bytecode;
+pub mod cache;
+pub mod chunk;
+pub mod compiler;
+pub mod doc;
+#[cfg(feature = "extensions")]
+pub mod extension;
+mod gc;
+pub mod graph;
+pub mod highlight;
+pub mod include;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
+mod natives;
+pub mod number;
+pub mod parallel;
+pub mod program;
+pub mod scanner;
+pub mod serve;
+pub mod source;
+mod suggest;
+pub mod tutorial;
+pub mod value;
+pub mod vm;
+
+pub use parallel::run_parallel;
+
+#[macro_use]
+extern crate num_derive;
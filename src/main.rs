@@ -1,73 +1,512 @@
-mod chunk;
-mod compiler;
-mod scanner;
-mod value;
-mod vm;
-
 use anyhow::Result;
-use std::{
-    env,
-    fs::File,
-    io::{self, BufRead, Read, Write},
-    process, str,
-};
+use std::{env, fs, io, path::Path, process};
 
-use chunk::{Chunk, OpCode};
-use vm::InterpretResult;
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, DefaultEditor, Editor, Helper,
+};
 
-#[macro_use]
-extern crate num_derive;
+use rlox::{
+    bundle, bytecode, cache, compiler, doc, graph, highlight, include, program::Program, scanner,
+    serve, source, tutorial, vm, vm::InterpretResult,
+};
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    run_bundled_payload();
+
+    let mut args: Vec<String> = env::args().collect();
+    let config = vm::Config {
+        clox_compat: args.iter().any(|a| a == "--clox-compat"),
+        gc_stress: args.iter().any(|a| a == "--gc-stress"),
+        gc_log: args.iter().any(|a| a == "--gc-log"),
+        ..Default::default()
+    };
+    let use_cache = !args.iter().any(|a| a == "--no-cache");
+    let mut options = source::Options {
+        lossy: args.iter().any(|a| a == "--lossy"),
+        ..Default::default()
+    };
+    if let Some(i) = args.iter().position(|a| a == "--max-source-size") {
+        options.max_size = args
+            .get(i + 1)
+            .and_then(|size| size.parse().ok())
+            .unwrap_or_else(|| {
+                eprintln!("--max-source-size takes a number of bytes.");
+                process::exit(64);
+            });
+        args.drain(i..i + 2);
+    }
+    args.retain(|a| {
+        !matches!(
+            a.as_str(),
+            "--clox-compat" | "--no-cache" | "--gc-stress" | "--gc-log" | "--lossy"
+        )
+    });
+
     match &args[..] {
-        [_] => repl().unwrap(),
-        [_, path] => run_file(path),
+        [_] => repl(config).unwrap(),
+        [_, flag, source] if flag == "-e" => exit_with(interpret_argument(source, config)),
+        [_, command, subcommand] if command == "cache" && subcommand == "clear" => clear_cache(),
+        [_, command] if command == "tutorial" => tutorial().unwrap(),
+        [_, command] if command == "serve" => serve(None),
+        [_, command, flag, path] if command == "serve" && flag == "--socket" => serve(Some(path)),
+        [_, path] => run_file(path, config, use_cache, options),
+        [_, command, path, flag, output] if command == "highlight" && flag == "-o" => {
+            highlight_file(path, output, options)
+        }
+        [_, command, path, flag, output] if command == "compile" && flag == "-o" => {
+            compile_file(path, output, false, options)
+        }
+        [_, command, path, flag, output, strip]
+            if command == "compile" && flag == "-o" && strip == "--strip" =>
+        {
+            compile_file(path, output, true, options)
+        }
+        [_, command, path, flag, output] if command == "build" && flag == "-o" => {
+            build_file(path, output, options)
+        }
+        [_, command, path] if command == "doc" => doc_file(path, None, options),
+        [_, command, path] if command == "graph" => graph_file(path, options),
+        [_, command, path, flag, output] if command == "doc" && flag == "-o" => {
+            doc_file(path, Some(output), options)
+        }
         _ => {
-            eprintln!("Usage: rlox [path]");
+            eprintln!("Usage: rlox [options] [path]");
+            eprintln!("       rlox [options] -e [source]");
+            eprintln!("       rlox compile [path] -o [output] [--strip]");
+            eprintln!("       rlox build [path] -o [output]");
+            eprintln!("       rlox highlight [path] -o [output]");
+            eprintln!("       rlox doc [path] [-o output]");
+            eprintln!("       rlox graph [path]");
+            eprintln!("       rlox cache clear");
+            eprintln!("       rlox tutorial");
+            eprintln!("       rlox serve [--socket path]");
+            eprintln!();
+            eprintln!("Options: --clox-compat --no-cache --gc-stress --gc-log --lossy");
+            eprintln!("         --max-source-size [bytes]");
             process::exit(64);
         }
     }
 }
 
-fn repl() -> Result<()> {
+fn repl(config: vm::Config) -> Result<()> {
+    // Bracketed paste makes a pasted multi-line snippet arrive as one input,
+    // so it's compiled as a unit rather than line by line
+    let editor_config = rustyline::Config::builder().bracketed_paste(true).build();
+    let mut editor: Editor<Completions, DefaultHistory> = Editor::with_config(editor_config)?;
+    editor.set_helper(Some(Completions::default()));
+    let prompt = env::var("RLOX_PROMPT").unwrap_or_else(|_| "> ".to_string());
+    // One VM for the whole session so globals carry over between inputs
+    let mut vm = vm::VM::new(config);
+    run_startup_script(&mut vm);
+    // Inputs that ran successfully, so `:save` can turn the session into a script
+    let mut session = vec![];
     loop {
-        print!("> ");
-        io::stdout().flush()?;
-
-        if let Some(Ok(line)) = io::stdin().lock().lines().next() {
-            match vm::interpret(&line) {
-                InterpretResult::CompileError => eprintln!("Compile error"),
-                InterpretResult::RuntimeError => eprintln!("Runtime error"),
-                InterpretResult::Ok => (),
+        if let Some(completions) = editor.helper_mut() {
+            completions.globals = vm.global_names().map(String::from).collect();
+        }
+        match editor.readline(&prompt) {
+            Ok(input) => {
+                editor.add_history_entry(input.as_str())?;
+                if let Some(path) = input.strip_prefix(":save ") {
+                    save_session(path.trim(), &session);
+                } else if let Some(path) = input.strip_prefix(":load ") {
+                    load_session(path.trim(), &mut session, &mut vm);
+                } else {
+                    repl_input(input, &mut session, &mut vm, Some(&prompt));
+                }
             }
-        } else {
-            println!("");
-            break;
+            Err(ReadlineError::Interrupted) => (),
+            Err(ReadlineError::Eof) => {
+                println!();
+                break;
+            }
+            Err(e) => return Err(e.into()),
         }
     }
     Ok(())
 }
 
-fn run_file(path: &str) {
-    let mut f = File::open(path).unwrap_or_else(|_| {
-        eprintln!("Could not open file {}.", path);
+// Tab completes the word before the cursor as a keyword or one of the
+// globals defined so far
+#[derive(Default)]
+struct Completions {
+    globals: Vec<String>,
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .take_while(|(_, c)| c.is_alphanumeric() || *c == '_')
+            .last()
+            .map_or(pos, |(i, _)| i);
+        let word = &line[start..pos];
+        // A number isn't the start of a name
+        if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) {
+            return Ok((start, vec![]));
+        }
+        let names = scanner::KEYWORDS.iter().copied();
+        let names = names.chain(self.globals.iter().map(String::as_str));
+        let mut candidates: Vec<String> = names
+            .filter(|name| name.starts_with(word))
+            .map(String::from)
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+// Walks through the built-in lessons, running each answer in a fresh VM until
+// one prints what the lesson expects
+fn tutorial() -> Result<()> {
+    let editor_config = rustyline::Config::builder().bracketed_paste(true).build();
+    let mut editor = DefaultEditor::with_config(editor_config)?;
+    let lessons = tutorial::lessons();
+    println!("Answer each lesson by entering Lox at the prompt.");
+    println!("Enter :solution to see an answer, :skip to move on, or Ctrl-D to stop.");
+    for (number, lesson) in lessons.iter().enumerate() {
+        println!();
+        println!(
+            "Lesson {} of {}: {}",
+            number + 1,
+            lessons.len(),
+            lesson.title
+        );
+        println!();
+        println!("{}", lesson.text);
+        loop {
+            let input = match editor.readline("tutorial> ") {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => {
+                    println!();
+                    return Ok(());
+                }
+                Err(e) => return Err(e.into()),
+            };
+            editor.add_history_entry(input.as_str())?;
+            match input.trim() {
+                "" => continue,
+                ":skip" => break,
+                ":solution" => {
+                    println!("{}", lesson.solution);
+                    continue;
+                }
+                _ => (),
+            }
+            match lesson.check(&input, io::stdout()) {
+                tutorial::Outcome::Correct => {
+                    println!("Correct!");
+                    break;
+                }
+                tutorial::Outcome::Failed => println!("Try again."),
+                tutorial::Outcome::Incorrect => {
+                    print!("Not quite. The expected output is:\n{}", lesson.expected)
+                }
+            }
+        }
+    }
+    println!();
+    println!("That's every lesson. Run rlox with no arguments for a REPL.");
+    Ok(())
+}
+
+// Runs ~/.rloxrc, if there is one, so its functions and variables are
+// available from the first prompt. A failing script is reported but doesn't
+// stop the REPL from starting.
+fn run_startup_script(vm: &mut vm::VM) {
+    let Some(home) = env::var_os("HOME") else {
+        return;
+    };
+    let path = Path::new(&home).join(".rloxrc");
+    let Ok(source) = fs::read_to_string(&path) else {
+        return;
+    };
+    let path = path.display().to_string();
+    if interpret_source(vm, &path, &source) != InterpretResult::Ok {
+        eprintln!("[in startup script {path}]");
+    }
+}
+
+// `prompt` is the one `input` was typed after, if it was typed rather than
+// loaded from a file
+fn repl_input(mut input: String, session: &mut Vec<String>, vm: &mut vm::VM, prompt: Option<&str>) {
+    let (program, diagnostics) = compiler::compile_interactive(&mut input);
+    for diagnostic in &diagnostics {
+        report_inline(&input, diagnostic, prompt);
+    }
+    let Ok(program) = program else {
+        return;
+    };
+    match vm.run_program(&program) {
+        InterpretResult::RuntimeError => eprintln!("Runtime error"),
+        InterpretResult::CompileError | InterpretResult::Ok => session.push(input),
+    }
+}
+
+// Marks where in the input a diagnostic is. Input that was just typed is
+// still on screen, so its last line is marked right where it is.
+fn report_inline(input: &str, diagnostic: &compiler::Diagnostic, prompt: Option<&str>) {
+    let Some((line, marker)) = diagnostic.underline(input) else {
+        eprintln!("{diagnostic}");
+        return;
+    };
+    let Some(prompt) = prompt else {
+        eprintln!("{line}");
+        eprintln!("{marker}");
+        return;
+    };
+    let indent = " ".repeat(prompt.chars().count());
+    let last_line = input.lines().last().unwrap_or_default();
+    if !std::ptr::eq(line, last_line) {
+        eprintln!("{indent}{line}");
+    }
+    eprintln!("{indent}{marker}");
+}
+
+fn save_session(path: &str, session: &[String]) {
+    let mut contents = session.join("\n");
+    contents.push('\n');
+    if fs::write(path, contents).is_err() {
+        eprintln!("Could not write file {}.", path);
+    }
+}
+
+// Saved inputs can span lines, e.g. pasted functions, so the file is
+// compiled as one input rather than a line at a time
+fn load_session(path: &str, session: &mut Vec<String>, vm: &mut vm::VM) {
+    match fs::read_to_string(path) {
+        Ok(contents) if contents.trim().is_empty() => (),
+        Ok(contents) => repl_input(contents.trim_end().to_string(), session, vm, None),
+        Err(_) => eprintln!("Could not open file {}.", path),
+    }
+}
+
+// Executables produced by `rlox build` carry their program after the
+// interpreter, in which case that program is run instead of the command line.
+fn run_bundled_payload() {
+    let payload = env::current_exe()
+        .ok()
+        .and_then(|exe| bundle::read_payload(&exe).ok().flatten());
+    if let Some(payload) = payload {
+        let mut vm = vm::VM::new(vm::Config::default());
+        exit_with(run_bytecode(&mut vm, &payload, "bundled program"));
+        process::exit(0);
+    }
+}
+
+fn run_file(path: &str, config: vm::Config, use_cache: bool, options: source::Options) {
+    let bytes = read_bytes(path, options);
+    // Imports are relative to the file doing the importing
+    let mut vm = vm::VM::new(config);
+    vm.set_path(Path::new(path));
+    if let Some(dir) = cache::dir().filter(|_| use_cache) {
+        vm.set_cache(&dir);
+    }
+    let result = if bytecode::is_bytecode(&bytes) {
+        run_bytecode(&mut vm, &bytes, path)
+    } else {
+        let source = to_source(path, bytes, options);
+        interpret_source(&mut vm, path, &source)
+    };
+    exit_with(result);
+}
+
+fn clear_cache() {
+    let Some(dir) = cache::dir() else {
+        return;
+    };
+    cache::clear(&dir).unwrap_or_else(|_| {
+        eprintln!("Could not clear the cache in {}.", dir.display());
         process::exit(74);
     });
-    let mut buffer = vec![];
-    f.read_to_end(&mut buffer).unwrap_or_else(|_| {
-        eprintln!("Could not read file {}", path);
-        process::exit(74);
+}
+
+fn interpret_source(vm: &mut vm::VM, path: &str, source: &str) -> InterpretResult {
+    match expand_includes(path, source) {
+        Some((expanded, source_map)) => vm.interpret(&expanded, Some(source_map)),
+        None => vm.interpret(source, None),
+    }
+}
+
+fn expand_includes(path: &str, source: &str) -> Option<(String, include::SourceMap)> {
+    include::expand(path, source).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(65);
+    })
+}
+
+fn compile_source(path: &str, source: &str) -> Program {
+    let result = match expand_includes(path, source) {
+        Some((expanded, source_map)) => compiler::compile(&expanded, Some(source_map)),
+        None => compiler::compile(source, None),
+    };
+    result.unwrap_or_else(|_| process::exit(65))
+}
+
+fn run_bytecode(vm: &mut vm::VM, bytes: &[u8], name: &str) -> InterpretResult {
+    let (chunk, source_path) = bytecode::read(bytes).unwrap_or_else(|e| {
+        eprintln!("Invalid bytecode in {}: {}", name, e);
+        process::exit(65);
     });
-    let source = str::from_utf8(&buffer).unwrap_or_else(|_| {
-        eprintln!("Invalid source string");
+    let result = vm.run_program(&Program::new(chunk, None));
+    if let (InterpretResult::RuntimeError, Some(source_path)) = (&result, source_path) {
+        eprintln!("[compiled from {source_path}]");
+    }
+    result
+}
+
+// Answers requests from stdin, or from connections to a unix socket, until
+// the input ends
+fn serve(socket: Option<&str>) {
+    let result = match socket {
+        None => serve::serve(io::stdin().lock(), io::stdout().lock()),
+        #[cfg(unix)]
+        Some(path) => serve::serve_unix(Path::new(path)),
+        #[cfg(not(unix))]
+        Some(_) => Err(anyhow::anyhow!(
+            "Unix sockets aren't supported on this platform."
+        )),
+    };
+    if let Err(e) = result {
+        eprintln!("{e}");
         process::exit(74);
-    });
+    }
+}
+
+// Runs the program given with `-e`, reporting its errors the way a file's
+// are
+fn interpret_argument(source: &str, config: vm::Config) -> InterpretResult {
+    let (result, diagnostics) = vm::interpret_with_diagnostics(source, None, config);
+    for diagnostic in &diagnostics {
+        if config.clox_compat {
+            eprintln!("{diagnostic}");
+        } else {
+            eprintln!("{}", diagnostic.with_snippet(source));
+        }
+    }
+    if let Err(vm::InterpretError::Runtime(error)) = &result {
+        eprint!("{error}");
+    }
+    result.into()
+}
 
-    let result = vm::interpret(source);
+fn exit_with(result: InterpretResult) {
     match result {
         InterpretResult::CompileError => process::exit(65),
         InterpretResult::RuntimeError => process::exit(70),
         InterpretResult::Ok => (),
     }
 }
+
+fn compile_file(path: &str, output: &str, strip: bool, options: source::Options) {
+    let source = read_file(path, options);
+    let program = compile_source(path, &source);
+    let bytes = bytecode::write(program.chunk(), if strip { None } else { Some(path) });
+    fs::write(output, bytes).unwrap_or_else(|_| {
+        eprintln!("Could not write file {}.", output);
+        process::exit(74);
+    });
+}
+
+fn build_file(path: &str, output: &str, options: source::Options) {
+    let source = read_file(path, options);
+    let program = compile_source(path, &source);
+    let exe = env::current_exe().and_then(fs::read).unwrap_or_else(|_| {
+        eprintln!("Could not read the rlox executable.");
+        process::exit(74);
+    });
+    let bundled = bundle::build(&exe, &bytecode::write(program.chunk(), Some(path)));
+    fs::write(output, bundled).unwrap_or_else(|_| {
+        eprintln!("Could not write file {}.", output);
+        process::exit(74);
+    });
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Failing to mark the output executable isn't fatal, it can still be
+        // run once the user fixes its permissions
+        let _ = fs::set_permissions(output, fs::Permissions::from_mode(0o755));
+    }
+}
+
+fn highlight_file(path: &str, output: &str, options: source::Options) {
+    let source = read_file(path, options);
+    let html = highlight::highlight(&source);
+    fs::write(output, html).unwrap_or_else(|_| {
+        eprintln!("Could not write file {}.", output);
+        process::exit(74);
+    });
+}
+
+fn doc_file(path: &str, output: Option<&str>, options: source::Options) {
+    let source = read_file(path, options);
+    let title = Path::new(path)
+        .file_stem()
+        .map_or(path.into(), |s| s.to_string_lossy());
+    match output {
+        None => print!("{}", doc::generate(&source, &title, doc::Format::Markdown)),
+        Some(output) => {
+            let format = if output.ends_with(".html") {
+                doc::Format::Html
+            } else {
+                doc::Format::Markdown
+            };
+            fs::write(output, doc::generate(&source, &title, format)).unwrap_or_else(|_| {
+                eprintln!("Could not write file {}.", output);
+                process::exit(74);
+            });
+        }
+    }
+}
+
+// Prints the modules the file imports as a DOT graph, for Graphviz to draw
+fn graph_file(path: &str, options: source::Options) {
+    let source = read_file(path, options);
+    print!("{}", graph::generate(Path::new(path), &source));
+}
+
+fn read_file(path: &str, options: source::Options) -> String {
+    to_source(path, read_bytes(path, options), options)
+}
+
+fn read_bytes(path: &str, options: source::Options) -> Vec<u8> {
+    source::read(Path::new(path), options).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        process::exit(74);
+    })
+}
+
+fn to_source(path: &str, bytes: Vec<u8>, options: source::Options) -> String {
+    match source::decode(bytes) {
+        Ok(source) => source,
+        Err((position, lossy)) if options.lossy => {
+            eprintln!("Warning: Replaced invalid UTF-8 in {path} at {position} with U+FFFD.");
+            lossy
+        }
+        Err((position, _)) => {
+            eprintln!("Invalid UTF-8 in {path} at {position}. Pass --lossy to replace it.");
+            process::exit(74);
+        }
+    }
+}
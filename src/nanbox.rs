@@ -0,0 +1,175 @@
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("NaN boxing needs 64-bit pointers");
+
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+use crate::value::{Obj, Value};
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+
+/// A `Value` packed into a single 64-bit word, half the size of the enum.
+/// It's what the VM's stack holds when this module is enabled.
+///
+/// Numbers are stored as their own bits. Everything else is hidden in the
+/// payload of a quiet NaN, which no arithmetic produces: the singletons in
+/// its low bits, and objects as a pointer with the sign bit set. NaNs that
+/// are numbers are all stored as the canonical `f64::NAN`, so they can't be
+/// mistaken for one of those.
+///
+/// An object is an `Arc<Obj>` turned into its raw pointer, so cloning and
+/// dropping a `NanBox` keep the reference count the same way `Value` does.
+pub struct NanBox(u64);
+
+impl NanBox {
+    pub fn nil() -> NanBox {
+        NanBox(NIL)
+    }
+
+    pub fn from_bool(b: bool) -> NanBox {
+        NanBox(if b { TRUE } else { FALSE })
+    }
+
+    pub fn from_number(n: f64) -> NanBox {
+        let n = if n.is_nan() { f64::NAN } else { n };
+        NanBox(n.to_bits())
+    }
+
+    pub fn from_obj(obj: Arc<Obj>) -> NanBox {
+        let ptr = Arc::into_raw(obj) as u64;
+        // User space pointers on 64-bit platforms only use the low 48 bits
+        assert_eq!(ptr & (SIGN_BIT | QNAN), 0, "Pointer doesn't fit in a NaN");
+        NanBox(SIGN_BIT | QNAN | ptr)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.0 & QNAN != QNAN
+    }
+
+    pub fn is_obj(&self) -> bool {
+        self.0 & (SIGN_BIT | QNAN) == SIGN_BIT | QNAN
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self.0 {
+            TRUE => Some(true),
+            FALSE => Some(false),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        self.is_number().then(|| f64::from_bits(self.0))
+    }
+
+    /// The object, borrowed for as long as this value is.
+    pub fn as_obj(&self) -> Option<&Obj> {
+        // Safety: the pointer came from `Arc::into_raw` and this value holds
+        // one of its references until it's dropped
+        self.is_obj().then(|| unsafe { &*self.obj_ptr() })
+    }
+
+    /// The word itself, e.g. to compare representations.
+    pub fn to_bits(&self) -> u64 {
+        self.0
+    }
+
+    /// Converts back to a `Value`, sharing any object with this one.
+    pub fn to_value(&self) -> Value {
+        if let Some(n) = self.as_number() {
+            return Value::Number(n);
+        }
+        if self.is_obj() {
+            let ptr = self.obj_ptr();
+            // Safety: as for `as_obj`. The new `Arc` gets a reference of its
+            // own.
+            unsafe {
+                Arc::increment_strong_count(ptr);
+                return Value::Obj(Arc::from_raw(ptr));
+            }
+        }
+        match self.0 {
+            TRUE => Value::Bool(true),
+            FALSE => Value::Bool(false),
+            _ => Value::Nil,
+        }
+    }
+
+    fn obj_ptr(&self) -> *const Obj {
+        (self.0 & !(SIGN_BIT | QNAN)) as *const Obj
+    }
+}
+
+impl From<Value> for NanBox {
+    fn from(value: Value) -> NanBox {
+        match value {
+            Value::Bool(b) => NanBox::from_bool(b),
+            Value::Nil => NanBox::nil(),
+            Value::Number(n) => NanBox::from_number(n),
+            Value::Obj(obj) => NanBox::from_obj(obj),
+        }
+    }
+}
+
+impl From<NanBox> for Value {
+    fn from(value: NanBox) -> Value {
+        value.to_value()
+    }
+}
+
+impl Default for NanBox {
+    fn default() -> NanBox {
+        NanBox::nil()
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> NanBox {
+        if self.is_obj() {
+            // Safety: as for `as_obj`
+            unsafe { Arc::increment_strong_count(self.obj_ptr()) };
+        }
+        NanBox(self.0)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if self.is_obj() {
+            // Safety: gives back the reference this value held
+            unsafe { drop(Arc::from_raw(self.obj_ptr())) };
+        }
+    }
+}
+
+// Equal exactly when the `Value`s they hold are
+impl PartialEq for NanBox {
+    fn eq(&self, other: &NanBox) -> bool {
+        match (self.as_number(), other.as_number()) {
+            (Some(a), Some(b)) => a == b,
+            _ if self.is_obj() && other.is_obj() => self.to_value() == other.to_value(),
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+impl Debug for NanBox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.to_value(), f)
+    }
+}
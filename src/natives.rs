@@ -0,0 +1,417 @@
+use std::{
+    ops::RangeInclusive,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::number;
+use crate::value::{Instance, NativeFn, Value};
+
+/// Every VM starts with these defined as globals.
+pub const GLOBALS: &[(&str, NativeFn)] = &[
+    ("clock", clock),
+    ("bytes", bytes),
+    ("utf8", utf8),
+    ("hex", hex),
+    ("fromHex", from_hex),
+    ("base64", base64),
+    ("fromBase64", from_base64),
+    ("hasField", has_field),
+    ("removeField", remove_field),
+    ("getField", get_field),
+    ("setField", set_field),
+    ("fields", fields),
+    ("methods", methods),
+    ("className", class_name),
+    ("help", help),
+    ("toFixed", to_fixed),
+    ("toPrecision", to_precision),
+    #[cfg(feature = "extensions")]
+    ("loadExtension", crate::extension::load),
+];
+
+/// Methods every string has, which get the string as their first argument.
+pub const STRING_METHODS: &[(&str, NativeFn)] = &[
+    ("len", len),
+    ("upper", upper),
+    ("lower", lower),
+    ("trim", trim),
+    ("contains", contains),
+    ("startsWith", starts_with),
+    ("endsWith", ends_with),
+    ("indexOf", index_of),
+    ("slice", slice),
+    ("replace", replace),
+    ("repeat", repeat),
+];
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Seconds since the Unix epoch, for timing code from Lox.
+pub fn clock(_args: &[Value]) -> Result<Value, String> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?;
+    Ok(Value::Number(elapsed.as_secs_f64()))
+}
+
+/// The UTF-8 encoding of a string.
+fn bytes(args: &[Value]) -> Result<Value, String> {
+    let s = string_arg("bytes", args)?;
+    Ok(Value::from_bytes(s.as_bytes().to_vec()))
+}
+
+fn utf8(args: &[Value]) -> Result<Value, String> {
+    let bytes = bytes_arg("utf8", args)?;
+    match String::from_utf8(bytes.to_vec()) {
+        Ok(s) => Ok(Value::from_string(s)),
+        Err(_) => Err("Bytes aren't valid UTF-8.".to_string()),
+    }
+}
+
+fn hex(args: &[Value]) -> Result<Value, String> {
+    let bytes = bytes_arg("hex", args)?;
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    Ok(Value::from_string(hex))
+}
+
+fn from_hex(args: &[Value]) -> Result<Value, String> {
+    let hex = string_arg("fromHex", args)?.as_bytes();
+    let invalid = || "Invalid hex string.".to_string();
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            u8::from_str_radix(pair, 16).map_err(|_| invalid())
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(Value::from_bytes(bytes))
+}
+
+// Standard base64 with padding
+fn base64(args: &[Value]) -> Result<Value, String> {
+    let bytes = bytes_arg("base64", args)?;
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - i * 6)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    Ok(Value::from_string(out))
+}
+
+fn from_base64(args: &[Value]) -> Result<Value, String> {
+    let text = string_arg("fromBase64", args)?.as_bytes();
+    let invalid = || "Invalid base64 string.".to_string();
+    if text.len() % 4 != 0 {
+        return Err(invalid());
+    }
+    let mut bytes = vec![];
+    for (i, chunk) in text.chunks(4).enumerate() {
+        let last = i == text.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(invalid());
+        }
+        let mut n = 0u32;
+        for (j, c) in chunk[..4 - padding].iter().enumerate() {
+            let digit = BASE64_ALPHABET
+                .iter()
+                .position(|a| a == c)
+                .ok_or_else(invalid)?;
+            n |= (digit as u32) << (18 - j * 6);
+        }
+        bytes.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(Value::from_bytes(bytes))
+}
+
+/// Whether an instance has a field. Methods aren't fields, even though
+/// `instance.name` gets them too.
+fn has_field(args: &[Value]) -> Result<Value, String> {
+    let (instance, name) = field_args("hasField", args)?;
+    let has = instance.fields.lock().unwrap().contains_key(name);
+    Ok(Value::Bool(has))
+}
+
+/// Removes a field from an instance, returning whether it had the field.
+fn remove_field(args: &[Value]) -> Result<Value, String> {
+    let (instance, name) = field_args("removeField", args)?;
+    let removed = instance.fields.lock().unwrap().remove(name);
+    Ok(Value::Bool(removed.is_some()))
+}
+
+/// The field with a name, which doesn't have to be an identifier.
+fn get_field(args: &[Value]) -> Result<Value, String> {
+    let (instance, name) = field_args("getField", args)?;
+    let fields = instance.fields.lock().unwrap();
+    fields
+        .get(name)
+        .cloned()
+        .ok_or_else(|| format!("Undefined field '{name}'."))
+}
+
+/// Sets a field by name, returning the value like assigning it does.
+fn set_field(args: &[Value]) -> Result<Value, String> {
+    let [instance, name, value] = args else {
+        return Err(format!("Expected 3 arguments but got {}.", args.len()));
+    };
+    match (instance.as_instance(), name.as_str()) {
+        (Some(instance), Some(name)) => {
+            let mut fields = instance.fields.lock().unwrap();
+            fields.insert(name.to_string(), value.clone());
+            Ok(value.clone())
+        }
+        _ => Err("setField() takes an instance, a field name and a value.".to_string()),
+    }
+}
+
+/// The names of an instance's fields, sorted and separated by ", " since
+/// there's no list type to hold them.
+fn fields(args: &[Value]) -> Result<Value, String> {
+    let [instance] = args else {
+        return Err(arity_error(args));
+    };
+    let instance = instance
+        .as_instance()
+        .ok_or("fields() takes an instance.")?;
+    let fields = instance.fields.lock().unwrap();
+    Ok(sorted_names(fields.keys()))
+}
+
+/// The names of a class's methods, like `fields`.
+fn methods(args: &[Value]) -> Result<Value, String> {
+    let [class] = args else {
+        return Err(arity_error(args));
+    };
+    let class = class.as_class().ok_or("methods() takes a class.")?;
+    let methods = class.methods.lock().unwrap();
+    Ok(sorted_names(methods.keys()))
+}
+
+fn sorted_names<'a>(names: impl Iterator<Item = &'a String>) -> Value {
+    let mut names = names.map(String::as_str).collect::<Vec<_>>();
+    names.sort_unstable();
+    Value::from_string(names.join(", "))
+}
+
+/// The name of a class, or of an instance's class. Anything else has none.
+fn class_name(args: &[Value]) -> Result<Value, String> {
+    let [value] = args else {
+        return Err(arity_error(args));
+    };
+    let class = match value.as_instance() {
+        Some(instance) => Some(&instance.class),
+        None => value.as_class(),
+    };
+    Ok(class.map_or(Value::Nil, |class| Value::from_string(class.name.clone())))
+}
+
+/// What a function or class is, and its documentation. Natives can't write
+/// to the VM's output, so it's returned for `print help(f);` to show.
+fn help(args: &[Value]) -> Result<Value, String> {
+    let [value] = args else {
+        return Err(arity_error(args));
+    };
+    let function = match (value.as_closure(), value.as_bound_method()) {
+        (Some(closure), _) => Some(&closure.function),
+        (_, Some(bound)) => Some(&bound.method.function),
+        _ => None,
+    };
+    let (summary, doc) = if let Some(function) = function {
+        let arity = function.arity;
+        let arguments = if arity == 1 { "argument" } else { "arguments" };
+        (
+            format!("{function}, taking {arity} {arguments}"),
+            &function.doc,
+        )
+    } else if let Some(class) = value.as_class() {
+        let methods = class.methods.lock().unwrap();
+        let mut names = methods.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        let mut summary = format!("class {}", class.name);
+        if !names.is_empty() {
+            summary.push_str(&format!(", with methods {}", names.join(", ")));
+        }
+        (summary, &class.doc)
+    } else if let Some(native) = value.as_native() {
+        (format!("<native fn {}>", native.name), &None)
+    } else {
+        return Err("help() takes a function or a class.".to_string());
+    };
+    Ok(Value::from_string(match doc {
+        Some(doc) => format!("{summary}\n{doc}"),
+        None => summary,
+    }))
+}
+
+fn field_args<'a>(name: &str, args: &'a [Value]) -> Result<(&'a Instance, &'a str), String> {
+    match args {
+        [instance, field] => match (instance.as_instance(), field.as_str()) {
+            (Some(instance), Some(field)) => Ok((instance, field)),
+            _ => Err(format!("{name}() takes an instance and a field name.")),
+        },
+        _ => Err(format!("Expected 2 arguments but got {}.", args.len())),
+    }
+}
+
+/// A number with a fixed number of decimal places, from 0 to 100.
+fn to_fixed(args: &[Value]) -> Result<Value, String> {
+    let (n, digits) = digits_args("toFixed", args, 0..=100)?;
+    Ok(Value::from_string(number::fixed(n, digits)))
+}
+
+/// A number rounded to a number of significant digits, from 1 to 100.
+fn to_precision(args: &[Value]) -> Result<Value, String> {
+    let (n, significant) = digits_args("toPrecision", args, 1..=100)?;
+    Ok(Value::from_string(number::precision(n, significant)))
+}
+
+fn digits_args(
+    name: &str,
+    args: &[Value],
+    range: RangeInclusive<usize>,
+) -> Result<(f64, usize), String> {
+    let [n, digits] = args else {
+        return Err(format!("Expected 2 arguments but got {}.", args.len()));
+    };
+    match (n, digits) {
+        (Value::Number(n), Value::Number(digits))
+            if *digits >= 0.0 && digits.fract() == 0.0 && range.contains(&(*digits as usize)) =>
+        {
+            Ok((*n, *digits as usize))
+        }
+        _ => Err(format!(
+            "{name}() takes a number and a digit count from {} to {}.",
+            range.start(),
+            range.end()
+        )),
+    }
+}
+
+/// The number of characters in a string, like its `length` property.
+fn len(args: &[Value]) -> Result<Value, String> {
+    let (s, []) = method_args(args)?;
+    Ok(Value::Number(s.chars().count() as f64))
+}
+
+fn upper(args: &[Value]) -> Result<Value, String> {
+    let (s, []) = method_args(args)?;
+    Ok(Value::from_string(s.to_uppercase()))
+}
+
+fn lower(args: &[Value]) -> Result<Value, String> {
+    let (s, []) = method_args(args)?;
+    Ok(Value::from_string(s.to_lowercase()))
+}
+
+fn trim(args: &[Value]) -> Result<Value, String> {
+    let (s, []) = method_args(args)?;
+    Ok(Value::from_string(s.trim().to_string()))
+}
+
+fn contains(args: &[Value]) -> Result<Value, String> {
+    let (s, [other]) = method_args(args)?;
+    Ok(Value::Bool(s.contains(string_value("contains", other)?)))
+}
+
+fn starts_with(args: &[Value]) -> Result<Value, String> {
+    let (s, [prefix]) = method_args(args)?;
+    Ok(Value::Bool(
+        s.starts_with(string_value("startsWith", prefix)?),
+    ))
+}
+
+fn ends_with(args: &[Value]) -> Result<Value, String> {
+    let (s, [suffix]) = method_args(args)?;
+    Ok(Value::Bool(s.ends_with(string_value("endsWith", suffix)?)))
+}
+
+/// Where a substring first appears, counting characters, or -1 if it
+/// doesn't.
+fn index_of(args: &[Value]) -> Result<Value, String> {
+    let (s, [other]) = method_args(args)?;
+    let index = match s.find(string_value("indexOf", other)?) {
+        Some(byte) => s[..byte].chars().count() as f64,
+        None => -1.0,
+    };
+    Ok(Value::Number(index))
+}
+
+/// The characters from `start` up to but not including `end`, which are
+/// clamped to the string.
+fn slice(args: &[Value]) -> Result<Value, String> {
+    let (s, [start, end]) = method_args(args)?;
+    let (start, end) = (index_value("slice", start)?, index_value("slice", end)?);
+    let sliced = s.chars().skip(start).take(end.saturating_sub(start));
+    Ok(Value::from_string(sliced.collect()))
+}
+
+fn replace(args: &[Value]) -> Result<Value, String> {
+    let (s, [from, to]) = method_args(args)?;
+    let (from, to) = (string_value("replace", from)?, string_value("replace", to)?);
+    if from.is_empty() {
+        return Err("replace() can't replace an empty string.".to_string());
+    }
+    Ok(Value::from_string(s.replace(from, to)))
+}
+
+fn repeat(args: &[Value]) -> Result<Value, String> {
+    let (s, [count]) = method_args(args)?;
+    Ok(Value::from_string(s.repeat(index_value("repeat", count)?)))
+}
+
+// The string a method was called on, and its arguments
+fn method_args<const N: usize>(args: &[Value]) -> Result<(&str, &[Value; N]), String> {
+    let (receiver, rest) = args.split_first().unwrap();
+    let rest = rest
+        .try_into()
+        .map_err(|_| format!("Expected {N} arguments but got {}.", rest.len()))?;
+    Ok((receiver.as_str().unwrap(), rest))
+}
+
+fn string_value<'a>(name: &str, value: &'a Value) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("{name}() takes a string."))
+}
+
+fn index_value(name: &str, value: &Value) -> Result<usize, String> {
+    match value {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err(format!("{name}() takes a non-negative integer.")),
+    }
+}
+
+fn string_arg<'a>(name: &str, args: &'a [Value]) -> Result<&'a str, String> {
+    match args {
+        [arg] => arg
+            .as_str()
+            .ok_or_else(|| format!("{name}() takes a string.")),
+        _ => Err(arity_error(args)),
+    }
+}
+
+fn bytes_arg<'a>(name: &str, args: &'a [Value]) -> Result<&'a [u8], String> {
+    match args {
+        [arg] => arg
+            .as_bytes()
+            .ok_or_else(|| format!("{name}() takes bytes.")),
+        _ => Err(arity_error(args)),
+    }
+}
+
+fn arity_error(args: &[Value]) -> String {
+    format!("Expected 1 arguments but got {}.", args.len())
+}
@@ -0,0 +1,105 @@
+// Numbers are formatted and parsed here rather than wherever they're needed,
+// so printed output, disassembler listings and the assembler all agree. None
+// of it depends on the platform's locale.
+
+// Magnitudes outside this range are written in exponent notation, as
+// JavaScript does, rather than with a long run of zeros
+const PLAIN_RANGE: std::ops::Range<f64> = 1e-7..1e21;
+
+/// How numbers are written out.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// The fewest digits that `parse` reads back as the same number, e.g.
+    /// `0.1`, `-0` and `1e300`.
+    #[default]
+    Shortest,
+    /// Like clox's `printf("%g")`: six significant digits, trailing zeros
+    /// trimmed, and exponent notation for very large or small magnitudes.
+    Printf,
+}
+
+pub fn format(n: f64, format: Format) -> String {
+    if n.is_nan() {
+        return "nan".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    match format {
+        Format::Shortest => shortest(n),
+        Format::Printf => printf(n),
+    }
+}
+
+/// `n` with exactly `digits` digits after the decimal point, like
+/// JavaScript's `toFixed` except that exact halves round to even. Magnitudes
+/// too large for plain notation are written as `format` writes them.
+pub fn fixed(n: f64, digits: usize) -> String {
+    if !n.is_finite() || n.abs() >= PLAIN_RANGE.end {
+        return format(n, Format::Shortest);
+    }
+    format!("{n:.digits$}")
+}
+
+/// `n` rounded to `significant` digits, like JavaScript's `toPrecision`:
+/// in exponent notation when plain notation would need more digits than
+/// that, or when the number is very small. Exact halves round to even.
+pub fn precision(n: f64, significant: usize) -> String {
+    if !n.is_finite() {
+        return format(n, Format::Shortest);
+    }
+    let scientific = format!("{:.*e}", significant - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if exponent < -6 || exponent >= significant as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{mantissa}e{sign}{}", exponent.abs())
+    } else {
+        let decimals = (significant as i32 - 1 - exponent) as usize;
+        format!("{n:.decimals$}")
+    }
+}
+
+/// Reads a number written by `format` in either format, or a Lox number
+/// literal.
+pub fn parse(text: &str) -> Option<f64> {
+    // Rust's parser also accepts forms like `infinity` and `+1`, which is
+    // harmless, but not any that depend on the locale
+    text.parse().ok()
+}
+
+fn shortest(n: f64) -> String {
+    // Both of Rust's notations give the shortest digits that round-trip
+    if n == 0.0 || PLAIN_RANGE.contains(&n.abs()) {
+        format!("{n}")
+    } else {
+        format!("{n:e}")
+    }
+}
+
+fn printf(n: f64) -> String {
+    const PRECISION: i32 = 6;
+    if n == 0.0 {
+        return if n.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    // Rounding to the target precision first gives the exponent %g decides on
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..PRECISION).contains(&exponent) {
+        let decimals = (PRECISION - 1 - exponent) as usize;
+        trim_fraction(&format!("{n:.decimals$}")).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{sign}{:02}", trim_fraction(mantissa), exponent.abs())
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
@@ -0,0 +1,68 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::{
+    program::Program,
+    vm::{Config, InterpretResult, VM},
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// How many programs run at once. Zero uses one per available CPU.
+    pub jobs: usize,
+    pub config: Config,
+}
+
+/// What running one program printed and how it finished.
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub result: InterpretResult,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Runs each program in its own VM, several at a time, and returns their
+/// outputs in the same order as `programs`.
+pub fn run_parallel(programs: &[Program], options: Options) -> Vec<Output> {
+    let jobs = match options.jobs {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        jobs => jobs,
+    };
+    let next = AtomicUsize::new(0);
+    let outputs = Mutex::new(vec![None; programs.len()]);
+
+    thread::scope(|s| {
+        for _ in 0..jobs.min(programs.len()) {
+            s.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(program) = programs.get(index) else {
+                    break;
+                };
+                let output = run(program, options.config);
+                outputs.lock().unwrap()[index] = Some(output);
+            });
+        }
+    });
+
+    outputs
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+fn run(program: &Program, config: Config) -> Output {
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let result = VM::with_output(config, &mut stdout, &mut stderr).run_program(program);
+    Output {
+        result,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    include::SourceMap,
+    value::{Function, Value},
+};
+
+/// A compiled script along with the debug information for its errors. It's
+/// never modified by running it, so one program can be run any number of
+/// times, including by VMs on different threads at once.
+#[derive(Debug, Default)]
+pub struct Program {
+    pub script: Arc<Function>,
+    pub source_map: Option<Arc<SourceMap>>,
+    /// The source it was compiled from, which runtime errors quote. Programs
+    /// loaded from bytecode don't have it.
+    pub source: Option<Arc<str>>,
+}
+
+/// A global the script defines at the top level.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Symbol {
+    Variable(String),
+    Function(Signature),
+    Class {
+        name: String,
+        methods: Vec<Signature>,
+        doc: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+    pub name: String,
+    pub arity: usize,
+    /// The `///` comment above its declaration, without the slashes, or
+    /// the docstring starting its body.
+    pub doc: Option<String>,
+}
+
+impl Program {
+    pub fn new(chunk: Chunk, source_map: Option<SourceMap>) -> Program {
+        let script = Function {
+            arity: 0,
+            upvalue_count: 0,
+            chunk,
+            name: None,
+            doc: None,
+        };
+        Program {
+            script: Arc::new(script),
+            source_map: source_map.map(Arc::new),
+            source: None,
+        }
+    }
+
+    /// The top-level code, whose constants hold the code of its functions.
+    pub fn chunk(&self) -> &Chunk {
+        &self.script.chunk
+    }
+
+    /// Every constant in the program, including those of the functions
+    /// nested in it, which come right after the function itself.
+    pub fn constants(&self) -> Vec<&Value> {
+        fn collect<'a>(chunk: &'a Chunk, constants: &mut Vec<&'a Value>) {
+            for constant in &chunk.constants {
+                constants.push(constant);
+                if let Some(function) = constant.as_function() {
+                    collect(&function.chunk, constants);
+                }
+            }
+        }
+        let mut constants = vec![];
+        collect(self.chunk(), &mut constants);
+        constants
+    }
+
+    /// The globals the script defines, in the order they're first defined.
+    /// They're read from the bytecode, so this works for programs loaded
+    /// from bytecode files too, which have no source to parse. Doc comments
+    /// are kept in the bytecode for the same reason.
+    pub fn symbols(&self) -> Vec<Symbol> {
+        let chunk = self.chunk();
+        let constant = |offset: usize| {
+            let op_code = OpCode::try_from(chunk.code[offset]).unwrap();
+            &chunk.constants[chunk.constant_index(op_code, offset).unwrap()]
+        };
+        let signature = |offset: usize| {
+            let function = constant(offset).as_function()?;
+            Some(Signature {
+                name: function.name.clone()?,
+                arity: function.arity,
+                doc: function.doc.clone(),
+            })
+        };
+
+        let mut symbols: Vec<Symbol> = vec![];
+        let mut previous = None;
+        // The global loaded before a run of methods, which is the class
+        // they're added to
+        let mut loaded = None;
+        for (offset, op_code) in chunk.op_codes() {
+            // Long forms only differ in the size of their operand
+            let op_code = op_code.short();
+            match op_code {
+                OpCode::DefineGlobal => {
+                    let name = constant(offset).to_string();
+                    let symbol = match previous {
+                        Some((OpCode::Closure, at)) => signature(at).map(Symbol::Function),
+                        Some((OpCode::Class, at)) => Some(Symbol::Class {
+                            name: name.clone(),
+                            methods: vec![],
+                            doc: chunk.get_doc(at).map(String::from),
+                        }),
+                        _ => None,
+                    };
+                    let symbol = symbol.unwrap_or_else(|| Symbol::Variable(name.clone()));
+                    // Redefining a global replaces it
+                    match symbols.iter_mut().find(|s| s.name() == name) {
+                        Some(existing) => *existing = symbol,
+                        None => symbols.push(symbol),
+                    }
+                }
+                OpCode::GetGlobal => loaded = Some(constant(offset).to_string()),
+                OpCode::Method => {
+                    let class = symbols
+                        .iter_mut()
+                        .find(|s| Some(s.name()) == loaded.as_deref());
+                    let method = match previous {
+                        Some((OpCode::Closure, at)) => signature(at),
+                        _ => None,
+                    };
+                    if let (Some(Symbol::Class { methods, .. }), Some(method)) = (class, method) {
+                        methods.push(method);
+                    }
+                }
+                OpCode::Closure => (),
+                // e.g. a class declared in a block, which is loaded as a local
+                _ => loaded = None,
+            }
+            previous = Some((op_code, offset));
+        }
+        symbols
+    }
+}
+
+impl Symbol {
+    pub fn name(&self) -> &str {
+        match self {
+            Symbol::Variable(name) | Symbol::Class { name, .. } => name,
+            Symbol::Function(signature) => &signature.name,
+        }
+    }
+}
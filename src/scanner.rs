@@ -1,19 +1,50 @@
+use std::ops::Range;
+
+/// Every reserved word, in alphabetical order.
+pub const KEYWORDS: &[&str] = &[
+    "and", "break", "catch", "class", "continue", "else", "false", "finally", "for", "fun", "if",
+    "import", "is", "nil", "or", "print", "return", "super", "this", "throw", "true", "try", "var",
+    "while",
+];
+
+/// Splits Lox source into tokens.
+///
+/// Scanning never panics, whatever the input. Text that isn't a valid token
+/// comes back as a [`TokenType::Error`] token, and the iterator yields a
+/// single [`TokenType::Eof`] token once the source is exhausted before it
+/// ends. Whitespace and comments are skipped, so they only show up as the
+/// gaps between token spans.
+#[derive(Clone)]
 pub struct Scanner<'a> {
     start: &'a str,
     current: usize,
+    // Byte offset of `start` in the original source
+    offset: usize,
     line: u32,
+    // Column of the next character, and of the token being scanned
+    column: u32,
+    start_column: u32,
+    done: bool,
+    // Whether `++` and `--` are single tokens, which they aren't in clox
+    increments: bool,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
+    Colon,
     Comma,
     Dot,
     Minus,
+    MinusMinus,
     Plus,
+    PlusPlus,
+    Question,
     Semicolon,
     Slash,
     Star,
@@ -21,6 +52,7 @@ pub enum TokenType {
     BangEqual,
     Equal,
     EqualEqual,
+    Arrow,
     Greater,
     GreaterEqual,
     Less,
@@ -29,30 +61,47 @@ pub enum TokenType {
     String,
     Number,
     And,
+    Break,
+    Catch,
     Class,
+    Continue,
     Else,
     False,
+    Finally,
     For,
     Fun,
     If,
+    Import,
+    Is,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    // A `#pragma` line, which is a directive to the compiler rather than code
+    Pragma,
     Error,
-    EOF,
+    Eof,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Token<'a> {
     pub ty: TokenType,
+    /// The token's text, or the error message for an error token.
     pub str: &'a str,
+    /// The line the token ends on, counting from 1.
     pub line: u32,
+    /// The column the token starts at, counting characters from 1.
+    pub column: u32,
+    /// Byte range of the token in the source. For an error token this covers
+    /// the text that couldn't be scanned.
+    pub span: Range<usize>,
 }
 
 impl<'a> Default for Token<'a> {
@@ -61,20 +110,26 @@ impl<'a> Default for Token<'a> {
             ty: TokenType::Error,
             str: Default::default(),
             line: Default::default(),
+            column: Default::default(),
+            span: Default::default(),
         }
     }
 }
 
 impl<'a> Token<'a> {
-    pub fn new(ty: TokenType, str: &'a str, line: u32) -> Token<'a> {
-        Token { ty, str, line }
-    }
-
-    pub fn error(message: &'static str, line: u32) -> Token<'a> {
+    pub(crate) fn new(
+        ty: TokenType,
+        str: &'a str,
+        line: u32,
+        column: u32,
+        span: Range<usize>,
+    ) -> Token<'a> {
         Token {
-            ty: TokenType::Error,
-            str: message,
+            ty,
+            str,
             line,
+            column,
+            span,
         }
     }
 }
@@ -84,25 +139,59 @@ impl<'a> Scanner<'a> {
         Scanner {
             start: source,
             current: 0,
+            offset: 0,
             line: 1,
+            column: 1,
+            start_column: 1,
+            done: false,
+            increments: true,
+        }
+    }
+
+    /// Scans `--` and `++` as two tokens each, the way clox does, so that
+    /// `--x` negates `x` twice.
+    pub fn without_increments(source: &'a str) -> Scanner<'a> {
+        Scanner {
+            increments: false,
+            ..Scanner::new(source)
         }
     }
 
     fn make_token(&self, ty: TokenType) -> Token<'a> {
-        Token::new(ty, &self.start[..self.current], self.line)
+        Token::new(
+            ty,
+            &self.start[..self.current],
+            self.line,
+            self.start_column,
+            self.span(),
+        )
+    }
+
+    fn error_token(&self, message: &'static str) -> Token<'a> {
+        Token::new(
+            TokenType::Error,
+            message,
+            self.line,
+            self.start_column,
+            self.span(),
+        )
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.offset..self.offset + self.current
     }
 
     fn advance(&mut self) -> Option<char> {
-        self.start[self.current..].chars().next().map(|c| {
+        self.start[self.current..].chars().next().inspect(|&c| {
             self.current += c.len_utf8();
-            c
+            self.column = if c == '\n' { 1 } else { self.column + 1 };
         })
     }
 
     fn matches(&mut self, expected: char) -> bool {
         match self.peek() {
             Some(c) if c == expected => {
-                self.current += c.len_utf8();
+                self.advance();
                 true
             }
             _ => false,
@@ -125,7 +214,7 @@ impl<'a> Scanner<'a> {
                         self.advance();
                     }
                     '/' if self.peek_next() == Some('/') => {
-                        while self.peek().map_or(false, |c| c != '\n') {
+                        while self.peek().is_some_and(|c| c != '\n') {
                             self.advance();
                         }
                     }
@@ -143,35 +232,35 @@ impl<'a> Scanner<'a> {
     }
 
     fn string(&mut self) -> Token<'a> {
-        while self.peek().map_or(false, |c| c != '"') {
-            if self.peek().unwrap() == '\n' {
+        while self.peek().is_some_and(|c| c != '"') {
+            if self.peek() == Some('\n') {
                 self.line += 1;
-                self.advance();
             }
+            self.advance();
         }
 
         // The closing quote
-        if let None = self.advance() {
-            return Token::error("Unterminated string", self.line);
+        if self.advance().is_none() {
+            return self.error_token("Unterminated string");
         }
         self.make_token(TokenType::String)
     }
 
     fn is_digit(c: char) -> bool {
-        c.is_digit(10)
+        c.is_ascii_digit()
     }
 
     fn number(&mut self) -> Token<'a> {
-        while self.peek().map_or(false, Self::is_digit) {
+        while self.peek().is_some_and(Self::is_digit) {
             self.advance();
         }
 
         // Look for a fractional part
-        if self.peek() == Some('.') && self.peek_next().map_or(false, Self::is_digit) {
+        if self.peek() == Some('.') && self.peek_next().is_some_and(Self::is_digit) {
             // Consume the "."
             self.advance();
 
-            while Self::is_digit(self.peek().unwrap()) {
+            while self.peek().is_some_and(Self::is_digit) {
                 self.advance();
             }
         }
@@ -183,10 +272,23 @@ impl<'a> Scanner<'a> {
         c.is_alphabetic() || c == '_'
     }
 
+    fn at_pragma(&self) -> bool {
+        let rest = &self.start[self.current..];
+        rest.strip_prefix("pragma")
+            .is_some_and(|rest| !rest.starts_with(|c| Self::is_alpha(c) || Self::is_digit(c)))
+    }
+
+    fn pragma(&mut self) -> Token<'a> {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.advance();
+        }
+        self.make_token(TokenType::Pragma)
+    }
+
     fn identifier(&mut self) -> Token<'a> {
         while self
             .peek()
-            .map_or(false, |c| Self::is_alpha(c) || Self::is_digit(c))
+            .is_some_and(|c| Self::is_alpha(c) || Self::is_digit(c))
         {
             self.advance();
         }
@@ -196,19 +298,27 @@ impl<'a> Scanner<'a> {
     fn identifier_type(&self) -> TokenType {
         match &self.start[..self.current] {
             "and" => TokenType::And,
+            "break" => TokenType::Break,
+            "catch" => TokenType::Catch,
             "class" => TokenType::Class,
+            "continue" => TokenType::Continue,
             "else" => TokenType::Else,
             "false" => TokenType::False,
+            "finally" => TokenType::Finally,
             "for" => TokenType::For,
             "fun" => TokenType::Fun,
             "if" => TokenType::If,
+            "import" => TokenType::Import,
+            "is" => TokenType::Is,
             "nil" => TokenType::Nil,
             "or" => TokenType::Or,
             "print" => TokenType::Print,
             "return" => TokenType::Return,
             "super" => TokenType::Super,
             "this" => TokenType::This,
+            "throw" => TokenType::Throw,
             "true" => TokenType::True,
+            "try" => TokenType::Try,
             "var" => TokenType::Var,
             "while" => TokenType::While,
             _ => TokenType::Identifier,
@@ -220,15 +330,21 @@ impl<'a> Iterator for Scanner<'a> {
     type Item = Token<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
         self.skip_whitespace();
         self.start = &self.start[self.current..];
+        self.offset += self.current;
         self.current = 0;
+        self.start_column = self.column;
 
         let c = self.advance();
         let c = if let Some(c) = c {
             c
         } else {
-            return Some(self.make_token(TokenType::EOF));
+            self.done = true;
+            return Some(self.make_token(TokenType::Eof));
         };
         if Self::is_alpha(c) {
             return Some(self.identifier());
@@ -242,11 +358,29 @@ impl<'a> Iterator for Scanner<'a> {
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::Semicolon),
+            ':' => self.make_token(TokenType::Colon),
+            '?' => self.make_token(TokenType::Question),
             ',' => self.make_token(TokenType::Comma),
             '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '-' => {
+                let ty = if self.increments && self.matches('-') {
+                    TokenType::MinusMinus
+                } else {
+                    TokenType::Minus
+                };
+                self.make_token(ty)
+            }
+            '+' => {
+                let ty = if self.increments && self.matches('+') {
+                    TokenType::PlusPlus
+                } else {
+                    TokenType::Plus
+                };
+                self.make_token(ty)
+            }
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
             '!' => {
@@ -260,6 +394,8 @@ impl<'a> Iterator for Scanner<'a> {
             '=' => {
                 let ty = if self.matches('=') {
                     TokenType::EqualEqual
+                } else if self.matches('>') {
+                    TokenType::Arrow
                 } else {
                     TokenType::Equal
                 };
@@ -282,7 +418,8 @@ impl<'a> Iterator for Scanner<'a> {
                 self.make_token(ty)
             }
             '\"' => self.string(),
-            _ => Token::error("Unexpected character.", self.line),
+            '#' if self.at_pragma() => self.pragma(),
+            _ => self.error_token("Unexpected character."),
         })
     }
 }
@@ -0,0 +1,249 @@
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use crate::{
+    compiler::{self, Severity},
+    source,
+    vm::{Config, InterpretResult, VM},
+};
+use anyhow::{bail, Result};
+
+// Requests and responses are each a frame: a u32 length followed by that many
+// bytes. All integers are little-endian, and strings are a u32 length
+// followed by UTF-8 bytes.
+//
+//   request: flags u8, timeout in milliseconds u32 (zero for none), then the
+//     rest of the frame is the source
+//   response: status u8, stdout, stderr, then a u32 count of diagnostics,
+//     each a severity u8 followed by its text
+const FLAG_CLOX_COMPAT: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_COMPILE_ERROR: u8 = 1;
+const STATUS_RUNTIME_ERROR: u8 = 2;
+
+const SEVERITY_ERROR: u8 = 0;
+const SEVERITY_WARNING: u8 = 1;
+
+/// A program to run and how to run it.
+#[derive(Clone, Debug, Default)]
+pub struct Request {
+    pub source: String,
+    pub clox_compat: bool,
+    pub timeout: Option<Duration>,
+}
+
+/// How running a request went.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Response {
+    pub result: InterpretResult,
+    pub stdout: String,
+    /// Runtime errors and their stack traces.
+    pub stderr: String,
+    /// Compile errors and warnings, as the compiler prints them.
+    pub diagnostics: Vec<(Severity, String)>,
+}
+
+/// Answers requests read from `input` until it ends, each with a fresh VM so
+/// that one program can't affect the next.
+pub fn serve(mut input: impl Read, mut output: impl Write) -> Result<()> {
+    while let Some(frame) = read_frame(&mut input)? {
+        // A frame that can't be decoded is still a whole frame, so later
+        // requests can be answered after it
+        let response = match Request::decode(frame) {
+            Ok(request) => handle(request),
+            Err(e) => Response {
+                result: InterpretResult::CompileError,
+                stdout: String::new(),
+                stderr: String::new(),
+                diagnostics: vec![(Severity::Error, e.to_string())],
+            },
+        };
+        write_frame(&mut output, &response.encode())?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Serves each connection to a unix socket at `path` on its own thread.
+#[cfg(unix)]
+pub fn serve_unix(path: &std::path::Path) -> Result<()> {
+    use anyhow::Context;
+    use std::{os::unix::net::UnixListener, thread};
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Could not listen on {}.", path.display()))?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        thread::spawn(move || {
+            if let Err(e) = serve(&stream, &stream) {
+                eprintln!("{e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Compiles and runs a single request.
+pub fn handle(request: Request) -> Response {
+    let (program, diagnostics) = compiler::compile_with_diagnostics(&request.source, None);
+    let diagnostics = diagnostics
+        .iter()
+        .map(|d| (d.severity, d.to_string()))
+        .collect();
+    let Ok(program) = program else {
+        return Response {
+            result: InterpretResult::CompileError,
+            stdout: String::new(),
+            stderr: String::new(),
+            diagnostics,
+        };
+    };
+
+    let config = Config {
+        clox_compat: request.clox_compat,
+        timeout: request.timeout,
+        ..Default::default()
+    };
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let result = VM::with_output(config, &mut stdout, &mut stderr).run_program(&program);
+    Response {
+        result,
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        diagnostics,
+    }
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![if self.clox_compat {
+            FLAG_CLOX_COMPAT
+        } else {
+            0
+        }];
+        let timeout = self.timeout.map_or(0, |t| t.as_millis().max(1));
+        write_u32(&mut out, timeout.min(u32::MAX as u128) as u32);
+        out.extend_from_slice(self.source.as_bytes());
+        out
+    }
+
+    pub fn decode(frame: Vec<u8>) -> Result<Request> {
+        let Some((header, source)) = frame.split_at_checked(5) else {
+            bail!("Request is too short");
+        };
+        let timeout = u32::from_le_bytes(header[1..].try_into()?);
+        let source = match source::decode(source.to_vec()) {
+            Ok(source) => source,
+            Err((position, _)) => bail!("Invalid UTF-8 in request at {position}"),
+        };
+        Ok(Request {
+            source,
+            clox_compat: header[0] & FLAG_CLOX_COMPAT != 0,
+            timeout: (timeout > 0).then(|| Duration::from_millis(timeout as u64)),
+        })
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![match self.result {
+            InterpretResult::Ok => STATUS_OK,
+            InterpretResult::CompileError => STATUS_COMPILE_ERROR,
+            InterpretResult::RuntimeError => STATUS_RUNTIME_ERROR,
+        }];
+        write_bytes(&mut out, self.stdout.as_bytes());
+        write_bytes(&mut out, self.stderr.as_bytes());
+        write_u32(&mut out, self.diagnostics.len() as u32);
+        for (severity, text) in &self.diagnostics {
+            out.push(match severity {
+                Severity::Error => SEVERITY_ERROR,
+                Severity::Warning => SEVERITY_WARNING,
+            });
+            write_bytes(&mut out, text.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(frame: Vec<u8>) -> Result<Response> {
+        let mut reader = &frame[..];
+        let result = match read_u8(&mut reader)? {
+            STATUS_OK => InterpretResult::Ok,
+            STATUS_COMPILE_ERROR => InterpretResult::CompileError,
+            STATUS_RUNTIME_ERROR => InterpretResult::RuntimeError,
+            status => bail!("Unknown status {status}"),
+        };
+        let stdout = read_string(&mut reader)?;
+        let stderr = read_string(&mut reader)?;
+        let mut diagnostics = vec![];
+        for _ in 0..read_u32(&mut reader)? {
+            let severity = match read_u8(&mut reader)? {
+                SEVERITY_ERROR => Severity::Error,
+                SEVERITY_WARNING => Severity::Warning,
+                severity => bail!("Unknown severity {severity}"),
+            };
+            diagnostics.push((severity, read_string(&mut reader)?));
+        }
+        Ok(Response {
+            result,
+            stdout,
+            stderr,
+            diagnostics,
+        })
+    }
+}
+
+/// Reads the next frame, or None if `input` ended cleanly before it.
+pub fn read_frame(input: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len) as u64;
+    let mut frame = vec![];
+    input.take(len).read_to_end(&mut frame)?;
+    if (frame.len() as u64) < len {
+        bail!("Unexpected end of frame");
+    }
+    Ok(Some(frame))
+}
+
+pub fn write_frame(output: &mut impl Write, frame: &[u8]) -> Result<()> {
+    output.write_all(&(frame.len() as u32).to_le_bytes())?;
+    output.write_all(frame)?;
+    Ok(())
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn read_u8(reader: &mut &[u8]) -> Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_u32(reader: &mut &[u8]) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string(reader: &mut &[u8]) -> Result<String> {
+    let len = read_u32(reader)? as usize;
+    let Some((bytes, rest)) = reader.split_at_checked(len) else {
+        bail!("Unexpected end of response");
+    };
+    *reader = rest;
+    Ok(String::from_utf8(bytes.to_vec())?)
+}
@@ -0,0 +1,121 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::Read,
+    ops::Range,
+    path::Path,
+    str,
+};
+
+use anyhow::{bail, Context, Result};
+
+/// How source files are read.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Files larger than this many bytes are refused rather than read.
+    pub max_size: u64,
+    /// Replace invalid UTF-8 with U+FFFD and warn about it, instead of
+    /// refusing the file.
+    pub lossy: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_size: 1 << 30,
+            lossy: false,
+        }
+    }
+}
+
+/// Where a source first stops being valid UTF-8.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct InvalidUtf8 {
+    pub line: u32,
+    /// Counted in characters from 1, like the column of an editor.
+    pub column: usize,
+    pub offset: usize,
+}
+
+impl Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {} (byte {})",
+            self.line, self.column, self.offset
+        )
+    }
+}
+
+/// Reads the file at `path`, refusing it once it turns out to be larger than
+/// `options.max_size`. It's read a piece at a time rather than all at once,
+/// so a file that grows while it's read is still caught.
+pub fn read(path: &Path, options: Options) -> Result<Vec<u8>> {
+    let file =
+        File::open(path).with_context(|| format!("Could not open file {}.", path.display()))?;
+    let expected = file.metadata().map_or(0, |m| m.len());
+    if expected > options.max_size {
+        bail!(too_large(path, options));
+    }
+
+    let mut bytes = Vec::with_capacity(expected as usize);
+    file.take(options.max_size.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .with_context(|| format!("Could not read file {}.", path.display()))?;
+    if bytes.len() as u64 > options.max_size {
+        bail!(too_large(path, options));
+    }
+    Ok(bytes)
+}
+
+fn too_large(path: &Path, options: Options) -> String {
+    format!(
+        "File {} is larger than the limit of {} bytes.",
+        path.display(),
+        options.max_size
+    )
+}
+
+/// Decodes `bytes` as UTF-8. If they aren't valid, the error says where the
+/// first invalid byte is, and comes with a decoding in which every invalid
+/// sequence is replaced by U+FFFD.
+pub fn decode(bytes: Vec<u8>) -> Result<String, (InvalidUtf8, String)> {
+    let error = match String::from_utf8(bytes) {
+        Ok(source) => return Ok(source),
+        Err(e) => e,
+    };
+    let bytes = error.as_bytes();
+    let offset = error.utf8_error().valid_up_to();
+    // Everything before the invalid byte is valid, so it can be counted in
+    // characters
+    let valid = str::from_utf8(&bytes[..offset]).unwrap();
+    let line_start = valid.rfind('\n').map_or(0, |i| i + 1);
+    let position = InvalidUtf8 {
+        line: valid.matches('\n').count() as u32 + 1,
+        column: valid[line_start..].chars().count() + 1,
+        offset,
+    };
+    Err((position, String::from_utf8_lossy(bytes).into_owned()))
+}
+
+/// The line of `source` that `span` starts on, and a line to go under it
+/// marking the span with carets. A span running onto later lines is only
+/// marked up to the end of the first, and an empty one, e.g. at the end,
+/// still gets a caret. There's nothing to mark if the span isn't in
+/// `source`, e.g. because the source changed since it was compiled.
+pub fn underline(source: &str, span: Range<usize>) -> Option<(&str, String)> {
+    let before = source.get(..span.start)?;
+    let start = before.rfind('\n').map_or(0, |i| i + 1);
+    let end = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+    let line = &source[start..end];
+
+    // Tabs are kept so the carets line up however wide they're shown
+    let indent: String = source[start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = source.get(span.start..span.end.min(end))?.chars().count();
+    Some((line, format!("{indent}{}", "^".repeat(width.max(1)))))
+}
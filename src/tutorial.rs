@@ -0,0 +1,84 @@
+use std::io::Write;
+
+use crate::vm::{InterpretResult, VM};
+
+// Each lesson is a Lox program in the same format as the clox test suite: a
+// comment introducing it and setting a task, followed by a solution whose
+// output is given by `// expect:` comments
+const SOURCES: &[&str] = &[
+    include_str!("../lessons/01-print.lox"),
+    include_str!("../lessons/02-variables.lox"),
+    include_str!("../lessons/03-control-flow.lox"),
+    include_str!("../lessons/04-functions.lox"),
+    include_str!("../lessons/05-closures.lox"),
+    include_str!("../lessons/06-classes.lox"),
+];
+
+const EXPECT: &str = "// expect: ";
+
+pub struct Lesson {
+    pub title: String,
+    /// What the lesson teaches, ending with the task it sets.
+    pub text: String,
+    /// A program that completes the task.
+    pub solution: String,
+    /// What a program completing the task prints.
+    pub expected: String,
+}
+
+pub enum Outcome {
+    Correct,
+    /// The answer didn't compile or hit a runtime error, which has already
+    /// been reported.
+    Failed,
+    /// The answer ran but printed something other than the expected output.
+    Incorrect,
+}
+
+pub fn lessons() -> Vec<Lesson> {
+    SOURCES.iter().map(|source| Lesson::parse(source)).collect()
+}
+
+impl Lesson {
+    fn parse(source: &str) -> Lesson {
+        let mut lines = source.lines();
+        let title = lines.next().unwrap_or_default();
+        let title = title.trim_start_matches('/').trim().to_string();
+
+        let mut text = vec![];
+        let mut solution = vec![];
+        for line in lines {
+            match line.strip_prefix("//") {
+                Some(comment) if solution.is_empty() => text.push(comment.trim()),
+                _ => solution.push(line),
+            }
+        }
+
+        let expected = solution
+            .iter()
+            .filter_map(|line| line.split_once(EXPECT))
+            .map(|(_, output)| format!("{output}\n"))
+            .collect();
+        Lesson {
+            title,
+            text: text.join("\n").trim().to_string(),
+            solution: solution.join("\n"),
+            expected,
+        }
+    }
+
+    /// Runs `answer` in a fresh VM, passing on what it prints to `stdout`, and
+    /// checks that it printed the expected output.
+    pub fn check(&self, answer: &str, mut stdout: impl Write) -> Outcome {
+        let mut output = vec![];
+        let result = VM::with_output(Default::default(), &mut output, std::io::stderr())
+            .interpret(answer, None);
+        // Like the VM, failures to write output are ignored
+        let _ = stdout.write_all(&output);
+        match result {
+            InterpretResult::Ok if output == self.expected.as_bytes() => Outcome::Correct,
+            InterpretResult::Ok => Outcome::Incorrect,
+            InterpretResult::CompileError | InterpretResult::RuntimeError => Outcome::Failed,
+        }
+    }
+}
@@ -1,29 +1,207 @@
 use std::{
+    collections::HashMap,
     fmt::{self, Display, Formatter},
-    rc::Rc,
+    sync::{Arc, Mutex},
 };
 
+use crate::{chunk::Chunk, number};
+
 #[derive(Debug)]
 pub enum Obj {
     String(String),
+    Bytes(Vec<u8>),
+    Function(Arc<Function>),
+    Native(Native),
+    Closure(Arc<Closure>),
+    Class(Arc<Class>),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+}
+
+/// A function implemented in Rust. Returning an error reports it as a
+/// runtime error at the call.
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct Native {
+    /// The global it was defined as, for stack traces.
+    pub name: String,
+    pub function: NativeFn,
+    /// For a method of a built-in type, the value it was accessed on, which
+    /// is passed as the first argument.
+    pub receiver: Option<Value>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// None for the top-level script.
+    pub name: Option<String>,
+    /// Its doc comment, or the docstring starting its body.
+    pub doc: Option<String>,
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// A function along with the variables it captured from the functions
+/// enclosing it. Functions are only ever called through one.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: Arc<Function>,
+    pub upvalues: Vec<Arc<Mutex<Upvalue>>>,
+    /// The VM's index for the module it was created in, whose globals it
+    /// uses.
+    pub module: usize,
+}
+
+/// A captured variable, which stays on the stack until it goes out of scope
+/// and then moves into the upvalue so closures can keep using it.
+#[derive(Debug)]
+pub enum Upvalue {
+    /// The variable's absolute stack slot
+    Open(usize),
+    Closed(Value),
+}
+
+/// Methods are added one at a time as the class declaration runs, after the
+/// class itself has been created.
+#[derive(Debug)]
+pub struct Class {
+    pub name: String,
+    /// Its doc comment, or the docstring starting its body.
+    pub doc: Option<String>,
+    pub methods: Mutex<HashMap<String, Arc<Closure>>>,
+}
+
+#[derive(Debug)]
+pub struct Instance {
+    pub class: Arc<Class>,
+    pub fields: Mutex<HashMap<String, Value>>,
+}
+
+/// A method accessed on an instance, which remembers the instance to use as
+/// `this` when it's called.
+#[derive(Debug)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Arc<Closure>,
+}
+
+#[derive(Debug, Clone, Default)]
 pub enum Value {
     Bool(bool),
+    #[default]
     Nil,
     Number(f64),
-    Obj(Rc<Obj>),
+    Obj(Arc<Obj>),
 }
 
 impl Value {
     pub fn as_str(&self) -> Option<&str> {
-        if let Self::Obj(o) = self {
-            if let Obj::String(s) = o.as_ref() {
-                return Some(s);
-            }
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::String(s) => Some(s),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Bytes(bytes) => Some(bytes),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Arc<Function>> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Function(function) => Some(function),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Arc<Closure>> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Closure(closure) => Some(closure),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Arc<Class>> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Class(class) => Some(class),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Instance> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Instance(instance) => Some(instance),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self) -> Option<&BoundMethod> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::BoundMethod(bound) => Some(bound),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self) -> Option<&Native> {
+        match self {
+            Self::Obj(o) => match o.as_ref() {
+                Obj::Native(native) => Some(native),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The name `is` checks a value against.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Number(_) => "number",
+            Value::Obj(o) => match o.as_ref() {
+                Obj::String(_) => "string",
+                Obj::Bytes(_) => "bytes",
+                Obj::Function(_) | Obj::Native(_) | Obj::Closure(_) | Obj::BoundMethod(_) => {
+                    "function"
+                }
+                Obj::Class(_) => "class",
+                Obj::Instance(_) => "instance",
+            },
         }
-        None
     }
 
     pub fn is_string(&self) -> bool {
@@ -31,7 +209,58 @@ impl Value {
     }
 
     pub fn from_string(s: String) -> Value {
-        Self::Obj(Rc::new(Obj::String(s)))
+        Self::Obj(Arc::new(Obj::String(s)))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Value {
+        Self::Obj(Arc::new(Obj::Bytes(bytes)))
+    }
+
+    pub fn from_function(function: Arc<Function>) -> Value {
+        Self::Obj(Arc::new(Obj::Function(function)))
+    }
+
+    pub fn from_closure(closure: Arc<Closure>) -> Value {
+        Self::Obj(Arc::new(Obj::Closure(closure)))
+    }
+
+    pub fn from_class(name: String, doc: Option<String>) -> Value {
+        let class = Class {
+            name,
+            doc,
+            methods: Mutex::default(),
+        };
+        Self::Obj(Arc::new(Obj::Class(Arc::new(class))))
+    }
+
+    pub fn from_instance(class: Arc<Class>) -> Value {
+        let instance = Instance {
+            class,
+            fields: Mutex::default(),
+        };
+        Self::Obj(Arc::new(Obj::Instance(instance)))
+    }
+
+    pub fn from_bound_method(receiver: Value, method: Arc<Closure>) -> Value {
+        Self::Obj(Arc::new(Obj::BoundMethod(BoundMethod { receiver, method })))
+    }
+
+    pub fn from_native(name: &str, function: NativeFn) -> Value {
+        let native = Native {
+            name: name.to_string(),
+            function,
+            receiver: None,
+        };
+        Self::Obj(Arc::new(Obj::Native(native)))
+    }
+
+    pub fn from_native_method(receiver: Value, name: &str, function: NativeFn) -> Value {
+        let native = Native {
+            name: name.to_string(),
+            function,
+            receiver: Some(receiver),
+        };
+        Self::Obj(Arc::new(Obj::Native(native)))
     }
 }
 
@@ -40,20 +269,25 @@ impl Display for Value {
         match self {
             Value::Bool(b) => write!(f, "{b}"),
             Value::Nil => write!(f, "nil"),
-            Value::Number(n) => write!(f, "{n}"),
+            Value::Number(n) => f.write_str(&number::format(*n, number::Format::Shortest)),
             Value::Obj(o) => match o.as_ref() {
                 Obj::String(s) => write!(f, "{s}"),
+                Obj::Bytes(bytes) => {
+                    write!(f, "<bytes ")?;
+                    bytes.iter().try_for_each(|b| write!(f, "{b:02x}"))?;
+                    write!(f, ">")
+                }
+                Obj::Function(function) => write!(f, "{function}"),
+                Obj::Native(_) => write!(f, "<native fn>"),
+                Obj::Closure(closure) => write!(f, "{}", closure.function),
+                Obj::Class(class) => write!(f, "{}", class.name),
+                Obj::Instance(instance) => write!(f, "{} instance", instance.class.name),
+                Obj::BoundMethod(bound) => write!(f, "{}", bound.method.function),
             },
         }
     }
 }
 
-impl Default for Value {
-    fn default() -> Self {
-        Self::Nil
-    }
-}
-
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -62,6 +296,11 @@ impl PartialEq for Value {
             (Self::Nil, Self::Nil) => true,
             (Self::Obj(a), Self::Obj(b)) => match (a.as_ref(), b.as_ref()) {
                 (Obj::String(a), Obj::String(b)) => a == b,
+                (Obj::Bytes(a), Obj::Bytes(b)) => a == b,
+                // Functions are only equal to themselves
+                (Obj::Function(a), Obj::Function(b)) => Arc::ptr_eq(a, b),
+                // So is every other object
+                _ => Arc::ptr_eq(a, b),
             },
             _ => false,
         }
@@ -1,25 +1,237 @@
 use core::fmt;
-use std::{array, mem};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt::{Display, Formatter},
+    fs,
+    io::{self, Write},
+    iter,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-use crate::value::Value;
-use crate::{compiler, Chunk, OpCode};
+use crate::cache;
+use crate::chunk::OpCode;
+use crate::compiler;
+use crate::gc::{Heap, Root, FIRST_GC};
+use crate::include::SourceMap;
+use crate::natives;
+use crate::number;
+use crate::program::Program;
+use crate::source;
+use crate::suggest;
+use crate::value::{Closure, Instance, Native, NativeFn, Upvalue, Value};
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+// The stack starts with room for a frame's worth of values and grows as
+// needed, up to the configured maximum
+const STACK_INITIAL: usize = u8::MAX as usize + 1;
+// Small enough to overflow within a few calls, but with room for a function
+// with a handful of locals
+const STRESS_FRAMES_MAX: usize = 8;
+const STRESS_STACK_MAX: usize = 64;
+// Bounds the size of `"..." * n` so a large count reports an error instead of
+// exhausting memory
+const MAX_REPEAT_LEN: usize = 1 << 30;
+// How many toString() calls can be running at once, so that one which ends up
+// converting its own instance reports that rather than a stack overflow
+const MAX_TO_STRING_DEPTH: usize = 8;
+
+// With the nan-boxing feature the stack holds NanBoxes, half the size of a
+// Value, which are packed as they're pushed and unpacked as they're read
+#[cfg(feature = "nan-boxing")]
+type Slot = crate::nanbox::NanBox;
+#[cfg(not(feature = "nan-boxing"))]
+type Slot = Value;
+
+#[allow(clippy::useless_conversion)]
+fn pack(value: Value) -> Slot {
+    value.into()
+}
+
+#[allow(clippy::useless_conversion)]
+fn unpack(slot: Slot) -> Value {
+    slot.into()
+}
+
+// Slots as the values they hold, which only takes unpacking each of them when
+// they're NanBoxes
+fn values(slots: &[Slot]) -> Cow<'_, [Value]> {
+    #[cfg(feature = "nan-boxing")]
+    return Cow::Owned(slots.iter().cloned().map(unpack).collect());
+    #[cfg(not(feature = "nan-boxing"))]
+    Cow::Borrowed(slots)
+}
 
 pub struct VM<'a> {
-    chunk: &'a Chunk,
+    frames: Vec<CallFrame>,
+    // Grows as needed, but never past the configured maximum
+    stack: Vec<Slot>,
+    // The program being run first, followed by the modules it imported
+    modules: Vec<Module>,
+    // Each imported module's index, by its canonical path
+    registry: HashMap<PathBuf, usize>,
+    // Natives every module starts out with as globals
+    natives: HashMap<String, Value>,
+    // Upvalues still pointing at the stack, ordered by their slot
+    open_upvalues: Vec<(usize, Arc<Mutex<Upvalue>>)>,
+    // The try blocks being run, innermost last
+    handlers: Vec<Handler>,
+    // An exception on its way to the innermost handler
+    thrown: Option<Value>,
+    // The runtime error that ended the last run, if one did
+    error: Option<RuntimeError>,
+    heap: Heap,
+    config: Config,
+    stdout: Box<dyn Write + 'a>,
+    stderr: Box<dyn Write + 'a>,
+    // Set by the watchdog thread when the timeout has passed
+    interrupted: Arc<AtomicBool>,
+    // Where programs and the modules they import are cached once compiled
+    cache: Option<PathBuf>,
+}
+
+// A program or a file it imported, each of which has its own globals
+#[derive(Default)]
+struct Module {
+    globals: HashMap<String, Value>,
+    // The canonical path of its file, which its imports are relative to
+    path: Option<PathBuf>,
+    source_map: Option<Arc<SourceMap>>,
+    source: Option<Arc<str>>,
+    // What importing it gives, once it has finished running
+    exports: Option<Value>,
+}
+
+// Where execution resumes when a try block throws
+struct Handler {
+    // How many frames and values there were when the try block started,
+    // which are all that's left once it throws
+    frames: usize,
+    stack: usize,
+    // The catch code's offset in the innermost of those frames
+    ip: usize,
+}
+
+// A function call in progress
+struct CallFrame {
+    closure: Arc<Closure>,
     ip: usize,
-    stack: [Value; STACK_MAX],
-    stack_top: usize,
+    // Index of the stack slot holding the function being called, which is
+    // followed by its arguments and then its locals
+    slots: usize,
+    // The module whose top level this frame is running for an import
+    importing: Option<usize>,
+    // For a call the VM made itself, what it does with the returned value
+    // instead of pushing it
+    then: Option<Then>,
+}
+
+// What the VM does with the value returned by a method it calls itself
+#[derive(Clone, Copy)]
+enum Then {
+    // With the string from toString()
+    Print,
+    // Replacing the instance among the operands, which are still on the
+    // stack, and adding them
+    Concatenate { left: bool },
+    // With the result of equals(), pushing whether it's truthy
+    Equal,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Match clox's output where ours intentionally differs, e.g. printing
+    /// numbers like `printf("%g")`
+    pub clox_compat: bool,
+    /// How `print` writes numbers, unless `clox_compat` is set
+    pub number_format: number::Format,
+    /// Stop a program with a runtime error once it has run this long. The
+    /// VM only notices at a safepoint, i.e. a jump, so a slow native can
+    /// overrun it.
+    pub timeout: Option<Duration>,
+    /// Collect garbage after every allocation of an object that could be
+    /// part of a cycle, rather than waiting for them to pile up
+    pub gc_stress: bool,
+    /// Report each garbage collection to stderr
+    pub gc_log: bool,
+    /// How many objects that could be part of a cycle can pile up before
+    /// the first garbage collection
+    pub gc_threshold: usize,
+    /// How deeply calls can nest before a stack overflow
+    pub max_frames: usize,
+    /// How many values the stack holds before a stack overflow
+    pub max_stack: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            clox_compat: false,
+            number_format: Default::default(),
+            timeout: None,
+            gc_stress: false,
+            gc_log: false,
+            gc_threshold: FIRST_GC,
+            max_frames: FRAMES_MAX,
+            max_stack: STACK_MAX,
+        }
+    }
 }
 
 #[must_use]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
     RuntimeError,
 }
 
+/// Why `interpret` failed.
+#[derive(Clone, Debug)]
+pub enum InterpretError {
+    /// The diagnostics from compiling, including any warnings.
+    Compile(Vec<compiler::Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+/// A runtime error that nothing caught, along with the calls that led to it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    /// The line of source the error happened on, with the code that failed
+    /// underlined beneath it, when the source is at hand.
+    pub snippet: Option<String>,
+    /// Innermost call first.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// A call in a runtime error's stack trace.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceFrame {
+    /// What was being run, e.g. `f()`, `script` or `[native fn clock]`.
+    pub function: String,
+    /// The line being run, which chunks loaded from stripped bytecode don't
+    /// have.
+    pub line: Option<u32>,
+    /// The file the line is in, when the program had a source map.
+    pub file: Option<String>,
+    /// The byte range of the (expanded) source being run, e.g. the operator
+    /// whose operands were wrong. Bytecode compiled without debug
+    /// information doesn't have one.
+    pub span: Option<Range<usize>>,
+}
+
+#[derive(Clone, Copy)]
 enum BinaryOp {
     Add,
     Subtract,
@@ -30,31 +242,336 @@ enum BinaryOp {
 }
 
 impl<'a> VM<'a> {
-    pub fn new(chunk: &'a Chunk) -> VM<'a> {
-        VM {
-            chunk,
-            ip: 0,
-            stack: array::from_fn(|_| Value::default()),
-            stack_top: 0,
+    pub fn new(config: Config) -> VM<'a> {
+        VM::with_output(config, io::stdout(), io::stderr())
+    }
+
+    /// Creates a VM that prints to `stdout` and reports runtime errors to
+    /// `stderr` instead of the process's streams.
+    pub fn with_output(config: Config, stdout: impl Write + 'a, stderr: impl Write + 'a) -> VM<'a> {
+        let mut vm = VM {
+            frames: vec![],
+            // Most scripts barely use the stack, so it only grows to the
+            // configured maximum when one does
+            stack: Vec::with_capacity(config.max_stack.min(STACK_INITIAL)),
+            modules: vec![Module::default()],
+            registry: HashMap::new(),
+            natives: HashMap::new(),
+            open_upvalues: vec![],
+            handlers: vec![],
+            thrown: None,
+            error: None,
+            heap: Heap::new(config.gc_stress, config.gc_threshold),
+            config,
+            stdout: Box::new(stdout),
+            stderr: Box::new(stderr),
+            interrupted: Arc::default(),
+            cache: None,
+        };
+        for (name, function) in natives::GLOBALS {
+            vm.define_native(name, *function);
+        }
+        vm
+    }
+
+    /// A config that pushes programs into the VM's limits and rarely taken
+    /// paths: a tiny stack, few frames, and garbage collection on every
+    /// allocation, starting from the first object. Used to test those paths
+    /// with small programs.
+    pub fn stress_options() -> Config {
+        Config {
+            gc_stress: true,
+            gc_threshold: 1,
+            max_frames: STRESS_FRAMES_MAX,
+            max_stack: STRESS_STACK_MAX,
+            ..Default::default()
+        }
+    }
+
+    /// Makes `function` callable from Lox as the global `name`, in the
+    /// program and in every module it imports from now on.
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
+        let native = Value::from_native(name, function);
+        self.modules[0]
+            .globals
+            .insert(name.to_string(), native.clone());
+        self.natives.insert(name.to_string(), native);
+    }
+
+    /// The names of the globals programs it runs can use, including the
+    /// natives, in no particular order.
+    pub fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.modules[0].globals.keys().map(String::as_str)
+    }
+
+    /// Sets the file the programs it runs came from, which their imports
+    /// are resolved relative to. Otherwise they're relative to the current
+    /// directory.
+    pub fn set_path(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        // So that a module importing the program is caught as circular
+        self.registry.insert(path.clone(), 0);
+        self.modules[0].path = Some(path);
+    }
+
+    /// Caches the programs it compiles from files, and the modules they
+    /// import, in `dir`, reusing them when the same source is seen again.
+    pub fn set_cache(&mut self, dir: &Path) {
+        self.cache = Some(dir.to_path_buf());
+    }
+
+    /// Compiles and runs `source`. Globals it defines stay around for
+    /// whatever the VM runs next.
+    pub fn interpret(&mut self, source: &str, source_map: Option<SourceMap>) -> InterpretResult {
+        match self.compile(source, source_map) {
+            Err(_) => InterpretResult::CompileError,
+            Ok(program) => self.run_program(&program),
+        }
+    }
+
+    /// Compiles `source`, reporting errors and warnings the way this VM
+    /// reports runtime errors.
+    pub fn compile(
+        &mut self,
+        source: &str,
+        source_map: Option<SourceMap>,
+    ) -> anyhow::Result<Program> {
+        let path = self.modules[0].path.clone();
+        self.compile_file(source, source_map, path.as_deref())
+    }
+
+    // Compiles the source of the file at `path`, going through the cache if
+    // there is one. Programs with warnings aren't cached so that they're
+    // reported every time. Failing to write the cache isn't worth stopping
+    // for.
+    fn compile_file(
+        &mut self,
+        source: &str,
+        source_map: Option<SourceMap>,
+        path: Option<&Path>,
+    ) -> anyhow::Result<Program> {
+        // clox reads some source differently, so what compat mode compiles
+        // isn't cached in place of the usual program
+        let cached = self
+            .cache
+            .clone()
+            .zip(path)
+            .filter(|_| !self.config.clox_compat);
+        let key = cache::key(source, source_map.as_ref());
+        if let Some(mut program) = cached.as_ref().and_then(|(dir, _)| cache::load(dir, &key)) {
+            // Only the bytecode is cached, and errors quote the source
+            program.source = Some(source.into());
+            return Ok(program);
+        }
+        let (program, diagnostics) = compile(source, source_map, self.config);
+        for diagnostic in &diagnostics {
+            self.report(source, diagnostic);
+        }
+        if let (Ok(program), Some((dir, path)), true) = (&program, cached, diagnostics.is_empty()) {
+            let _ = cache::store(&dir, &key, &path.display().to_string(), program);
+        }
+        program
+    }
+
+    // Quotes the source a diagnostic is about, unless output has to match
+    // clox's
+    fn report(&mut self, source: &str, diagnostic: &compiler::Diagnostic) {
+        let _ = if self.config.clox_compat {
+            writeln!(self.stderr, "{diagnostic}")
+        } else {
+            writeln!(self.stderr, "{}", diagnostic.with_snippet(source))
+        };
+    }
+
+    /// Runs `program` from the start on a fresh stack.
+    pub fn run_program(&mut self, program: &Program) -> InterpretResult {
+        self.error = None;
+        self.modules[0].source_map = program.source_map.clone();
+        self.modules[0].source = program.source.clone();
+        self.reset_stack();
+        let script = Arc::new(Closure {
+            function: program.script.clone(),
+            upvalues: vec![],
+            module: 0,
+        });
+        self.push(Value::from_closure(script.clone()));
+        if !self.call(script, 0) {
+            return InterpretResult::RuntimeError;
+        }
+
+        // Dropping the sender when the run ends stops the watchdog early
+        let _watchdog = self.config.timeout.map(|timeout| self.watch(timeout));
+        self.run()
+    }
+
+    /// Frees objects that are only kept alive by reference cycles, e.g. an
+    /// instance stored in its own field, returning how many were freed. The
+    /// VM also does this by itself as such objects pile up.
+    pub fn collect_garbage(&mut self) -> usize {
+        let stack = values(&self.stack);
+        let roots = stack
+            .iter()
+            .chain(
+                self.modules
+                    .iter()
+                    .flat_map(|module| module.globals.values().chain(&module.exports)),
+            )
+            .map(Root::Value)
+            .chain(
+                self.frames
+                    .iter()
+                    .map(|frame| Root::Closure(&frame.closure)),
+            )
+            .chain(
+                self.open_upvalues
+                    .iter()
+                    .map(|(_, upvalue)| Root::Upvalue(upvalue)),
+            );
+        let start = Instant::now();
+        let (freed, bytes) = self.heap.collect(roots);
+        if self.config.gc_log {
+            let _ = writeln!(
+                self.stderr,
+                "-- gc: freed {freed} objects (about {bytes} bytes), {} remain, took {:?}",
+                self.heap.len(),
+                start.elapsed()
+            );
+        }
+        freed
+    }
+
+    fn watch(&mut self, timeout: Duration) -> mpsc::Sender<()> {
+        // A fresh flag per run, so a watchdog that's slow to exit can't
+        // interrupt a later one
+        let interrupted = Arc::new(AtomicBool::new(false));
+        self.interrupted = interrupted.clone();
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+                interrupted.store(true, Ordering::Relaxed);
+            }
+        });
+        done
+    }
+
+    fn run(&mut self) -> InterpretResult {
+        loop {
+            match self.execute() {
+                InterpretResult::RuntimeError => match self.thrown.take() {
+                    Some(exception) => self.unwind(exception),
+                    None => return InterpretResult::RuntimeError,
+                },
+                result => return result,
+            }
         }
     }
 
-    pub fn run(&mut self) -> InterpretResult {
+    // Runs until the program ends or something is thrown
+    fn execute(&mut self) -> InterpretResult {
         loop {
             if cfg!(feature = "debug_trace_execution") {
                 print!("           ");
-                for i in 0..self.stack_top {
-                    print!("[ {} ]", self.stack[i]);
+                for value in values(&self.stack).iter() {
+                    print!("[ {value} ]");
                 }
-                println!("");
-                self.chunk.disassemble_instruction(self.ip);
+                println!();
+                let frame = self.frame();
+                frame
+                    .closure
+                    .function
+                    .chunk
+                    .disassemble_instruction(frame.ip);
             }
-            let instruction = self.read_byte().try_into().unwrap();
+            // Every instruction pushes at most one value, so checking before
+            // each one is enough to never write past the end of the stack
+            if self.stack.len() >= self.config.max_stack {
+                self.runtime_error(format_args!("Stack overflow."));
+                return InterpretResult::RuntimeError;
+            }
+            // Between instructions, every live object is reachable from the
+            // roots
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+            let instruction = match self.read_byte().try_into() {
+                Ok(instruction) => instruction,
+                Err(_) => {
+                    self.runtime_error(format_args!("Unknown opcode."));
+                    return InterpretResult::RuntimeError;
+                }
+            };
             match instruction {
-                OpCode::Return => {
+                OpCode::Print => {
                     let val = self.pop();
-                    println!("{val}");
-                    return InterpretResult::Ok;
+                    match self.call_to_string(&val, Then::Print) {
+                        Some(true) => (),
+                        Some(false) => return InterpretResult::RuntimeError,
+                        None => self.print(&val),
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    if !self.call_value(self.peek(arg_count), arg_count) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let function = self
+                        .read_constant(instruction)
+                        .as_function()
+                        .unwrap()
+                        .clone();
+                    let upvalues = (0..function.upvalue_count)
+                        .map(|_| {
+                            let is_local = self.read_byte() == 1;
+                            let index = self.read_byte() as usize;
+                            if is_local {
+                                self.capture_upvalue(self.frame().slots + index)
+                            } else {
+                                self.frame().closure.upvalues[index].clone()
+                            }
+                        })
+                        .collect();
+                    let module = self.frame().closure.module;
+                    self.push(Value::from_closure(Arc::new(Closure {
+                        function,
+                        upvalues,
+                        module,
+                    })));
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop();
+                }
+                OpCode::Return => {
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    // Returning from inside a try block leaves it
+                    while self
+                        .handlers
+                        .last()
+                        .is_some_and(|h| h.frames > self.frames.len())
+                    {
+                        self.handlers.pop();
+                    }
+                    self.close_upvalues(frame.slots);
+                    // Discard the callee and its arguments and locals
+                    self.stack.truncate(frame.slots);
+                    if self.frames.is_empty() {
+                        return InterpretResult::Ok;
+                    }
+                    match (frame.importing, frame.then) {
+                        (Some(module), _) => {
+                            let exports = self.export(module);
+                            self.push(exports);
+                        }
+                        (None, Some(then)) => {
+                            if !self.finish(then, result) {
+                                return InterpretResult::RuntimeError;
+                            }
+                        }
+                        (None, None) => self.push(result),
+                    }
                 }
                 OpCode::Add => match self.binary_op(BinaryOp::Add) {
                     InterpretResult::CompileError => return InterpretResult::CompileError,
@@ -90,17 +607,227 @@ impl<'a> VM<'a> {
                         return InterpretResult::RuntimeError;
                     }
                 },
-                OpCode::Constant => {
-                    let constant = self.read_constant().clone();
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(instruction).clone();
                     self.push(constant);
                 }
+                OpCode::Is => {
+                    let ty = self.pop();
+                    let value = self.pop();
+                    // Classes don't have superclasses, so an instance's class
+                    // is the only one in its chain
+                    let class = value.as_instance().map(|instance| &instance.class);
+                    let is = match (ty.as_str(), ty.as_class()) {
+                        (Some(name), _) => {
+                            value.type_name() == name || class.is_some_and(|c| c.name == name)
+                        }
+                        (None, Some(ty)) => class.is_some_and(|c| Arc::ptr_eq(c, ty)),
+                        (None, None) => {
+                            self.runtime_error(format_args!(
+                                "Right operand of 'is' must be a type name or a class."
+                            ));
+                            return InterpretResult::RuntimeError;
+                        }
+                    };
+                    self.push(Value::Bool(is));
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_string(instruction);
+                    let value = self.pop();
+                    self.globals().insert(name, value);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_string(instruction);
+                    match self.globals().get(&name).cloned() {
+                        Some(value) => self.push(value),
+                        None => {
+                            self.undefined_variable(&name);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_string(instruction);
+                    // Assignment leaves its value on the stack as the
+                    // expression's result
+                    let value = self.peek(0);
+                    match self.globals().get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => {
+                            self.undefined_variable(&name);
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.push(unpack(self.stack[slot].clone()));
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = pack(self.peek(0));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let value = match &*upvalue.lock().unwrap() {
+                        Upvalue::Open(slot) => unpack(self.stack[*slot].clone()),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let value = self.peek(0);
+                    let upvalue = self.frame().closure.upvalues[index].clone();
+                    let mut upvalue = upvalue.lock().unwrap();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack[*slot] = pack(value),
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_string(instruction);
+                    let receiver = self.peek(0);
+                    let value = match receiver.as_instance() {
+                        Some(instance) => Self::instance_property(&receiver, instance, &name),
+                        None => Self::property(&receiver, &name),
+                    };
+                    match value {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        }
+                        None => {
+                            let names = Self::property_names(&receiver);
+                            let hint = self.did_you_mean(&name, names.iter().map(String::as_str));
+                            self.runtime_error(format_args!("Undefined property '{name}'.{hint}"));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_string(instruction);
+                    let value = self.pop();
+                    let receiver = self.pop();
+                    let Some(instance) = receiver.as_instance() else {
+                        self.runtime_error(format_args!("Only instances have fields."));
+                        return InterpretResult::RuntimeError;
+                    };
+                    instance.fields.lock().unwrap().insert(name, value.clone());
+                    self.push(value);
+                }
+                OpCode::GetIndex => {
+                    let index = self.pop();
+                    let receiver = self.pop();
+                    match Self::index(&receiver, &index) {
+                        Ok(value) => self.push(value),
+                        Err(message) => {
+                            self.runtime_error(format_args!("{message}"));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let frame = self.frame();
+                    let doc = frame.closure.function.chunk.get_doc(frame.ip - 1);
+                    let doc = doc.map(String::from);
+                    let name = self.read_string(instruction);
+                    let class = Value::from_class(name, doc);
+                    self.heap.track(&class);
+                    self.push(class);
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_string(instruction);
+                    let method = self.pop();
+                    let class = self.peek(0);
+                    match (class.as_class(), method.as_closure()) {
+                        (Some(class), Some(method)) => {
+                            class.methods.lock().unwrap().insert(name, method.clone());
+                        }
+                        _ => {
+                            self.runtime_error(format_args!("Can only add methods to classes."));
+                            return InterpretResult::RuntimeError;
+                        }
+                    }
+                }
+                OpCode::Pop => {
+                    self.pop();
+                }
+                OpCode::Dup => self.push(self.peek(0)),
+                OpCode::Swap => {
+                    let top = self.stack.len();
+                    self.stack.swap(top - 1, top - 2);
+                }
+                OpCode::Jump => {
+                    if !self.safepoint() {
+                        return InterpretResult::RuntimeError;
+                    }
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    if !self.safepoint() {
+                        return InterpretResult::RuntimeError;
+                    }
+                    let offset = self.read_short();
+                    if Self::is_falsey(self.peek(0)) {
+                        self.frame_mut().ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    if !self.safepoint() {
+                        return InterpretResult::RuntimeError;
+                    }
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset;
+                }
+                OpCode::Import | OpCode::ImportLong => {
+                    let target = self.read_string(instruction);
+                    if !self.import(&target) {
+                        return InterpretResult::RuntimeError;
+                    }
+                }
+                OpCode::ImportAll => {
+                    let exports = self.pop();
+                    let Some(exports) = exports.as_instance() else {
+                        self.runtime_error(format_args!("Can only import all of a module."));
+                        return InterpretResult::RuntimeError;
+                    };
+                    let fields = exports.fields.lock().unwrap().clone();
+                    self.globals().extend(fields);
+                }
+                OpCode::PushHandler => {
+                    let offset = self.read_short();
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        ip: self.frame().ip + offset,
+                    });
+                }
+                OpCode::PopHandler => {
+                    self.handlers.pop();
+                }
+                OpCode::Throw => {
+                    let exception = self.pop();
+                    if self.handlers.is_empty() {
+                        self.runtime_error(format_args!("{exception}"));
+                    } else {
+                        self.thrown = Some(exception);
+                    }
+                    return InterpretResult::RuntimeError;
+                }
                 OpCode::Nil => self.push(Value::Nil),
                 OpCode::True => self.push(Value::Bool(true)),
                 OpCode::False => self.push(Value::Bool(false)),
                 OpCode::Equal => {
                     let b = self.pop();
                     let a = self.pop();
-                    self.push(Value::Bool(a == b));
+                    match self.call_equals(&a, &b) {
+                        Some(true) => (),
+                        Some(false) => return InterpretResult::RuntimeError,
+                        None => self.push(Value::Bool(a == b)),
+                    }
                 }
                 OpCode::Greater => match self.binary_op(BinaryOp::GreaterThan) {
                     InterpretResult::CompileError => return InterpretResult::CompileError,
@@ -116,27 +843,180 @@ impl<'a> VM<'a> {
         }
     }
 
+    #[inline(always)]
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    #[inline(always)]
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
     #[inline(always)]
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
         byte
     }
 
     #[inline(always)]
-    fn read_constant(&mut self) -> &Value {
-        let index = self.read_byte();
-        &self.chunk.constants[index as usize]
+    fn read_short(&mut self) -> usize {
+        let frame = self.frame_mut();
+        let short = frame.closure.function.chunk.read_short(frame.ip);
+        frame.ip += 2;
+        short
+    }
+
+    #[inline(always)]
+    fn read_constant(&mut self, instruction: OpCode) -> &Value {
+        let frame = self.frame_mut();
+        let chunk = &frame.closure.function.chunk;
+        let index = chunk.constant_index(instruction, frame.ip - 1).unwrap();
+        frame.ip += instruction.operand_len();
+        &chunk.constants[index]
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
+        if let Some(closure) = callee.as_closure() {
+            return self.call(closure.clone(), arg_count);
+        }
+        if let Some(native) = callee.as_native() {
+            return self.call_native(native, arg_count);
+        }
+        // Calling a class creates an instance, which replaces the class on the
+        // stack so that it's `this` when the arguments are passed on to init
+        if let Some(class) = callee.as_class() {
+            let slot = self.stack.len() - arg_count - 1;
+            let instance = Value::from_instance(class.clone());
+            self.heap.track(&instance);
+            self.stack[slot] = pack(instance);
+            let initializer = class.methods.lock().unwrap().get("init").cloned();
+            return match initializer {
+                Some(initializer) => self.call(initializer, arg_count),
+                None if arg_count != 0 => {
+                    self.runtime_error(format_args!("Expected 0 arguments but got {arg_count}."));
+                    false
+                }
+                None => true,
+            };
+        }
+        // The receiver takes the method's place in slot 0, as `this`
+        if let Some(bound) = callee.as_bound_method() {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = pack(bound.receiver.clone());
+            return self.call(bound.method.clone(), arg_count);
+        }
+        self.runtime_error(format_args!("Can only call functions and classes."));
+        false
+    }
+
+    // Natives run to completion without a frame of their own, so their result
+    // replaces the callee and arguments straight away
+    fn call_native(&mut self, native: &Native, arg_count: usize) -> bool {
+        let mut args = values(&self.stack[self.stack.len() - arg_count..]);
+        if let Some(receiver) = &native.receiver {
+            args.to_mut().insert(0, receiver.clone());
+        }
+        match (native.function)(&args) {
+            Ok(result) => {
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.push(result);
+                true
+            }
+            Err(message) => {
+                self.native_error(&native.name, &message);
+                false
+            }
+        }
+    }
+
+    // The callee and its arguments are already on the stack, where they
+    // become the new frame's first slots
+    fn call(&mut self, closure: Arc<Closure>, arg_count: usize) -> bool {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            self.runtime_error(format_args!(
+                "Expected {arity} arguments but got {arg_count}."
+            ));
+            return false;
+        }
+        if self.frames.len() >= self.config.max_frames {
+            self.runtime_error(format_args!("Stack overflow."));
+            return false;
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+            importing: None,
+            then: None,
+        });
+        true
+    }
+
+    // Shares the upvalue for a slot between every closure that captures it
+    fn capture_upvalue(&mut self, slot: usize) -> Arc<Mutex<Upvalue>> {
+        let index = self.open_upvalues.partition_point(|(s, _)| *s < slot);
+        if let Some((s, upvalue)) = self.open_upvalues.get(index) {
+            if *s == slot {
+                return upvalue.clone();
+            }
+        }
+        let upvalue = Arc::new(Mutex::new(Upvalue::Open(slot)));
+        self.heap.track_upvalue(&upvalue);
+        self.open_upvalues.insert(index, (slot, upvalue.clone()));
+        upvalue
+    }
+
+    // Moves the variables in `last` and every slot above it into their
+    // upvalues, since the stack is about to lose them
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some((slot, _)) = self.open_upvalues.last() {
+            if *slot < last {
+                break;
+            }
+            let (slot, upvalue) = self.open_upvalues.pop().unwrap();
+            *upvalue.lock().unwrap() = Upvalue::Closed(unpack(self.stack[slot].clone()));
+        }
+    }
+
+    // Jumps are where the VM checks whether it should stop, which is enough to
+    // interrupt any loop. Returns false after reporting a timeout.
+    fn safepoint(&mut self) -> bool {
+        if self.interrupted.load(Ordering::Relaxed) {
+            // Otherwise a loop that catches everything would never stop
+            self.handlers.clear();
+            self.runtime_error(format_args!("Execution timed out."));
+            return false;
+        }
+        true
+    }
+
+    // Verification checks that names are string constants
+    fn read_string(&mut self, instruction: OpCode) -> String {
+        self.read_constant(instruction)
+            .as_str()
+            .unwrap()
+            .to_string()
     }
 
     #[inline(always)]
     fn binary_op(&mut self, op: BinaryOp) -> InterpretResult {
-        match (self.peek(0), self.peek(1)) {
-            (a @ _, b @ _) if a.is_string() && b.is_string() => {
+        match (op, self.peek(1), self.peek(0)) {
+            (BinaryOp::Add, a, b) if a.is_string() && b.is_string() => {
                 self.concatenate();
                 InterpretResult::Ok
             }
-            (Value::Number(a), Value::Number(b)) => {
+            (BinaryOp::Multiply, s, Value::Number(n))
+            | (BinaryOp::Multiply, Value::Number(n), s)
+                if s.is_string() =>
+            {
+                self.repeat(s.as_str().unwrap(), n)
+            }
+            (op, Value::Number(a), Value::Number(b)) => {
                 self.pop();
                 self.pop();
                 let c = match op {
@@ -150,48 +1030,413 @@ impl<'a> VM<'a> {
                 self.push(c);
                 InterpretResult::Ok
             }
+            (BinaryOp::Add, a, b) => {
+                // An instance added to a string is converted with toString()
+                let converted = match (a.is_string(), b.is_string()) {
+                    (true, false) => self.call_to_string(&b, Then::Concatenate { left: false }),
+                    (false, true) => self.call_to_string(&a, Then::Concatenate { left: true }),
+                    _ => None,
+                };
+                match converted {
+                    Some(true) => InterpretResult::Ok,
+                    Some(false) => InterpretResult::RuntimeError,
+                    None => {
+                        self.runtime_error(format_args!(
+                            "Operands must be two numbers or two strings."
+                        ));
+                        InterpretResult::RuntimeError
+                    }
+                }
+            }
+            (BinaryOp::Multiply, _, _) => {
+                self.runtime_error(format_args!(
+                    "Operands must be numbers, or a string and a number."
+                ));
+                InterpretResult::RuntimeError
+            }
             _ => {
-                self.runtime_error(format_args!("Operands must be two numbers or two strings."));
+                self.runtime_error(format_args!("Operands must be numbers."));
                 InterpretResult::RuntimeError
             }
         }
     }
 
     fn push(&mut self, value: Value) {
-        self.stack[self.stack_top] = value;
-        self.stack_top += 1;
+        self.stack.push(pack(value));
     }
 
     fn pop(&mut self) -> Value {
-        let ret = mem::take(&mut self.stack[self.stack_top]);
-        self.stack_top -= 1;
-        ret
+        unpack(self.stack.pop().unwrap())
     }
 
     fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack_top - 1 - distance].clone()
+        unpack(self.stack[self.stack.len() - 1 - distance].clone())
+    }
+
+    // The globals of the module the running code is from
+    fn globals(&mut self) -> &mut HashMap<String, Value> {
+        let module = self.frame().closure.module;
+        &mut self.modules[module].globals
+    }
+
+    // Runs the module at `target` unless it already has been, then pushes
+    // its exports. A module's path is relative to the one importing it.
+    // Returns false after reporting an error.
+    fn import(&mut self, target: &str) -> bool {
+        let importer = &self.modules[self.frame().closure.module];
+        let dir = importer
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+        let Ok(path) = fs::canonicalize(dir.join(target)) else {
+            self.runtime_error(format_args!("Could not find module \"{target}\"."));
+            return false;
+        };
+        if let Some(&module) = self.registry.get(&path) {
+            let Some(exports) = self.modules[module].exports.clone() else {
+                self.runtime_error(format_args!("Circular import of \"{target}\"."));
+                return false;
+            };
+            self.push(exports);
+            return true;
+        }
+
+        let Ok(source) = fs::read_to_string(&path) else {
+            self.runtime_error(format_args!("Could not read module \"{target}\"."));
+            return false;
+        };
+        let source_map = SourceMap::for_file(&path.display().to_string());
+        let Ok(program) = self.compile_file(&source, Some(source_map), Some(&path)) else {
+            self.runtime_error(format_args!("Could not compile module \"{target}\"."));
+            return false;
+        };
+
+        let module = self.modules.len();
+        self.modules.push(Module {
+            globals: self.natives.clone(),
+            path: Some(path.clone()),
+            source_map: program.source_map.clone(),
+            source: program.source.clone(),
+            exports: None,
+        });
+        self.registry.insert(path, module);
+        let script = Arc::new(Closure {
+            function: program.script.clone(),
+            upvalues: vec![],
+            module,
+        });
+        self.push(Value::from_closure(script.clone()));
+        if !self.call(script, 0) {
+            return false;
+        }
+        self.frame_mut().importing = Some(module);
+        true
+    }
+
+    // Makes a finished module's exports: an object with a field for each of
+    // its globals, other than the natives it started with
+    fn export(&mut self, module: usize) -> Value {
+        let class = Value::from_class("module".to_string(), None);
+        let exports = Value::from_instance(class.as_class().unwrap().clone());
+        let fields = self.modules[module]
+            .globals
+            .iter()
+            .filter(|(name, value)| self.natives.get(*name) != Some(value))
+            .map(|(name, value)| (name.clone(), value.clone()));
+        exports
+            .as_instance()
+            .unwrap()
+            .fields
+            .lock()
+            .unwrap()
+            .extend(fields);
+        self.heap.track(&exports);
+        self.modules[module].exports = Some(exports.clone());
+        exports
+    }
+
+    // Modules whose top level is run by frames that are being discarded will
+    // never finish, so they're forgotten and importing them again starts over
+    fn abandon_imports(&mut self, frames: usize) {
+        for frame in &self.frames[frames..] {
+            if let Some(module) = frame.importing {
+                let path = self.modules[module].path.as_ref().unwrap();
+                self.registry.remove(path);
+            }
+        }
     }
 
     fn reset_stack(&mut self) {
-        self.stack = array::from_fn(|_| Value::default());
-        self.stack_top = 0;
+        self.abandon_imports(0);
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.handlers.clear();
     }
 
+    // Resumes at the innermost handler with `exception` on the stack in place
+    // of whatever its try block left there
+    fn unwind(&mut self, exception: Value) {
+        let handler = self.handlers.pop().unwrap();
+        self.abandon_imports(handler.frames);
+        self.frames.truncate(handler.frames);
+        self.close_upvalues(handler.stack);
+        self.stack.truncate(handler.stack);
+        self.push(exception);
+        self.frame_mut().ip = handler.ip;
+    }
+
+    /// Takes the runtime error that ended the last run, which has also been
+    /// reported on stderr.
+    pub fn take_error(&mut self) -> Option<RuntimeError> {
+        self.error.take()
+    }
+
+    // Inside a try block, errors are thrown as their message instead of being
+    // reported. Like clox, failures to write output are ignored.
     fn runtime_error(&mut self, args: fmt::Arguments) {
-        eprintln!("{args}");
+        if !self.handlers.is_empty() {
+            self.thrown = Some(Value::from_string(args.to_string()));
+            return;
+        }
+        self.stack_trace(args.to_string(), None);
+    }
 
-        let instruction = self.ip - 1;
-        let line = self.chunk.lines[instruction];
-        eprintln!("[line {line}] in script");
+    // Natives have no frame of their own, so the trace gets one standing in
+    // for the native at the line it was called from
+    fn native_error(&mut self, native: &str, message: &str) {
+        if !self.handlers.is_empty() {
+            self.thrown = Some(Value::from_string(message.to_string()));
+            return;
+        }
+        self.stack_trace(message.to_string(), Some(native));
+    }
+
+    // Reports the error and the calls that led to it, then abandons them
+    fn stack_trace(&mut self, message: String, native: Option<&str>) {
+        let mut trace = vec![];
+        // Innermost call first. Chunks loaded from stripped bytecode have no
+        // line information.
+        for frame in self.frames.iter().rev() {
+            let function = &frame.closure.function;
+            let offset = frame.ip.checked_sub(1);
+            let line = offset.and_then(|i| function.chunk.get_line(i));
+            let span = offset.and_then(|i| function.chunk.get_span(i));
+            // Lines of the expanded source are reported in their own files
+            let (file, line) = match (line, &self.modules[frame.closure.module].source_map) {
+                (Some(line), Some(source_map)) => {
+                    let (file, line) = source_map.locate(line);
+                    (Some(file.to_string()), Some(line))
+                }
+                (line, _) => (None, line),
+            };
+            if let (Some(native), true) = (native, trace.is_empty()) {
+                trace.push(TraceFrame {
+                    function: format!("[native fn {native}]"),
+                    line,
+                    file: file.clone(),
+                    span: span.clone(),
+                });
+            }
+            let function = match &function.name {
+                Some(name) => format!("{name}()"),
+                None => "script".to_string(),
+            };
+            trace.push(TraceFrame {
+                function,
+                line,
+                file,
+                span,
+            });
+        }
+
+        // The code that failed, in the innermost call
+        let snippet = match (self.frames.last(), trace.first()) {
+            (
+                Some(frame),
+                Some(TraceFrame {
+                    span: Some(span), ..
+                }),
+            ) => {
+                let source = self.modules[frame.closure.module].source.as_deref();
+                source.and_then(|source| source::underline(source, span.clone()))
+            }
+            _ => None,
+        };
+        let snippet = snippet
+            .filter(|_| !self.config.clox_compat)
+            .map(|(line, carets)| format!("{line}\n{carets}"));
+        let error = RuntimeError {
+            message,
+            snippet,
+            trace,
+        };
+        let _ = write!(self.stderr, "{error}");
+        self.error = Some(error);
         self.reset_stack();
     }
 
+    fn print(&mut self, value: &Value) {
+        let _ = match value {
+            Value::Number(n) => {
+                let format = if self.config.clox_compat {
+                    number::Format::Printf
+                } else {
+                    self.config.number_format
+                };
+                writeln!(self.stdout, "{}", number::format(*n, format))
+            }
+            _ => writeln!(self.stdout, "{value}"),
+        };
+    }
+
+    // Fields shadow methods, which are bound to the instance they're
+    // accessed on so that calling them later still has the right `this`
+    fn instance_property(receiver: &Value, instance: &Instance, name: &str) -> Option<Value> {
+        if let Some(value) = instance.fields.lock().unwrap().get(name) {
+            return Some(value.clone());
+        }
+        let method = instance.class.methods.lock().unwrap().get(name)?.clone();
+        Some(Value::from_bound_method(receiver.clone(), method))
+    }
+
+    // Built-in properties shared by values of a type
+    fn property(receiver: &Value, name: &str) -> Option<Value> {
+        if let (Some(bytes), "length") = (receiver.as_bytes(), name) {
+            return Some(Value::Number(bytes.len() as f64));
+        }
+        match (receiver.as_str(), name) {
+            (Some(s), "length") => Some(Value::Number(s.chars().count() as f64)),
+            (Some(_), _) => {
+                let &(name, method) = natives::STRING_METHODS.iter().find(|(n, _)| *n == name)?;
+                Some(Value::from_native_method(receiver.clone(), name, method))
+            }
+            _ => None,
+        }
+    }
+
+    fn index(receiver: &Value, index: &Value) -> Result<Value, &'static str> {
+        let Some(bytes) = receiver.as_bytes() else {
+            return Err("Only bytes can be indexed.");
+        };
+        let index = match index {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => *n as usize,
+            _ => return Err("Index must be a non-negative integer."),
+        };
+        match bytes.get(index) {
+            Some(byte) => Ok(Value::Number(*byte as f64)),
+            None => Err("Index out of range."),
+        }
+    }
+
+    fn property_names(receiver: &Value) -> Vec<String> {
+        if let Some(instance) = receiver.as_instance() {
+            let fields = instance.fields.lock().unwrap();
+            let methods = instance.class.methods.lock().unwrap();
+            return fields.keys().chain(methods.keys()).cloned().collect();
+        }
+        let methods = natives::STRING_METHODS
+            .iter()
+            .map(|(name, _)| name.to_string());
+        match receiver {
+            _ if receiver.is_string() => iter::once("length".to_string()).chain(methods).collect(),
+            _ if receiver.as_bytes().is_some() => vec!["length".to_string()],
+            _ => vec![],
+        }
+    }
+
+    fn undefined_variable(&mut self, name: &str) {
+        let module = self.frame().closure.module;
+        let globals = self.modules[module].globals.keys().map(String::as_str);
+        let hint = self.did_you_mean(name, globals);
+        self.runtime_error(format_args!("Undefined variable '{name}'.{hint}"));
+    }
+
+    // clox's messages never have hints, so they're left off in compat mode
+    fn did_you_mean<'n>(&self, name: &str, candidates: impl Iterator<Item = &'n str>) -> String {
+        match suggest::closest(name, candidates) {
+            Some(closest) if !self.config.clox_compat => format!(" Did you mean '{closest}'?"),
+            _ => String::new(),
+        }
+    }
+
     fn is_falsey(value: Value) -> bool {
-        match value {
-            Value::Nil => true,
-            Value::Bool(false) => true,
-            _ => false,
+        matches!(value, Value::Nil | Value::Bool(false))
+    }
+
+    // Calls the toString() method of an instance that has one, returning
+    // whether that started without an error. `then` uses the string once it
+    // returns. In clox compat mode instances print as clox prints them.
+    fn call_to_string(&mut self, value: &Value, then: Then) -> Option<bool> {
+        if self.config.clox_compat {
+            return None;
         }
+        let instance = value.as_instance()?;
+        let method = instance
+            .class
+            .methods
+            .lock()
+            .unwrap()
+            .get("toString")?
+            .clone();
+        let depth = self
+            .frames
+            .iter()
+            .filter(|f| matches!(f.then, Some(Then::Print | Then::Concatenate { .. })))
+            .count();
+        if depth >= MAX_TO_STRING_DEPTH {
+            self.runtime_error(format_args!("toString() calls are nested too deeply."));
+            return Some(false);
+        }
+        // A copy as the receiver, leaving the operands as they are
+        self.push(value.clone());
+        if !self.call(method, 0) {
+            return Some(false);
+        }
+        self.frame_mut().then = Some(then);
+        Some(true)
+    }
+
+    // Calls `a.equals(b)` when both are instances and a's class has that
+    // method, like `call_to_string`. Otherwise instances are only equal to
+    // themselves.
+    fn call_equals(&mut self, a: &Value, b: &Value) -> Option<bool> {
+        if self.config.clox_compat || b.as_instance().is_none() {
+            return None;
+        }
+        let method = a
+            .as_instance()?
+            .class
+            .methods
+            .lock()
+            .unwrap()
+            .get("equals")?
+            .clone();
+        self.push(a.clone());
+        self.push(b.clone());
+        if !self.call(method, 1) {
+            return Some(false);
+        }
+        self.frame_mut().then = Some(Then::Equal);
+        Some(true)
+    }
+
+    fn finish(&mut self, then: Then, result: Value) -> bool {
+        match then {
+            Then::Print | Then::Concatenate { .. } if !result.is_string() => {
+                self.runtime_error(format_args!("toString() must return a string."));
+                return false;
+            }
+            Then::Print => self.print(&result),
+            Then::Concatenate { left } => {
+                let operand = self.stack.len() - if left { 2 } else { 1 };
+                self.stack[operand] = pack(result);
+                self.concatenate();
+            }
+            Then::Equal => self.push(Value::Bool(!Self::is_falsey(result))),
+        }
+        true
     }
 
     fn concatenate(&mut self) {
@@ -203,14 +1448,137 @@ impl<'a> VM<'a> {
         concatenated.push_str(b);
         self.push(Value::from_string(concatenated));
     }
+
+    fn repeat(&mut self, s: &str, count: f64) -> InterpretResult {
+        if count < 0.0 || count.fract() != 0.0 {
+            self.runtime_error(format_args!(
+                "String repetition count must be a non-negative integer."
+            ));
+            return InterpretResult::RuntimeError;
+        }
+        let len = (count <= usize::MAX as f64)
+            .then(|| s.len().checked_mul(count as usize))
+            .flatten();
+        match len {
+            Some(len) if len <= MAX_REPEAT_LEN => {
+                let repeated = s.repeat(count as usize);
+                self.pop();
+                self.pop();
+                self.push(Value::from_string(repeated));
+                InterpretResult::Ok
+            }
+            _ => {
+                self.runtime_error(format_args!("String repetition result is too long."));
+                InterpretResult::RuntimeError
+            }
+        }
+    }
+}
+
+// Nothing outside the VM can hold its objects, so whatever it's tracking can
+// be freed along with it
+impl Drop for VM<'_> {
+    fn drop(&mut self) {
+        self.heap.free_all();
+    }
+}
+
+/// Compiles and runs `source` on a new VM, returning its errors rather than
+/// reporting them. The program's output still goes to stdout.
+pub fn interpret(
+    source: &str,
+    source_map: Option<SourceMap>,
+    config: Config,
+) -> Result<(), InterpretError> {
+    interpret_with_diagnostics(source, source_map, config).0
+}
+
+/// Like `interpret`, but also returns the errors and warnings from compiling
+/// `source`, which are otherwise only returned when it fails to compile.
+pub fn interpret_with_diagnostics(
+    source: &str,
+    source_map: Option<SourceMap>,
+    config: Config,
+) -> (Result<(), InterpretError>, Vec<compiler::Diagnostic>) {
+    let (program, diagnostics) = compile(source, source_map, config);
+    let Ok(program) = program else {
+        return (
+            Err(InterpretError::Compile(diagnostics.clone())),
+            diagnostics,
+        );
+    };
+    let mut vm = VM::with_output(config, io::stdout(), io::sink());
+    let result = match vm.run_program(&program) {
+        InterpretResult::RuntimeError => Err(InterpretError::Runtime(vm.take_error().unwrap())),
+        _ => Ok(()),
+    };
+    (result, diagnostics)
+}
+
+fn compile(
+    source: &str,
+    source_map: Option<SourceMap>,
+    config: Config,
+) -> (anyhow::Result<Program>, Vec<compiler::Diagnostic>) {
+    if config.clox_compat {
+        compiler::compile_clox(source, source_map)
+    } else {
+        compiler::compile_with_diagnostics(source, source_map)
+    }
+}
+
+pub fn interpret_program(program: &Program, config: Config) -> InterpretResult {
+    VM::new(config).run_program(program)
 }
 
-pub fn interpret(source: &str) -> InterpretResult {
-    match compiler::compile(source) {
-        Err(_) => return InterpretResult::CompileError,
-        Ok(chunk) => {
-            let mut vm = VM::new(&chunk);
-            vm.run()
+impl From<Result<(), InterpretError>> for InterpretResult {
+    fn from(result: Result<(), InterpretError>) -> InterpretResult {
+        match result {
+            Ok(()) => InterpretResult::Ok,
+            Err(InterpretError::Compile(_)) => InterpretResult::CompileError,
+            Err(InterpretError::Runtime(_)) => InterpretResult::RuntimeError,
+        }
+    }
+}
+
+impl Display for InterpretError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            InterpretError::Compile(diagnostics) => {
+                for diagnostic in diagnostics {
+                    writeln!(f, "{diagnostic}")?;
+                }
+                Ok(())
+            }
+            InterpretError::Runtime(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl Error for InterpretError {}
+
+// The message and then a line per call, as runtime errors are reported
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        if let Some(snippet) = &self.snippet {
+            writeln!(f, "{snippet}")?;
+        }
+        for frame in &self.trace {
+            writeln!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for RuntimeError {}
+
+impl Display for TraceFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (self.line, &self.file) {
+            (Some(line), Some(file)) => write!(f, "[line {line} in {file}] in {}", self.function),
+            (Some(line), None) => write!(f, "[line {line}] in {}", self.function),
+            (None, _) => write!(f, "in {}", self.function),
         }
     }
 }
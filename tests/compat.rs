@@ -0,0 +1,192 @@
+// Runs the craftinginterpreters test corpus against rlox and reports how much
+// of it passes. Point RLOX_COMPAT_DIR at the corpus's `test` directory to run
+// it, and set RLOX_COMPAT_MIN to a percentage to fail below that conformance.
+// RLOX_COMPAT_JOBS sets how many tests run at once, at least one, defaulting
+// to one per CPU.
+
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Expectation {
+    output: Vec<String>,
+    errors: Vec<String>,
+    runtime_error: Option<String>,
+}
+
+#[test]
+fn clox_test_suite() {
+    let Ok(dir) = env::var("RLOX_COMPAT_DIR") else {
+        eprintln!("RLOX_COMPAT_DIR is not set, skipping the clox compatibility suite");
+        return;
+    };
+
+    let mut tests = vec![];
+    collect(Path::new(&dir), &mut tests);
+    tests.sort();
+    assert!(!tests.is_empty(), "No .lox tests found in {dir}");
+
+    let jobs = match env::var("RLOX_COMPAT_JOBS") {
+        Ok(jobs) => jobs.parse().expect("RLOX_COMPAT_JOBS must be a number"),
+        Err(_) => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    // With no workers, no test would run and none would fail
+    assert!(jobs > 0, "RLOX_COMPAT_JOBS must be at least 1");
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(vec![]);
+    thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| {
+                while let Some(test) = tests.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if let Err(reason) = run(test) {
+                        let failure = format!("{}: {reason}", test.display());
+                        failures.lock().unwrap().push(failure);
+                    }
+                }
+            });
+        }
+    });
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort();
+
+    for failure in &failures {
+        eprintln!("FAIL {failure}");
+    }
+    let passed = tests.len() - failures.len();
+    let conformance = passed as f64 * 100.0 / tests.len() as f64;
+    eprintln!(
+        "clox conformance: {passed}/{} tests ({conformance:.1}%)",
+        tests.len()
+    );
+
+    if let Ok(min) = env::var("RLOX_COMPAT_MIN") {
+        let min: f64 = min.parse().expect("RLOX_COMPAT_MIN must be a number");
+        assert!(
+            conformance >= min,
+            "Conformance {conformance:.1}% is below {min}%"
+        );
+    }
+}
+
+fn collect(dir: &Path, tests: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect(&path, tests);
+        } else if path.extension().is_some_and(|e| e == "lox") {
+            tests.push(path);
+        }
+    }
+}
+
+fn parse(source: &str) -> Expectation {
+    let mut expectation = Expectation {
+        output: vec![],
+        errors: vec![],
+        runtime_error: None,
+    };
+    for (i, line) in source.lines().enumerate() {
+        if let Some((_, output)) = line.split_once("// expect: ") {
+            expectation.output.push(output.to_string());
+        } else if let Some((_, error)) = line.split_once("// expect runtime error: ") {
+            expectation.runtime_error = Some(error.to_string());
+        } else if let Some((_, error)) = line.split_once("// [line ") {
+            expectation.errors.push(format!("[line {error}"));
+        } else if let Some((_, error)) = line.split_once("// Error") {
+            expectation
+                .errors
+                .push(format!("[line {}] Error{error}", i + 1));
+        }
+    }
+    expectation
+}
+
+fn run(test: &Path) -> Result<(), String> {
+    let source = fs::read_to_string(test).map_err(|e| e.to_string())?;
+    let expectation = parse(&source);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+        .arg("--clox-compat")
+        .arg(test)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    // The pipes are drained while waiting, since a test that fills one would
+    // otherwise block until it timed out
+    let stdout = drain(child.stdout.take().unwrap());
+    let stderr = drain(child.stderr.take().unwrap());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+            break status.code();
+        }
+        if start.elapsed() > TIMEOUT {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err("timed out".to_string());
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let stdout = stdout.join().unwrap();
+    let stderr = stderr.join().unwrap();
+    let stdout = String::from_utf8_lossy(&stdout);
+    let stderr = String::from_utf8_lossy(&stderr);
+
+    if !expectation.errors.is_empty() {
+        if status != Some(65) {
+            return Err(format!("expected a compile error, exited with {status:?}"));
+        }
+        let actual: Vec<&str> = stderr.lines().collect();
+        if actual != expectation.errors {
+            return Err(format!(
+                "expected errors {:?}, got {actual:?}",
+                expectation.errors
+            ));
+        }
+        return Ok(());
+    }
+
+    let actual: Vec<&str> = stdout.lines().collect();
+    if actual != expectation.output {
+        return Err(format!(
+            "expected output {:?}, got {actual:?}",
+            expectation.output
+        ));
+    }
+
+    match expectation.runtime_error {
+        Some(error) => {
+            if status != Some(70) {
+                return Err(format!("expected a runtime error, exited with {status:?}"));
+            }
+            if stderr.lines().next() != Some(error.as_str()) {
+                return Err(format!("expected runtime error {error:?}, got {stderr:?}"));
+            }
+        }
+        None if status != Some(0) => {
+            return Err(format!("exited with {status:?}: {stderr}"));
+        }
+        None => (),
+    }
+    Ok(())
+}
+
+fn drain(mut pipe: impl Read + Send + 'static) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = vec![];
+        let _ = pipe.read_to_end(&mut bytes);
+        bytes
+    })
+}
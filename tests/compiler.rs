@@ -0,0 +1,884 @@
+use rlox::{
+    bytecode,
+    chunk::{self, ChunkBuilder, OpCode},
+    compiler::{self, Severity},
+    doc,
+    program::{Program, Signature, Symbol},
+    value::Value,
+};
+
+fn assert_compiles_to(source: &str, listing: &str) {
+    let program = compiler::compile(source, None).unwrap();
+    let actual = program.chunk();
+    let expected = chunk::assemble(listing).unwrap();
+    // Listings have no source for the code to have come from, so its spans
+    // aren't compared
+    assert!(
+        actual.code == expected.code && actual.constants == expected.constants,
+        "{source} compiled differently:\n{}",
+        chunk::diff(&expected, actual)
+    );
+}
+
+#[test]
+fn arithmetic_precedence() {
+    assert_compiles_to(
+        "print a + b * c;",
+        "
+        GetGlobal '\"a\"'
+        GetGlobal '\"b\"'
+        GetGlobal '\"c\"'
+        Multiply
+        Add
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn grouping_and_unary() {
+    assert_compiles_to(
+        "print -(a - b) / c;",
+        "
+        GetGlobal '\"a\"'
+        GetGlobal '\"b\"'
+        Subtract
+        Negate
+        GetGlobal '\"c\"'
+        Divide
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn comparisons_desugar_to_negations() {
+    assert_compiles_to(
+        "print a >= b != !c;",
+        "
+        GetGlobal '\"a\"'
+        GetGlobal '\"b\"'
+        Less
+        Not
+        GetGlobal '\"c\"'
+        Not
+        Equal
+        Not
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn literals() {
+    assert_compiles_to(
+        "print a == nil != false;",
+        "
+        GetGlobal '\"a\"'
+        Nil
+        Equal
+        False
+        Equal
+        Not
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn if_expression() {
+    assert_compiles_to(
+        "print if (a < b) 3 else 4;",
+        "
+        GetGlobal '\"a\"'
+        GetGlobal '\"b\"'
+        Less
+        JumpIfFalse -> else
+        Pop
+        Constant '3'
+        Jump -> end
+        else:
+        Pop
+        Constant '4'
+        end:
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn conditional_operator_is_right_associative() {
+    assert_compiles_to(
+        "print nil ? 1 : true ? 2 : 3;",
+        "
+        Nil
+        JumpIfFalse -> else
+        Pop
+        Constant '1'
+        Jump -> end
+        else:
+        Pop
+        True
+        JumpIfFalse -> inner_else
+        Pop
+        Constant '2'
+        Jump -> end
+        inner_else:
+        Pop
+        Constant '3'
+        end:
+        Print
+        Nil
+        Return
+        ",
+    );
+    assert!(compiler::compile("print 1 ? 2;", None).is_err());
+}
+
+#[test]
+fn constant_if_conditions_keep_only_the_taken_branch() {
+    assert_compiles_to(
+        "print if (true) 1 else 2;",
+        "Constant '1'\nPrint\nNil\nReturn",
+    );
+    assert_compiles_to(
+        "print if (nil) 1 else 2;",
+        "Constant '2'\nPrint\nNil\nReturn",
+    );
+}
+
+#[test]
+fn literal_operands_are_folded() {
+    assert_compiles_to("print 2 * 3 + 4;", "Constant '10'\nPrint\nNil\nReturn");
+    assert_compiles_to(
+        "print -(1 - 2) / 4 >= 0.25 == !nil;",
+        "True\nPrint\nNil\nReturn",
+    );
+    assert_compiles_to(
+        "print \"a\" + \"b\";",
+        "Constant '\"ab\"'\nPrint\nNil\nReturn",
+    );
+    // Operands that would be a runtime error are left for the VM to report
+    assert_compiles_to(
+        "print 1 + \"a\";",
+        "Constant '1'\nConstant '\"a\"'\nAdd\nPrint\nNil\nReturn",
+    );
+    assert_compiles_to(
+        "print a + 1 + 2;",
+        "GetGlobal '\"a\"'\nConstant '1'\nAdd\nConstant '2'\nAdd\nPrint\nNil\nReturn",
+    );
+}
+
+#[test]
+fn statements_compile_in_order() {
+    assert_compiles_to(
+        "1; print 2;",
+        "
+        Constant '1'
+        Pop
+        Constant '2'
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn if_statement() {
+    assert_compiles_to(
+        "if (a == nil) print 1; else print 2;",
+        "
+        GetGlobal '\"a\"'
+        Nil
+        Equal
+        JumpIfFalse -> else
+        Pop
+        Constant '1'
+        Print
+        Jump -> end
+        else:
+        Pop
+        Constant '2'
+        Print
+        end:
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn while_loop() {
+    assert_compiles_to(
+        "while (a) print 1;",
+        "
+        start:
+        GetGlobal '\"a\"'
+        JumpIfFalse -> end
+        Pop
+        Constant '1'
+        Print
+        Loop -> start
+        end:
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn for_loop() {
+    assert_compiles_to(
+        "for (var i = nil; i; i = nil) print i;",
+        "
+        Nil
+        condition:
+        GetLocal 1
+        JumpIfFalse -> exit
+        Pop
+        Jump -> body
+        increment:
+        Nil
+        SetLocal 1
+        Pop
+        Loop -> condition
+        body:
+        GetLocal 1
+        Print
+        Loop -> increment
+        exit:
+        Pop
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn logical_operators_short_circuit() {
+    assert_compiles_to(
+        "print nil and true or false;",
+        "
+        Nil
+        JumpIfFalse -> and_end
+        Pop
+        True
+        and_end:
+        JumpIfFalse -> or_else
+        Jump -> or_end
+        or_else:
+        Pop
+        False
+        or_end:
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn string_literals_drop_their_quotes() {
+    assert_compiles_to(
+        "print \"a\" + b;",
+        "
+        Constant '\"a\"'
+        GetGlobal '\"b\"'
+        Add
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn global_variables() {
+    assert_compiles_to(
+        "var a; a = true; print a;",
+        "
+        Nil
+        DefineGlobal '\"a\"'
+        True
+        SetGlobal '\"a\"'
+        Pop
+        GetGlobal '\"a\"'
+        Print
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn assignment_is_right_associative() {
+    assert_compiles_to(
+        "{ var a; var b; a = b = nil; }",
+        "
+        Nil
+        Nil
+        Nil
+        SetLocal 2
+        SetLocal 1
+        Pop
+        Pop
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn only_variables_and_properties_can_be_assigned() {
+    assert!(compiler::compile("var a; (a) = 3;", None).is_err());
+    assert!(compiler::compile("var a; var b; a + b = 3;", None).is_err());
+}
+
+#[test]
+fn locals_resolve_to_stack_slots() {
+    assert_compiles_to(
+        "{ var a = 1; { var b = a; b = nil; } }",
+        "
+        Constant '1'
+        GetLocal 1
+        Nil
+        SetLocal 2
+        Pop
+        Pop
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn property_assignment() {
+    assert_compiles_to(
+        "a.b = nil;",
+        "
+        GetGlobal '\"a\"'
+        Nil
+        SetProperty '\"b\"'
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn class_declarations_load_the_class_to_add_methods() {
+    assert_compiles_to(
+        "class A {}",
+        "
+        Class '\"A\"'
+        DefineGlobal 0 '\"A\"'
+        GetGlobal '\"A\"'
+        Pop
+        Nil
+        Return
+        ",
+    );
+}
+
+#[test]
+fn diff_marks_changed_instructions() {
+    let expected = chunk::assemble("Constant '1'\nNegate\nReturn").unwrap();
+    let actual = chunk::assemble("Constant '1'\nNot\nReturn").unwrap();
+    assert_eq!(
+        chunk::diff(&expected, &actual),
+        "  Constant    0 '1'\n- Negate\n+ Not\n  Return\n"
+    );
+    assert_eq!(chunk::diff(&expected, &expected), "");
+}
+
+#[test]
+fn return_is_only_allowed_in_functions() {
+    assert!(compiler::compile("return 1;", None).is_err());
+    assert!(compiler::compile("fun f() { return 1; }", None).is_ok());
+}
+
+#[test]
+fn break_and_continue_pop_locals_declared_in_the_loop() {
+    assert_compiles_to(
+        "while (a) { var b; if (b) break; continue; }",
+        "
+        start:
+        GetGlobal '\"a\"'
+        JumpIfFalse -> exit
+        Pop
+        Nil
+        GetLocal 1
+        JumpIfFalse -> else
+        Pop
+        Pop
+        Jump -> end
+        Jump -> endif
+        else:
+        Pop
+        endif:
+        Pop
+        Loop -> start
+        Pop
+        Loop -> start
+        exit:
+        Pop
+        end:
+        Nil
+        Return
+        ",
+    );
+    assert!(compiler::compile("break;", None).is_err());
+    assert!(compiler::compile("while (true) { fun f() { continue; } }", None).is_err());
+    let error = |source| {
+        let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+        assert!(program.is_err());
+        diagnostics[0].to_string()
+    };
+    assert_eq!(
+        error("a: while (true) { fun f() { b: for (;;) break a; } }"),
+        "[line 1] Error at 'a': Undefined loop label 'a'."
+    );
+    assert_eq!(
+        error("a: print 1;"),
+        "[line 1] Error at 'print': Expect a loop after a label."
+    );
+}
+
+#[test]
+fn block_expressions_cant_return_from_the_function_around_them() {
+    let (program, diagnostics) =
+        compiler::compile_with_diagnostics("fun f() { var x = { return 1; }; }", None);
+    assert!(program.is_err());
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 1] Error at 'return': Can't return from a block expression."
+    );
+}
+
+#[test]
+fn chunk_builder_patches_jumps_to_labels() {
+    let mut builder = ChunkBuilder::new();
+    let (start, end) = (builder.new_label(), builder.new_label());
+    builder.bind_label(start);
+    builder.emit(OpCode::True);
+    builder.emit_jump(OpCode::JumpIfFalse, end);
+    builder.set_line(2);
+    builder.emit_constant(Value::Number(1.0)).unwrap();
+    builder.emit(OpCode::Print);
+    builder.emit_jump(OpCode::Loop, start);
+    builder.bind_label(end);
+    builder.emit(OpCode::Return);
+    let chunk = builder.build().unwrap();
+
+    let expected = chunk::assemble(
+        "
+        start:
+        True
+        JumpIfFalse -> end
+        Constant '1'
+        Print
+        Loop -> start
+        end:
+        Return
+        ",
+    )
+    .unwrap();
+    assert_eq!(chunk.code, expected.code);
+    let lines: Vec<_> = (0..chunk.code.len()).map(|i| chunk.get_line(i)).collect();
+    assert_eq!(lines, [1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2].map(Some));
+    assert_eq!(chunk.get_line(chunk.code.len()), None);
+
+    let mut builder = ChunkBuilder::new();
+    let label = builder.new_label();
+    builder.emit_jump(OpCode::Jump, label);
+    assert!(builder.build().is_err());
+}
+
+#[test]
+fn programs_list_the_globals_they_define() {
+    let source = "
+        var a = 1;
+        /// Adds two numbers.
+        fun add(x, y) { return x + y; }
+        /// A point.
+        /// In two dimensions.
+        class Point {
+          init(x, y) { \"Makes a point.\"; this.x = x; }
+          /// Not the sum.
+          sum() { return \"sum\"; }
+        }
+        { class Local { hidden() {} } }
+        var a;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let signature = |name: &str, arity, doc: Option<&str>| Signature {
+        name: name.to_string(),
+        arity,
+        doc: doc.map(String::from),
+    };
+    let symbols = [
+        Symbol::Variable("a".to_string()),
+        Symbol::Function(signature("add", 2, Some("Adds two numbers."))),
+        Symbol::Class {
+            name: "Point".to_string(),
+            methods: vec![
+                signature("init", 2, Some("Makes a point.")),
+                signature("sum", 0, Some("Not the sum.")),
+            ],
+            doc: Some("A point.\nIn two dimensions.".to_string()),
+        },
+    ];
+    assert_eq!(program.symbols(), symbols);
+    assert!(program
+        .constants()
+        .contains(&&Value::from_string("sum".to_string())));
+
+    // Doc comments and docstrings are kept in bytecode, stripped or not
+    let bytes = bytecode::write(program.chunk(), None);
+    let (chunk, _) = bytecode::read(&bytes).unwrap();
+    assert_eq!(Program::new(chunk, None).symbols(), symbols);
+}
+
+#[test]
+fn docs_list_public_declarations_with_their_comments() {
+    let source = "
+        /// Adds two numbers.
+        fun add(x, y) { return x + y; }
+        fun _helper() {}
+        // Not a doc comment.
+        /// A <point>.
+        class Point {
+          sum() { \"Not the sum.\"; }
+        }
+    ";
+    assert_eq!(
+        doc::generate(source, "shapes", doc::Format::Markdown),
+        "# shapes\n\
+         \n## `fun add(x, y)`\n\nAdds two numbers.\n\
+         \n## `class Point`\n\nA <point>.\n\
+         \n### `sum()`\n\nNot the sum.\n"
+    );
+    assert_eq!(
+        doc::generate(source, "shapes", doc::Format::Html),
+        "<h1>shapes</h1>\n\
+         <h2><code>fun add(x, y)</code></h2>\n<p>Adds two numbers.</p>\n\
+         <h2><code>class Point</code></h2>\n<p>A &lt;point&gt;.</p>\n\
+         <h3><code>sum()</code></h3>\n<p>Not the sum.</p>\n"
+    );
+}
+
+#[test]
+fn postfix_increments_leave_the_old_value() {
+    assert_compiles_to(
+        "{ var a; print a++; }",
+        "
+        Nil
+        GetLocal 1
+        Dup
+        Constant '1'
+        Add
+        SetLocal 1
+        Pop
+        Print
+        Pop
+        Nil
+        Return
+        ",
+    );
+    assert!(compiler::compile("++1;", None).is_err());
+    assert!(compiler::compile("fun f() {} ++f();", None).is_err());
+    assert!(compiler::compile("class A { f() { this++; } }", None).is_err());
+}
+
+#[test]
+fn multiple_assignment_evaluates_every_value_first() {
+    assert_compiles_to(
+        "a, b = b, a;",
+        "
+        GetGlobal '\"b\"'
+        GetGlobal '\"a\"'
+        SetGlobal '\"b\"'
+        Pop
+        SetGlobal '\"a\"'
+        Pop
+        Nil
+        Return
+        ",
+    );
+    let error = |source| {
+        let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+        assert!(program.is_err());
+        diagnostics[0].to_string()
+    };
+    assert_eq!(
+        error("a, b = 1;"),
+        "[line 1] Error at '1': Expected 2 values to assign but got 1."
+    );
+    assert_eq!(
+        error("a, b = 1, 2, 3;"),
+        "[line 1] Error at '3': Expected 2 values to assign but got 3."
+    );
+    assert_eq!(
+        error("a, b.c = 1, 2;"),
+        "[line 1] Error at '.': Expect '=' after assignment targets."
+    );
+}
+
+#[test]
+fn minus_minus_negates_twice_what_it_cant_decrement() {
+    assert_compiles_to("print --(3);", "Constant '3'\nPrint\nNil\nReturn");
+    assert_compiles_to(
+        "print --(a);",
+        "GetGlobal '\"a\"'\nNegate\nNegate\nPrint\nNil\nReturn",
+    );
+    assert!(compiler::compile("++(3);", None).is_err());
+
+    // clox has no increments, so it always negates twice
+    let (program, diagnostics) = compiler::compile_clox("print --a; print 1--a;", None);
+    assert!(diagnostics.is_empty());
+    let expected = chunk::assemble(
+        "GetGlobal '\"a\"'\nNegate\nNegate\nPrint
+        Constant '1'\nGetGlobal '\"a\"'\nNegate\nSubtract\nPrint\nNil\nReturn",
+    )
+    .unwrap();
+    assert_eq!(program.unwrap().chunk().code, expected.code);
+}
+
+#[test]
+fn diagnostics_underline_the_code_they_are_about() {
+    let source = "print 1;\nvar x = \"a\" + ;";
+    let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+    assert!(program.is_err());
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 2] Error at ';': Expect expression."
+    );
+    let (line, marker) = diagnostics[0].underline(source).unwrap();
+    assert_eq!(line, "var x = \"a\" + ;");
+    assert_eq!(marker, "              ^ Error: Expect expression.");
+    assert_eq!(
+        diagnostics[0].with_snippet(source),
+        "[line 2] Error at ';': Expect expression.\nvar x = \"a\" + ;\n              ^"
+    );
+
+    let (_, diagnostics) = compiler::compile_with_diagnostics("if (true) 1; else 2;", None);
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+    assert!(diagnostics[0].underline("").is_none());
+    assert_eq!(
+        diagnostics[0].with_snippet(source),
+        "[line 1] Warning (dead-code): Condition is always true, so the else branch never runs."
+    );
+}
+
+#[test]
+fn shadowing_natives_and_globals_warns() {
+    let source = "var clock;\nvar a;\nfun f(a) { var hex; }\nvar a;\n{ var b; }";
+    let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+    assert!(program.is_ok());
+    let warnings: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        warnings,
+        [
+            "[line 1] Warning (shadow): Global 'clock' shadows a native function.",
+            "[line 3] Warning (shadow): Local 'a' shadows a global.",
+            "[line 3] Warning (shadow): Local 'hex' shadows a native function.",
+            "[line 4] Warning (shadow): Global 'a' is already defined.",
+        ]
+    );
+    assert!(diagnostics.iter().all(|d| d.name == Some("shadow")));
+    let (_, marker) = diagnostics[2].underline(source).unwrap();
+    assert_eq!(
+        marker,
+        "               ^^^ Warning: Local 'hex' shadows a native function."
+    );
+}
+
+#[test]
+fn pragmas_allow_warnings() {
+    let source = "var clock;\nif (false) print 1;\n#pragma allow shadow\nvar clock;";
+    let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+    assert!(program.is_ok());
+    let names: Vec<_> = diagnostics.iter().map(|d| d.name).collect();
+    assert_eq!(names, [Some("dead-code")]);
+
+    let allowed = format!("#pragma allow {}\n{source}", compiler::WARNINGS.join(" "));
+    let (_, diagnostics) = compiler::compile_with_diagnostics(&allowed, None);
+    assert!(diagnostics.is_empty());
+
+    // clox has no warnings
+    let (_, diagnostics) = compiler::compile_clox(source, None);
+    assert!(diagnostics.is_empty());
+
+    let (program, diagnostics) =
+        compiler::compile_with_diagnostics("#pragma\n#pragma deny\n#pragma allow all", None);
+    assert!(program.is_err());
+    let errors: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        errors,
+        [
+            "[line 1] Error at '#pragma': Expect pragma name.",
+            "[line 2] Error at 'deny': Unknown pragma 'deny'.",
+            "[line 3] Error at 'all': Unknown warning 'all'.",
+        ]
+    );
+}
+
+#[test]
+fn strict_pragmas_make_warnings_errors() {
+    let source = "#pragma strict\n#pragma allow dead-code\nvar clock;\nif (false) print 1;";
+    let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+    assert!(program.is_err());
+    let errors: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        errors,
+        ["[line 3] Error (shadow): Global 'clock' shadows a native function."]
+    );
+
+    let (program, diagnostics) = compiler::compile_with_diagnostics("#pragma strict on", None);
+    assert!(program.is_err());
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 1] Error at 'on': Expect end of pragma after 'strict'."
+    );
+}
+
+#[test]
+fn interactive_input_can_leave_off_the_last_semicolon() {
+    let mut input = "var a = 1; print a // comment".to_string();
+    let (program, diagnostics) = compiler::compile_interactive(&mut input);
+    assert!(program.is_ok());
+    assert!(diagnostics.is_empty());
+    assert_eq!(input, "var a = 1; print a; // comment");
+
+    let mut input = "var a = 1 print a".to_string();
+    assert!(compiler::compile_interactive(&mut input).0.is_err());
+    assert_eq!(input, "var a = 1 print a");
+    assert!(compiler::compile("print 1", None).is_err());
+}
+
+#[test]
+fn try_statements_need_a_handler() {
+    assert!(compiler::compile("try { throw 1; } catch (e) { print e; }", None).is_ok());
+    assert!(compiler::compile("try { throw 1; } finally { print 2; }", None).is_ok());
+    assert!(compiler::compile("try { print 1; }", None).is_err());
+    assert!(compiler::compile("try { print 1; } catch (e) {}", None).is_ok());
+    assert!(compiler::compile("while (true) { try { break; } finally {} }", None).is_ok());
+    assert!(compiler::compile("fun f() { try {} catch (e) { return; } finally {} }", None).is_ok());
+}
+
+#[test]
+fn exits_in_dead_code_are_dropped_with_it() {
+    let source = "
+        fun f(n) {
+          try { if (false) { return 1; } var a = n + 1; print a; }
+          finally { print \"fin\"; }
+          while (true) { try { if (false) break; break; } finally {} }
+          return 3;
+        }
+        print f(10);
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    assert!(program.chunk().verify().is_ok());
+}
+
+#[test]
+fn imports_name_a_module_or_copy_in_its_globals() {
+    assert_compiles_to(
+        "import \"a.lox\"; { import b from \"b.lox\"; }",
+        "
+        Import '\"a.lox\"'
+        ImportAll
+        Import '\"b.lox\"'
+        Pop
+        Nil
+        Return
+        ",
+    );
+    assert!(compiler::compile("import b \"b.lox\";", None).is_err());
+    assert!(compiler::compile("var from = 1; print from;", None).is_ok());
+}
+
+#[test]
+fn constants_past_the_first_256_load_with_a_long_index() {
+    let source: String = (0..300).map(|i| format!("print {i};")).collect();
+    let program = compiler::compile(&(source + "print a;"), None).unwrap();
+    let listing = program.chunk().listing("script");
+    assert!(listing.contains("Constant  255 '255'"), "{listing}");
+    assert!(listing.contains("ConstantLong  256 '256'"), "{listing}");
+    assert!(listing.contains("GetGlobalLong  300 '\"a\"'"), "{listing}");
+    assert!(program.chunk().verify().is_ok());
+    assert_eq!(
+        program.chunk().code,
+        chunk::assemble(&listing).unwrap().code
+    );
+}
+
+#[test]
+fn lines_are_kept_for_code_after_a_dead_branch() {
+    let program = compiler::compile("if (false) {\n  print 1;\n}\nprint 2;", None).unwrap();
+    let chunk = program.chunk();
+    let lines: Vec<_> = (0..chunk.code.len()).map(|i| chunk.get_line(i)).collect();
+    assert_eq!(lines, [Some(4); 5]);
+}
+
+#[test]
+fn errors_in_later_statements_are_reported_too() {
+    let source = "print ;\nvar = 1;\nprint 1 try { print +; } catch (e) {}\nprint 2 throw;";
+    let (program, diagnostics) = compiler::compile_with_diagnostics(source, None);
+    assert!(program.is_err());
+    let errors: Vec<_> = diagnostics.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        errors,
+        [
+            "[line 1] Error at ';': Expect expression.",
+            "[line 2] Error at '=': Expect variable name.",
+            "[line 3] Error at 'try': Expect ';' after value.",
+            "[line 3] Error at '+': Expect expression.",
+            "[line 4] Error at 'throw': Expect ';' after value.",
+            "[line 4] Error at ';': Expect expression.",
+        ]
+    );
+}
+
+#[test]
+fn listings_reassemble_into_the_chunk_they_came_from() {
+    let source = "
+        var a = \"one\";
+        for (var i = 0; i < 3; i = i + 1) {
+          if (i == 1 and a != nil) continue;
+          while (false) { break; }
+          a = a + \"!\";
+        }
+        print a or -1.5;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let expected = program.chunk();
+    let listing = expected.listing("script");
+    let actual = chunk::assemble(&listing).unwrap();
+    assert!(
+        actual.code == expected.code && actual.constants == expected.constants,
+        "{listing} reassembled differently:\n{}",
+        chunk::diff(expected, &actual)
+    );
+    assert_eq!(actual.listing("script"), listing);
+
+    assert!(chunk::assemble("Constant 99999999999999 '1'\nReturn").is_err());
+    assert!(chunk::assemble("Constant 1 '1'\nReturn").is_err());
+}
@@ -0,0 +1,32 @@
+#![cfg(feature = "extensions")]
+
+use std::io;
+
+use rlox::{
+    compiler,
+    vm::{InterpretResult, VM},
+};
+
+#[test]
+fn loading_a_missing_extension_is_a_runtime_error() {
+    let program = compiler::compile("loadExtension(\"./missing.so\");", None).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.starts_with("Could not load extension \"./missing.so\""));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn extensions_must_match_the_interface_version() {
+    // Any shared library without the version symbol is rejected before its
+    // code is run
+    let source = "loadExtension(\"libc.so.6\");";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.contains("RLOX_EXTENSION_ABI"), "{stderr}");
+}
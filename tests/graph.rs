@@ -0,0 +1,37 @@
+use std::{env, fs, path::PathBuf, process};
+
+use rlox::graph;
+
+// A directory of its own for each test, since they run at the same time
+fn dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rlox-graph-{name}-{}", process::id()));
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    dir
+}
+
+#[test]
+fn import_graphs_highlight_cycles_and_missing_modules() {
+    let dir = dir("cycles");
+    let main = "import \"lib/a.lox\";\nimport b from \"b.lox\";\nimport \"gone.lox\";\n";
+    fs::write(dir.join("main.lox"), main).unwrap();
+    fs::write(dir.join("lib/a.lox"), "import \"../b.lox\";\n").unwrap();
+    fs::write(dir.join("b.lox"), "import c from \"c.lox\";\n").unwrap();
+    fs::write(dir.join("c.lox"), "import \"b.lox\";\nimport \"b.lox\";\n").unwrap();
+
+    assert_eq!(
+        graph::generate(&dir.join("main.lox"), main),
+        "digraph imports {\n  \
+           \"main.lox\";\n  \
+           \"lib/a.lox\";\n  \
+           \"b.lox\" [color=red];\n  \
+           \"gone.lox\" [style=dashed];\n  \
+           \"c.lox\" [color=red];\n  \
+           \"main.lox\" -> \"lib/a.lox\";\n  \
+           \"main.lox\" -> \"b.lox\";\n  \
+           \"main.lox\" -> \"gone.lox\";\n  \
+           \"lib/a.lox\" -> \"b.lox\";\n  \
+           \"b.lox\" -> \"c.lox\" [color=red];\n  \
+           \"c.lox\" -> \"b.lox\" [color=red];\n\
+         }\n"
+    );
+}
@@ -0,0 +1,109 @@
+use std::{env, fs, path::PathBuf, process};
+
+use rlox::include::{self, SourceMap};
+
+// A directory of its own for each test, since they run at the same time
+fn dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rlox-include-{name}-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn expand(path: &PathBuf) -> anyhow::Result<Option<(String, SourceMap)>> {
+    let source = fs::read_to_string(path).unwrap();
+    include::expand(path.to_str().unwrap(), &source)
+}
+
+#[test]
+fn generated_files_map_back_to_their_sources() {
+    let dir = dir("generated");
+    fs::write(dir.join("gen.map"), "1 10 page.tmpl\n3 20 other.tmpl\n").unwrap();
+    fs::write(
+        dir.join("gen.lox"),
+        "print 1;\nprint 2;\nprint 3;\n//# sourceMappingURL=gen.map\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("main.lox"),
+        "print 0;\n#include \"gen.lox\"\nprint 4;\n",
+    )
+    .unwrap();
+
+    let (_, map) = expand(&dir.join("gen.lox")).unwrap().unwrap();
+    let lines: Vec<_> = (1..=4).map(|line| map.locate(line)).collect();
+    assert_eq!(
+        lines,
+        [
+            ("page.tmpl", 10),
+            ("page.tmpl", 11),
+            ("other.tmpl", 20),
+            ("other.tmpl", 21),
+        ]
+    );
+
+    // Included, a generated file's lines still map back to its sources
+    let main = dir.join("main.lox");
+    let (expanded, map) = expand(&main).unwrap().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(expanded.lines().count(), 6);
+    assert_eq!(map.locate(1), (main.to_str().unwrap(), 1));
+    assert_eq!(map.locate(2), ("page.tmpl", 10));
+    assert_eq!(map.locate(4), ("other.tmpl", 20));
+    assert_eq!(map.locate(6), (main.to_str().unwrap(), 3));
+}
+
+#[test]
+fn missing_and_invalid_source_maps_are_errors() {
+    let dir = dir("invalid");
+    let error = |map: Option<&str>| {
+        match map {
+            Some(map) => fs::write(dir.join("gen.map"), map).unwrap(),
+            None => {
+                let _ = fs::remove_file(dir.join("gen.map"));
+            }
+        }
+        fs::write(
+            dir.join("gen.lox"),
+            "print 1;\n//# sourceMappingURL=gen.map\n",
+        )
+        .unwrap();
+        expand(&dir.join("gen.lox")).unwrap_err().to_string()
+    };
+
+    let missing = error(None);
+    let invalid = error(Some("1 10 page.tmpl\none two page.tmpl\n"));
+    let out_of_order = error(Some("3 10 page.tmpl\n2 20 page.tmpl\n"));
+    let empty = error(Some("\n"));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(
+        missing.ends_with("] Could not read source map \"gen.map\"."),
+        "{missing}"
+    );
+    assert!(
+        invalid.starts_with("[line 2 in ") && invalid.ends_with("] Invalid source map entry."),
+        "{invalid}"
+    );
+    assert!(
+        out_of_order.ends_with("] Source map entries are out of order."),
+        "{out_of_order}"
+    );
+    assert!(empty.ends_with(" has no entries."), "{empty}");
+}
+
+#[test]
+fn lines_past_the_largest_number_saturate() {
+    let map = SourceMap::parse("gen.map", "1 4294967295 page.tmpl").unwrap();
+    assert_eq!(map.locate(2), ("page.tmpl", u32::MAX));
+
+    let dir = dir("saturate");
+    fs::write(dir.join("gen.map"), "2 4294967295 page.tmpl").unwrap();
+    fs::write(
+        dir.join("gen.lox"),
+        "print 1;\nprint 2;\nprint 3;\n//# sourceMappingURL=gen.map\n",
+    )
+    .unwrap();
+    let (_, map) = expand(&dir.join("gen.lox")).unwrap().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(map.locate(3), ("page.tmpl", u32::MAX));
+}
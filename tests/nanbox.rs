@@ -0,0 +1,43 @@
+#![cfg(feature = "nan-boxing")]
+
+use std::{mem, sync::Arc};
+
+use rlox::{nanbox::NanBox, value::Value};
+
+#[test]
+fn values_round_trip_through_a_single_word() {
+    assert_eq!(mem::size_of::<NanBox>(), 8);
+    let values = [
+        Value::Nil,
+        Value::Bool(true),
+        Value::Bool(false),
+        Value::Number(0.0),
+        Value::Number(-1.5),
+        Value::Number(f64::INFINITY),
+        Value::from_string("lox".to_string()),
+    ];
+    for value in values {
+        let boxed = NanBox::from(value.clone());
+        assert_eq!(Value::from(boxed), value);
+    }
+
+    // Every NaN is a number, whatever its payload
+    let nan = NanBox::from_number(f64::from_bits(0xfffc_0000_0000_0003));
+    assert!(nan.as_number().unwrap().is_nan());
+    assert!(nan.as_bool().is_none() && !nan.is_obj());
+}
+
+#[test]
+fn objects_keep_their_reference_count() {
+    let Value::Obj(obj) = Value::from_string("shared".to_string()) else {
+        unreachable!()
+    };
+    let boxed = NanBox::from_obj(obj.clone());
+    let copy = boxed.clone();
+    let value = copy.to_value();
+    assert_eq!(Arc::strong_count(&obj), 4);
+    assert_eq!(boxed, copy);
+
+    drop((boxed, copy, value));
+    assert_eq!(Arc::strong_count(&obj), 1);
+}
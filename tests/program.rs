@@ -0,0 +1,1353 @@
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use rlox::{
+    cache,
+    chunk::{Chunk, ChunkBuilder, OpCode},
+    compiler::{self, Severity},
+    number::{self, Format},
+    program::Program,
+    value::{Obj, Value},
+    vm::{self, Config, InterpretResult, VM},
+};
+
+#[test]
+fn programs_run_repeatedly_across_threads() {
+    let program = compiler::compile("print \"a\" + \"b\";", None).unwrap();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let mut vm = VM::new(Default::default());
+                for _ in 0..2 {
+                    assert!(matches!(vm.run_program(&program), InterpretResult::Ok));
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn parallel_runs_collect_each_programs_output() {
+    let programs: Vec<_> = ["print 1;", "print 2; -\"a\";", "print 3;"]
+        .into_iter()
+        .map(|source| compiler::compile(source, None).unwrap())
+        .collect();
+    let options = rlox::parallel::Options {
+        jobs: 2,
+        ..Default::default()
+    };
+    let outputs = rlox::run_parallel(&programs, options);
+
+    let stdout: Vec<_> = outputs.iter().map(|o| o.stdout.as_str()).collect();
+    assert_eq!(stdout, ["1\n", "2\n", "3\n"]);
+    assert_eq!(outputs[1].result, InterpretResult::RuntimeError);
+    assert_eq!(
+        outputs[1].stderr,
+        "Operand must be a number.\nprint 2; -\"a\";\n         ^\n[line 1] in script\n"
+    );
+}
+
+#[test]
+fn timeouts_interrupt_endless_loops() {
+    let program = compiler::compile("while (true) {}", None).unwrap();
+    let config = Config {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+    let mut stderr = vec![];
+    let result = VM::with_output(config, io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "Execution timed out.\nwhile (true) {}\n              ^\n[line 1] in script\n"
+    );
+
+    // Catching the timeout would let the loop go on forever
+    let program = compiler::compile(
+        "while (true) { try { while (true) {} } catch (e) {} }",
+        None,
+    );
+    let result = VM::with_output(config, io::sink(), io::sink()).run_program(&program.unwrap());
+    assert_eq!(result, InterpretResult::RuntimeError);
+}
+
+#[test]
+fn undefined_names_suggest_close_matches() {
+    let program = compiler::compile("var count = 1; print cont;", None).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert!(stderr.starts_with(b"Undefined variable 'cont'. Did you mean 'count'?\n"));
+}
+
+#[test]
+fn runtime_errors_trace_every_call() {
+    let source = "fun f(a) { g(); }\nfun g() { print 1 + nil; }\nf(1);";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "Operands must be two numbers or two strings.\n\
+         fun g() { print 1 + nil; }\n                  ^\n\
+         [line 2] in g()\n\
+         [line 1] in f()\n\
+         [line 3] in script\n"
+    );
+}
+
+#[test]
+fn globals_persist_between_runs_on_one_vm() {
+    let mut stdout = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, io::sink());
+    assert_eq!(
+        vm.interpret("fun f() { print a; }", None),
+        InterpretResult::Ok
+    );
+    assert_eq!(vm.interpret("var a = 1; f();", None), InterpretResult::Ok);
+    drop(vm);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "1\n");
+}
+
+#[test]
+fn global_names_include_natives_and_definitions() {
+    let mut vm = VM::with_output(Default::default(), io::sink(), io::sink());
+    assert_eq!(
+        vm.interpret("var a; fun b() {} { var c; }", None),
+        InterpretResult::Ok
+    );
+    let names: Vec<_> = vm.global_names().collect();
+    assert!(names.contains(&"clock"));
+    assert!(names.contains(&"a") && names.contains(&"b"));
+    assert!(!names.contains(&"c"));
+}
+
+#[test]
+fn natives_are_callable_from_lox() {
+    fn sum(args: &[Value]) -> Result<Value, String> {
+        args.iter()
+            .map(|arg| match arg {
+                Value::Number(n) => Ok(*n),
+                _ => Err("sum() takes numbers.".to_string()),
+            })
+            .sum::<Result<f64, String>>()
+            .map(Value::Number)
+    }
+
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    vm.define_native("sum", sum);
+    assert_eq!(
+        vm.interpret("print sum(1, 2, 3);", None),
+        InterpretResult::Ok
+    );
+    assert_eq!(
+        vm.interpret("fun f() {\n  sum(1, nil);\n}\nf();", None),
+        InterpretResult::RuntimeError
+    );
+    drop(vm);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "6\n");
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "sum() takes numbers.\n\
+         \x20 sum(1, nil);\n     ^^^^^^^^\n\
+         [line 2] in [native fn sum]\n\
+         [line 2] in f()\n\
+         [line 4] in script\n"
+    );
+}
+
+#[test]
+fn strings_have_methods() {
+    let source = "
+        var s = \"  Hello, World  \".trim();
+        print s.upper();
+        print s.len();
+        print s.indexOf(\"World\");
+        print s.slice(0, 5).repeat(2);
+        print s.replace(\"World\", \"Lox\");
+        var lower = s.lower;
+        print lower();
+        print \"x\".slice(1);
+    ";
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::RuntimeError);
+    drop(vm);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "HELLO, WORLD\n12\n7\nHelloHello\nHello, Lox\nhello, world\n"
+    );
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(
+        stderr.starts_with("Expected 2 arguments but got 1."),
+        "{stderr}"
+    );
+    assert!(stderr.contains("[native fn slice]"), "{stderr}");
+}
+
+#[test]
+fn print_and_concatenation_use_to_string_methods() {
+    let source = "
+        class Point {
+          init(x, y) { this.x = x; this.y = y; }
+          toString() { return \"(\" + toFixed(this.x, 0) + \", \" + toFixed(this.y, 0) + \")\"; }
+        }
+        class Plain {}
+        var p = Point(1, 2);
+        print p;
+        print \"at \" + p;
+        print p + \"!\";
+        print Plain();
+        class Loop { toString() { return \"\" + this; } }
+        print Loop();
+    ";
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::RuntimeError);
+    drop(vm);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "(1, 2)\nat (1, 2)\n(1, 2)!\nPlain instance\n"
+    );
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(
+        stderr.starts_with("toString() calls are nested too deeply."),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn equality_uses_equals_methods() {
+    let source = "
+        class Point {
+          init(x) { this.x = x; }
+          equals(other) { return hasField(other, \"x\") and this.x == other.x; }
+        }
+        class Plain {}
+        print Point(1) == Point(1);
+        print Point(1) != Point(2);
+        print Point(1) == Plain();
+        print Point(1) == nil;
+        var plain = Plain();
+        print plain == plain;
+        print plain == Plain();
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "true\ntrue\nfalse\nfalse\ntrue\nfalse\n"
+    );
+}
+
+#[test]
+fn closures_share_variables_that_outlive_their_scope() {
+    let source = "
+        var get; var set;
+        {
+          var a = 1;
+          fun g() { return a; }
+          fun s(v) { a = v; }
+          get = g; set = s;
+        }
+        set(2);
+        print get();
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "2\n");
+}
+
+#[test]
+fn methods_stay_bound_to_their_instance() {
+    let source = "
+        class Counter {
+          add() { this.count = this.count + 1; return this.count; }
+        }
+        var c = Counter();
+        c.count = 0;
+        var add = c.add;
+        add();
+        print add();
+        print c.count;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "2\n2\n");
+}
+
+#[test]
+fn printing_pops_the_value_on_top() {
+    let source = "print 1; { var a = 2; var b = 3; a; print a; print b; }";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "1\n2\n3\n");
+}
+
+#[test]
+fn binary_operators_take_their_left_operand_first() {
+    let source = "print 3 - 1; print 6 / 2; print 1 < 2; print 2 >= 3; print \"a\" + \"b\";";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "2\n3\ntrue\nfalse\nab\n"
+    );
+}
+
+#[test]
+fn repl_sessions_save_and_load() {
+    let path = std::env::temp_dir().join(format!("rlox-session-test-{}.lox", std::process::id()));
+    let repl = |input: String| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    repl(format!(
+        "var a = 1;\nprint -nil;\nfun f() {{ return a + 1; }}\n:save {}\n",
+        path.display()
+    ));
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let loaded = repl(format!(":load {}\nprint f();\n", path.display()));
+
+    // A pasted input spanning lines is saved as it was entered, and loads
+    // back as one input
+    let pasted = "fun g() {\n  return f() * 2;\n}";
+    std::fs::write(&path, format!("{contents}{pasted}\n")).unwrap();
+    let resaved = path.with_extension("resaved.lox");
+    let reloaded = repl(format!(
+        ":load {}\nprint g();\n:save {}\n",
+        path.display(),
+        resaved.display()
+    ));
+    let resaved_contents = std::fs::read_to_string(&resaved).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&resaved).unwrap();
+
+    assert_eq!(contents, "var a = 1;\nfun f() { return a + 1; }\n");
+    assert!(loaded.contains("2\n"), "{loaded}");
+    assert!(reloaded.contains("4\n"), "{reloaded}");
+    assert_eq!(
+        resaved_contents,
+        format!("{contents}{pasted}\nprint g();\n")
+    );
+}
+
+#[test]
+fn is_checks_types_and_classes() {
+    let source = "
+        class A {}
+        class B {}
+        var a = A();
+        print 1 is \"number\";
+        print \"s\" is \"number\";
+        print a is A;
+        print a is B;
+        print a is \"A\";
+        print a is \"instance\";
+        print A is A;
+        print 1 is A;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "true\nfalse\ntrue\nfalse\ntrue\ntrue\nfalse\nfalse\n"
+    );
+
+    let program = compiler::compile("print 1 is 2;", None).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.starts_with("Right operand of 'is' must be a type name or a class.\n"));
+}
+
+#[test]
+fn cached_programs_run_like_freshly_compiled_ones() {
+    let dir = std::env::temp_dir().join(format!("rlox-cache-test-{}", std::process::id()));
+    let source = "fun f() { print \"cached\"; }\nf();";
+    let key = cache::key(source, None);
+    assert!(cache::load(&dir, &key).is_none());
+
+    let program = compiler::compile(source, None).unwrap();
+    cache::store(&dir, &key, "test.lox", &program).unwrap();
+    let cached = cache::load(&dir, &key).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&cached);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "cached\n");
+
+    cache::clear(&dir).unwrap();
+    assert!(cache::load(&dir, &key).is_none());
+}
+
+#[test]
+fn dup_and_swap_rearrange_the_top_of_the_stack() {
+    let mut builder = ChunkBuilder::new();
+    builder.emit_constant(Value::Number(1.0)).unwrap();
+    builder.emit_constant(Value::Number(2.0)).unwrap();
+    builder.emit(OpCode::Swap);
+    builder.emit(OpCode::Print);
+    builder.emit(OpCode::Print);
+    builder.emit_constant(Value::Number(3.0)).unwrap();
+    builder.emit(OpCode::Dup);
+    builder.emit(OpCode::Add);
+    builder.emit(OpCode::Print);
+    builder.emit(OpCode::Nil);
+    builder.emit(OpCode::Return);
+    let program = Program::new(builder.build().unwrap(), None);
+
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "1\n2\n6\n");
+}
+
+#[test]
+fn bytecode_files_run_like_the_source_they_were_compiled_from() {
+    let source = "
+        class Greeter { init(name) { this.name = name; } greet() { return \"hi \" + this.name; } }
+        fun twice(f) { return f() + \" \" + f(); }
+        print twice(Greeter(\"lox\").greet);
+        print 1.5 + 2;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    for path in [Some("test.lox"), None] {
+        let bytes = rlox::bytecode::write(program.chunk(), path);
+        let (chunk, source_path) = rlox::bytecode::read(&bytes).unwrap();
+        assert_eq!(source_path.as_deref(), path);
+        let mut stdout = vec![];
+        let result = VM::with_output(Default::default(), &mut stdout, io::sink())
+            .run_program(&Program::new(chunk, None));
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(String::from_utf8(stdout).unwrap(), "hi lox hi lox\n3.5\n");
+    }
+
+    // The byte after the magic number is the format's version
+    let mut bytes = rlox::bytecode::write(program.chunk(), None);
+    bytes[4] = bytes[4].wrapping_add(1);
+    let error = rlox::bytecode::read(&bytes).unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("Unsupported bytecode version"));
+}
+
+#[test]
+fn verified_bytecode_importing_a_non_module_fails_without_panicking() {
+    let chunk = rlox::chunk::assemble("Constant '1'\nImportAll\nNil\nReturn").unwrap();
+    let bytes = rlox::bytecode::write(&chunk, None);
+    let (chunk, _) = rlox::bytecode::read(&bytes).unwrap();
+    let mut stderr = vec![];
+    let result = VM::with_output(Default::default(), io::sink(), &mut stderr)
+        .run_program(&Program::new(chunk, None));
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert!(String::from_utf8(stderr)
+        .unwrap()
+        .starts_with("Can only import all of a module."));
+}
+
+#[test]
+fn bundles_carry_their_payload_after_the_interpreter() {
+    let path = std::env::temp_dir().join(format!("rlox-bundle-test-{}", std::process::id()));
+    let read = |bytes: &[u8]| {
+        std::fs::write(&path, bytes).unwrap();
+        rlox::bundle::read_payload(&path).unwrap()
+    };
+    let exe = b"interpreter".as_slice();
+
+    let bundled = rlox::bundle::build(exe, b"payload");
+    let rebundled = rlox::bundle::build(&bundled, b"other");
+    let plain = read(exe);
+    let payload = read(&bundled);
+    let replaced = read(&rebundled);
+    let truncated = read(&bundled[..bundled.len() - 1]);
+    // A trailer claiming more than the file holds
+    let mut overlong = bundled.clone();
+    let len_at = overlong.len() - 16;
+    overlong[len_at..len_at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+    let overlong = read(&overlong);
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(plain, None);
+    assert_eq!(payload.as_deref(), Some(b"payload".as_slice()));
+    assert_eq!(rebundled.len(), exe.len() + "other".len() + 16);
+    assert_eq!(replaced.as_deref(), Some(b"other".as_slice()));
+    assert_eq!(truncated, None);
+    assert_eq!(overlong, None);
+}
+
+#[test]
+fn runtime_errors_in_included_files_name_that_file() {
+    let dir = std::env::temp_dir().join(format!("rlox-include-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("lib.lox"), "fun f() {\n  return -nil;\n}\n").unwrap();
+    let main = dir.join("main.lox");
+    let main = main.to_str().unwrap();
+    let source = "print 1;\n#include \"lib.lox\"\nf();\n";
+    let (expanded, source_map) = rlox::include::expand(main, source).unwrap().unwrap();
+    let mut vm = VM::with_output(Default::default(), io::sink(), io::sink());
+    let result = vm.interpret(&expanded, Some(source_map));
+    let error = vm.take_error().unwrap();
+    drop(vm);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(result, InterpretResult::RuntimeError);
+    let trace: Vec<_> = error
+        .trace
+        .iter()
+        .map(|f| (f.file.as_deref().unwrap(), f.line.unwrap()))
+        .collect();
+    assert!(trace[0].0.ends_with("lib.lox"), "{trace:?}");
+    assert_eq!(trace[0].1, 2);
+    assert_eq!(trace[1], (main, 3));
+}
+
+#[test]
+fn calling_a_class_runs_init_and_returns_the_instance() {
+    let source = "
+        class Pair {
+          init(a, b) { this.a = a; this.b = b; return; }
+        }
+        var pair = Pair(1, 2);
+        print pair.a + pair.b;
+        print pair.init(3, 4) == pair;
+        class Unit { init() { this.n = 1; return nil; } }
+        var unit = Unit();
+        print unit.n;
+        print unit.init() == unit;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "3\ntrue\n1\ntrue\n");
+    let (program, diagnostics) =
+        compiler::compile_with_diagnostics("class A { init() { return 1; } }", None);
+    assert!(program.is_err());
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 1] Error at 'return': Can't return a value from an initializer."
+    );
+}
+
+#[test]
+fn bytes_round_trip_through_their_encodings() {
+    let source = "
+        var b = bytes(\"hé\");
+        print b;
+        print b.length + b[0];
+        print hex(b) + \" \" + base64(b);
+        print utf8(fromHex(hex(b))) == utf8(fromBase64(base64(b)));
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "<bytes 68c3a9>\n107\n68c3a9 aMOp\ntrue\n"
+    );
+}
+
+#[test]
+fn arrow_functions_return_their_expression() {
+    let source = "
+        var add = (a, b) => a + b;
+        print add(1, 2);
+        print (() => 42)();
+        fun adder(n) { return (x) => x + n; }
+        print adder(2)(3);
+        print add;
+        var a = 5;
+        print (a) * (1 + 1);
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "3\n42\n5\n<fn lambda>\n10\n"
+    );
+}
+
+#[test]
+fn multiple_assignment_swaps_locals_globals_and_upvalues() {
+    let source = "
+        var a = 1;
+        var b = 2;
+        a, b = b, a;
+        print a; print b;
+        {
+          var c = 3;
+          fun f() { a, c = c, a; }
+          f();
+          print a; print c;
+        }
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "2\n1\n3\n2\n");
+}
+
+#[test]
+fn reflection_natives_inspect_instances_and_classes() {
+    let source = "
+        class Point { init(x, y) { this.y = y; this.x = x; } norm() {} }
+        var p = Point(1, 2);
+        print fields(p);
+        print methods(Point);
+        print className(p);
+        print className(Point);
+        print className(1);
+        print setField(p, \"z\", 3);
+        print getField(p, \"z\");
+        print getField(p, \"w\");
+    ";
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::RuntimeError);
+    drop(vm);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "x, y\ninit, norm\nPoint\nPoint\nnil\n3\n3\n"
+    );
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(stderr.starts_with("Undefined field 'w'."), "{stderr}");
+}
+
+#[test]
+fn help_describes_functions_and_classes_with_their_docs() {
+    let source = "
+        /// Adds two numbers.
+        fun add(a, b) { return a + b; }
+        fun negate(n) { \"Flips the sign.\"; return -n; }
+        class Point { \"A point.\"; norm() { return 0; } init() {} }
+        print help(add);
+        print help(negate);
+        print negate(1);
+        print help(Point);
+        print help(Point().norm);
+        print help(clock);
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "<fn add>, taking 2 arguments\nAdds two numbers.\n\
+         <fn negate>, taking 1 argument\nFlips the sign.\n\
+         -1\n\
+         class Point, with methods init, norm\nA point.\n\
+         <fn norm>, taking 0 arguments\n\
+         <native fn clock>\n"
+    );
+}
+
+#[test]
+fn fields_can_be_checked_for_and_removed() {
+    let source = "
+        class Point { norm() { return 0; } }
+        var p = Point();
+        p.x = 1;
+        print hasField(p, \"x\");
+        print hasField(p, \"norm\");
+        print removeField(p, \"x\");
+        print removeField(p, \"x\");
+        print hasField(p, \"x\");
+        p.x = 2;
+        print p.x;
+        removeField(p, \"x\");
+        try { print p.x; } catch (e) { print e; }
+        try { hasField(1, \"x\"); } catch (e) { print e; }
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "true\nfalse\ntrue\nfalse\nfalse\n2\nUndefined property 'x'.\n\
+         hasField() takes an instance and a field name.\n"
+    );
+}
+
+#[test]
+fn garbage_collection_frees_reference_cycles() {
+    let source = "
+        class Node {}
+        var kept = Node();
+        kept.self = kept;
+        for (var i = 0; i < 10; i = i + 1) {
+          var node = Node();
+          node.self = node;
+          fun f() { return f; }
+        }
+    ";
+    let mut vm = VM::with_output(Default::default(), io::sink(), io::sink());
+    assert_eq!(vm.interpret(source, None), InterpretResult::Ok);
+    // Ten instances and the ten upvalues each f captures itself through
+    assert_eq!(vm.collect_garbage(), 20);
+    assert_eq!(vm.collect_garbage(), 0);
+    assert_eq!(
+        vm.interpret("print kept.self == kept;", None),
+        InterpretResult::Ok
+    );
+}
+
+#[test]
+fn gc_stress_collects_as_objects_are_allocated() {
+    let config = Config {
+        gc_stress: true,
+        gc_log: true,
+        ..Default::default()
+    };
+    let source = "class A {} var a = A(); a.self = a; a = nil; print A() != nil;";
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let result = VM::with_output(config, &mut stdout, &mut stderr).interpret(source, None);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(stdout, b"true\n");
+    let log = String::from_utf8(stderr).unwrap();
+    assert_eq!(log.lines().count(), 3);
+    // The instance, with its one field
+    let bytes = std::mem::size_of::<Obj>() + std::mem::size_of::<(String, Value)>();
+    assert!(
+        log.contains(&format!(
+            "-- gc: freed 1 objects (about {bytes} bytes), 2 remain, took "
+        )),
+        "{log}"
+    );
+}
+
+#[test]
+fn break_and_continue_leave_and_restart_loops() {
+    let source = "
+        for (var i = 0; i < 10; i = i + 1) {
+          var doubled = i * 2;
+          if (i == 1) continue;
+          fun f() { return doubled; }
+          if (i == 3) break;
+          print f();
+        }
+        var n = 0;
+        while (true) { n = n + 1; if (n == 2) break; }
+        print n;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "0\n4\n2\n");
+}
+
+#[test]
+fn labeled_break_and_continue_leave_outer_loops() {
+    let source = "
+        outer: for (var i = 0; i < 3; i = i + 1) {
+          var j = 0;
+          while (true) {
+            j = j + 1;
+            if (j > 2) continue outer;
+            if (i == 2) break outer;
+            try { if (j == 2) continue outer; } finally { print \"finally\"; }
+            print i * 10 + j;
+          }
+        }
+        print \"done\";
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "finally\n1\nfinally\nfinally\n11\nfinally\ndone\n"
+    );
+}
+
+#[test]
+fn block_expressions_have_the_value_of_their_last_expression() {
+    let source = "
+        fun f() { return 3; }
+        var x = { var t = f(); t * 2 };
+        print x;
+        print 1 + { var a = 2; var b = 3; a * b };
+        print { print \"side effect\"; };
+        class Counter {
+          init() { this.count = 1; }
+          next() { return { var n = this.count; this.count = n + 1; n }; }
+        }
+        var counter = Counter();
+        print counter.next() + counter.next();
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "6\n7\nside effect\nnil\n3\n"
+    );
+}
+
+#[test]
+fn capture_clauses_copy_variables_instead_of_sharing_them() {
+    let source = "
+        var shared;
+        var copied;
+        for (var i = 0; i < 2; i = i + 1) {
+          fun get() { return i; }
+          fun snapshot[i]() { return i; }
+          if (i == 0) { shared = get; copied = snapshot; }
+        }
+        print shared();
+        print copied();
+        {
+          var total = 1;
+          fun add[total](n) { total = total + n; return total; }
+          total = 100;
+          print add(1);
+          print add(1);
+          print total;
+          fun countdown[total](n) { if (n == 0) return total; return countdown(n - 1); }
+          print countdown(3);
+        }
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "2\n0\n2\n3\n100\n100\n");
+}
+
+#[test]
+fn numbers_print_in_their_shortest_round_tripping_form() {
+    let source = "print 0.1 + 0.2; print -0; print 1000000000 * 1000000000000; print 0 / 0;";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "0.30000000000000004\n-0\n1e21\nnan\n"
+    );
+
+    for n in [
+        0.1,
+        -0.0,
+        1e21,
+        1.5e-8,
+        123456.789,
+        f64::MAX,
+        f64::MIN_POSITIVE,
+        f64::INFINITY,
+    ] {
+        let text = number::format(n, Format::Shortest);
+        assert_eq!(
+            number::parse(&text).map(f64::to_bits),
+            Some(n.to_bits()),
+            "{text}"
+        );
+    }
+    assert_eq!(number::format(1e300, Format::Shortest), "1e300");
+    assert_eq!(number::format(1e300, Format::Printf), "1e+300");
+}
+
+#[test]
+fn numbers_format_to_fixed_decimals_or_significant_digits() {
+    assert_eq!(number::fixed(1.23456, 2), "1.23");
+    assert_eq!(number::fixed(-1.0, 2), "-1.00");
+    assert_eq!(number::fixed(1e21, 2), "1e21");
+    assert_eq!(number::precision(123456.0, 2), "1.2e+5");
+    assert_eq!(number::precision(0.000001234, 2), "0.0000012");
+    assert_eq!(number::precision(0.0000001234, 2), "1.2e-7");
+    assert_eq!(number::precision(100.0, 3), "100");
+
+    let source = "print toFixed(2 / 3, 3); print toPrecision(2 / 3, 3); toFixed(1, 0.5);";
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::RuntimeError);
+    drop(vm);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "0.667\n0.667\n");
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(
+        stderr.starts_with("toFixed() takes a number and a digit count from 0 to 100."),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn increments_update_variables_and_fields() {
+    let source = "
+        var g = 1;
+        print g++ + g;
+        {
+          var l = 5;
+          fun f() { return --l; }
+          print f() + l--;
+          print l;
+        }
+        class Counter { init() { this.n = 0; } bump() { return ++this.n; } }
+        var c = Counter();
+        print c.n++;
+        print c.bump() + c.n;
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(String::from_utf8(stdout).unwrap(), "3\n8\n3\n0\n4\n");
+}
+
+#[test]
+fn strings_repeat_when_multiplied_by_a_count() {
+    let source = "
+        print \"ab\" * 3;
+        print 2 * \"xy\";
+        print \"ab\" * 0 == \"\";
+        try { \"ab\" * -1; } catch (e) { print e; }
+        try { \"ab\" * 1.5; } catch (e) { print e; }
+        try { \"ab\" * 100000000000000000000000000000; } catch (e) { print e; }
+        try { \"ab\" * 1073741824; } catch (e) { print e; }
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "ababab\nxyxy\ntrue\n\
+         String repetition count must be a non-negative integer.\n\
+         String repetition count must be a non-negative integer.\n\
+         String repetition result is too long.\n\
+         String repetition result is too long.\n"
+    );
+}
+
+#[test]
+fn strings_have_a_length_property() {
+    let source = "
+        print \"h\u{e9}llo\".length;
+        print \"\".length;
+        var s = \"ab\";
+        print (s + s).length;
+        try { print s.size; } catch (e) { print e; }
+        try { print (1).length; } catch (e) { print e; }
+        try { s.length = 3; } catch (e) { print e; }
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "5\n0\n4\n\
+         Undefined property 'size'.\n\
+         Undefined property 'length'.\n\
+         Only instances have fields.\n"
+    );
+}
+
+#[test]
+fn thrown_values_and_runtime_errors_can_be_caught() {
+    let source = "
+        fun fail(n) { if (n == 0) throw \"bottom\"; fail(n - 1); }
+        try { fail(3); } catch (e) { print \"caught \" + e; }
+        try { print 1 + nil; } catch (e) { print e; }
+        var f;
+        try {
+          try { throw \"inner\"; }
+          catch (e) { fun g() { return e; } f = g; throw \"again\"; }
+          finally { print \"cleanup\"; }
+        } catch (e) { print e + \" \" + f(); }
+        for (var i = 0; i < 3; i = i + 1) {
+          try { if (i == 1) continue; if (i == 2) break; print i; } catch (e) {}
+        }
+        fun r() { try { return \"returned\"; } catch (e) {} }
+        print r();
+        throw \"uncaught\";
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let result =
+        VM::with_output(Default::default(), &mut stdout, &mut stderr).run_program(&program);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "caught bottom\n\
+         Operands must be two numbers or two strings.\n\
+         cleanup\n\
+         again inner\n\
+         0\n\
+         returned\n"
+    );
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "uncaught\n        throw \"uncaught\";\n        ^^^^^\n[line 16] in script\n"
+    );
+}
+
+#[test]
+fn exits_run_finally_blocks_on_the_way_out() {
+    let source = "
+        fun f() { try { return \"try\"; } finally { print \"finally f\"; } }
+        print f();
+        fun g() {
+          try { try { return 1; } finally { print \"inner\"; } }
+          finally { print \"outer\"; }
+        }
+        print g();
+        fun h() {
+          try { throw \"x\"; }
+          catch (e) { return \"caught \" + e; }
+          finally { print \"finally h\"; }
+        }
+        print h();
+        fun replaced() { try { return 1; } finally { return 2; } }
+        print replaced();
+        for (var i = 0; i < 4; i = i + 1) {
+          var j = i * 10;
+          try {
+            var k = j + 1;
+            if (i == 1) continue;
+            if (i == 2) break;
+            print k;
+          } finally { print j; }
+        }
+        var keep;
+        fun captured() {
+          try {
+            var k = \"kept\";
+            fun c() { return k; }
+            keep = c;
+            return \"captured\";
+          } finally { print \"finally captured\"; }
+        }
+        print captured();
+        print keep();
+        while (true) {
+          try { try { break; } finally { print \"a\"; } } finally { print \"b\"; }
+        }
+        print \"out\";
+    ";
+    let program = compiler::compile(source, None).unwrap();
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "finally f\ntry\n\
+         inner\nouter\n1\n\
+         finally h\ncaught x\n\
+         2\n\
+         1\n0\n10\n20\n\
+         finally captured\ncaptured\nkept\n\
+         a\nb\nout\n"
+    );
+}
+
+#[test]
+fn imported_modules_are_cached_too() {
+    let dir = std::env::temp_dir().join(format!("rlox-module-cache-test-{}", std::process::id()));
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("lib.lox"), "var answer = 42;").unwrap();
+    let source = "import lib from \"lib.lox\"; print lib.answer;";
+    let run = || {
+        let mut stdout = vec![];
+        let mut vm = VM::with_output(Default::default(), &mut stdout, io::sink());
+        vm.set_path(&dir.join("main.lox"));
+        vm.set_cache(&cache_dir);
+        let result = vm.interpret(source, None);
+        drop(vm);
+        (result, String::from_utf8(stdout).unwrap())
+    };
+
+    let first = run();
+    let cached = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("loxb".as_ref()))
+        .count();
+    let second = run();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(first, (InterpretResult::Ok, "42\n".to_string()));
+    assert_eq!(cached, 2);
+    assert_eq!(second, first);
+}
+
+#[test]
+fn programs_with_warnings_are_not_cached() {
+    let dir = std::env::temp_dir().join(format!("rlox-warning-cache-test-{}", std::process::id()));
+    let run = || {
+        let mut stderr = vec![];
+        let mut vm = VM::with_output(Default::default(), io::sink(), &mut stderr);
+        vm.set_path(&dir.join("main.lox"));
+        vm.set_cache(&dir);
+        let result = vm.interpret("var clock = 1;", None);
+        drop(vm);
+        (result, String::from_utf8(stderr).unwrap())
+    };
+
+    let first = run();
+    let second = run();
+    let cached = dir.exists();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(first
+        .1
+        .contains("Global 'clock' shadows a native function."));
+    assert_eq!(second, first);
+    assert!(!cached);
+}
+
+#[test]
+fn imports_run_each_module_once() {
+    let dir = std::env::temp_dir().join(format!("rlox-import-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    let write = |name: &str, source: &str| std::fs::write(dir.join(name), source).unwrap();
+    write(
+        "lib/shapes.lox",
+        "print \"loading\"; var sides = 4; fun area(x) { return x * x; }",
+    );
+    write("lib/a.lox", "import \"b.lox\";");
+    write("lib/b.lox", "import \"a.lox\";");
+    write(
+        "main.lox",
+        "
+        import shapes from \"lib/shapes.lox\";
+        import \"lib/shapes.lox\";
+        print shapes.area(sides);
+        try { import \"lib/missing.lox\"; } catch (e) { print e; }
+        import \"lib/a.lox\";
+        ",
+    );
+
+    let source = std::fs::read_to_string(dir.join("main.lox")).unwrap();
+    let program = compiler::compile(&source, None).unwrap();
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), &mut stdout, &mut stderr);
+    vm.set_path(&dir.join("main.lox"));
+    let result = vm.run_program(&program);
+    drop(vm);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert_eq!(
+        String::from_utf8(stdout).unwrap(),
+        "loading\n16\nCould not find module \"lib/missing.lox\".\n"
+    );
+    let stderr = String::from_utf8(stderr).unwrap();
+    assert!(
+        stderr.starts_with("Circular import of \"a.lox\".\n"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn errors_quote_the_source_unless_matching_clox() {
+    let source = "print 1 +;\nprint -nil;";
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(Default::default(), io::sink(), &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::CompileError);
+    assert_eq!(
+        vm.interpret("print -nil;", None),
+        InterpretResult::RuntimeError
+    );
+    drop(vm);
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "[line 1] Error at ';': Expect expression.\n\
+         print 1 +;\n         ^\n\
+         Operand must be a number.\n\
+         print -nil;\n      ^\n\
+         [line 1] in script\n"
+    );
+
+    let config = Config {
+        clox_compat: true,
+        ..Default::default()
+    };
+    let mut stderr = vec![];
+    let mut vm = VM::with_output(config, io::sink(), &mut stderr);
+    assert_eq!(vm.interpret(source, None), InterpretResult::CompileError);
+    assert_eq!(
+        vm.interpret("print -nil;", None),
+        InterpretResult::RuntimeError
+    );
+    drop(vm);
+    assert_eq!(
+        String::from_utf8(stderr).unwrap(),
+        "[line 1] Error at ';': Expect expression.\n\
+         Operand must be a number.\n\
+         [line 1] in script\n"
+    );
+}
+
+#[test]
+fn interpret_errors_can_be_inspected() {
+    let Err(vm::InterpretError::Compile(diagnostics)) =
+        vm::interpret("var a = 1;\nprint a +;", None, Default::default())
+    else {
+        panic!("Expected a compile error");
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Expect expression.");
+
+    let source = "fun f() {\n  return -nil;\n}\nf();";
+    let Err(vm::InterpretError::Runtime(error)) = vm::interpret(source, None, Default::default())
+    else {
+        panic!("Expected a runtime error");
+    };
+    assert_eq!(error.message, "Operand must be a number.");
+    let trace: Vec<_> = error
+        .trace
+        .iter()
+        .map(|f| (f.function.as_str(), f.line))
+        .collect();
+    assert_eq!(trace, [("f()", Some(2)), ("script", Some(4))]);
+    assert_eq!(error.snippet.as_deref(), Some("  return -nil;\n         ^"));
+    assert_eq!(
+        error.to_string(),
+        "Operand must be a number.\n  return -nil;\n         ^\n[line 2] in f()\n[line 4] in script\n"
+    );
+
+    assert!(vm::interpret("print 1;", None, Default::default()).is_ok());
+    let (result, diagnostics) =
+        vm::interpret_with_diagnostics("var clock = 1;", None, Default::default());
+    assert!(result.is_ok());
+    assert_eq!(diagnostics[0].severity, Severity::Warning);
+}
+
+#[test]
+fn programs_given_on_the_command_line_report_their_errors() {
+    let run = |source: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_rlox"))
+            .args(["-e", source])
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stderr).unwrap(),
+        )
+    };
+    assert_eq!(
+        run("print 1 +;"),
+        (
+            Some(65),
+            "[line 1] Error at ';': Expect expression.\nprint 1 +;\n         ^\n".to_string()
+        )
+    );
+    assert_eq!(
+        run("print -nil;"),
+        (
+            Some(70),
+            "Operand must be a number.\nprint -nil;\n      ^\n[line 1] in script\n".to_string()
+        )
+    );
+}
+
+#[test]
+fn runtime_errors_point_at_the_source_they_came_from() {
+    let source = "fun f(x) {\n  return x + nil;\n}\nf(1);\nf();";
+    fn spans(source: &str) -> Vec<&str> {
+        let program = compiler::compile(source, None).unwrap();
+        let mut vm = VM::with_output(Default::default(), io::sink(), io::sink());
+        assert_eq!(vm.run_program(&program), InterpretResult::RuntimeError);
+        let error = vm.take_error().unwrap();
+        let spans = error.trace.into_iter().map(|f| f.span.unwrap());
+        spans.map(|span| &source[span]).collect()
+    }
+    assert_eq!(spans(source), ["+", "(1)"]);
+
+    let source = "fun f(x) {}\nf();";
+    assert_eq!(spans(source), ["()"]);
+
+    // Compiled bytecode keeps them as debug information
+    let program = compiler::compile(source, None).unwrap();
+    let bytes = rlox::bytecode::write(program.chunk(), Some("test.lox"));
+    let (chunk, _) = rlox::bytecode::read(&bytes).unwrap();
+    let spans_of = |chunk: &Chunk| {
+        (0..chunk.code.len())
+            .map(|i| chunk.get_span(i))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(spans_of(&chunk), spans_of(program.chunk()));
+}
+
+#[test]
+fn names_past_the_first_256_constants_use_long_forms() {
+    let literals: String = (0..300).map(|i| format!("print {i}.5;")).collect();
+    let source = format!(
+        "var x = 1; {literals}
+        fun f() {{ return x; }}
+        class C {{ m() {{ return this.v; }} }}
+        var c = C();
+        c.v = 2;
+        x = 3;
+        print x; print f(); print c.m();"
+    );
+    let program = compiler::compile(&source, None).unwrap();
+    assert!(program.chunk().verify().is_ok());
+    let listing = program.chunk().listing("script");
+    let long_forms = [
+        "DefineGlobalLong",
+        "GetGlobalLong",
+        "SetGlobalLong",
+        "SetPropertyLong",
+        "ClosureLong",
+        "ClassLong",
+        "MethodLong",
+    ];
+    for op_code in long_forms {
+        assert!(listing.contains(op_code), "{listing}");
+    }
+
+    let mut stdout = vec![];
+    let result = VM::with_output(Default::default(), &mut stdout, io::sink()).run_program(&program);
+    assert_eq!(result, InterpretResult::Ok);
+    let expected: String = (0..300).map(|i| format!("{i}.5\n")).collect();
+    assert_eq!(String::from_utf8(stdout).unwrap(), expected + "3\n3\n2\n");
+}
@@ -0,0 +1,73 @@
+use rlox::{
+    highlight::highlight,
+    scanner::{Scanner, TokenType, KEYWORDS},
+};
+
+#[test]
+fn tokens_carry_their_spans() {
+    let source = "print \"hi\"; // done\nx";
+    let tokens: Vec<_> = Scanner::new(source).collect();
+    let spans: Vec<_> = tokens
+        .iter()
+        .map(|t| (t.ty, &source[t.span.clone()], t.line, t.column))
+        .collect();
+    assert_eq!(
+        spans,
+        [
+            (TokenType::Print, "print", 1, 1),
+            (TokenType::String, "\"hi\"", 1, 7),
+            (TokenType::Semicolon, ";", 1, 11),
+            (TokenType::Identifier, "x", 2, 1),
+            (TokenType::Eof, "", 2, 2),
+        ]
+    );
+}
+
+#[test]
+fn errors_span_the_unscanned_text() {
+    let source = "1 @ \"open";
+    let errors: Vec<_> = Scanner::new(source)
+        .filter(|t| t.ty == TokenType::Error)
+        .map(|t| (t.str, &source[t.span]))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            ("Unexpected character.", "@"),
+            ("Unterminated string", "\"open"),
+        ]
+    );
+}
+
+#[test]
+fn columns_count_characters_rather_than_bytes() {
+    let columns: Vec<_> = Scanner::new("\"é\" x\n  y").map(|t| t.column).collect();
+    assert_eq!(columns, [1, 5, 3, 4]);
+}
+
+#[test]
+fn keywords_are_listed() {
+    for keyword in KEYWORDS {
+        let token = Scanner::new(keyword).next().unwrap();
+        assert_ne!(token.ty, TokenType::Identifier, "{keyword}");
+    }
+    assert!(KEYWORDS.windows(2).all(|w| w[0] < w[1]));
+    // `from` is only special in an import, so it can still be a name
+    assert!(!KEYWORDS.contains(&"from"));
+}
+
+#[test]
+fn highlight_marks_keywords_strings_numbers_and_comments() {
+    let source = "// a <comment>\nvar x = \"a&b\" + 1.5;\n  print x; // done";
+    assert_eq!(
+        highlight(source),
+        "<pre class=\"lox\"><code>\
+         <span class=\"lox-comment\">// a &lt;comment&gt;</span>\n\
+         <span class=\"lox-keyword\">var</span> x = \
+         <span class=\"lox-string\">&quot;a&amp;b&quot;</span> + \
+         <span class=\"lox-number\">1.5</span>;\n  \
+         <span class=\"lox-keyword\">print</span> x; \
+         <span class=\"lox-comment\">// done</span>\
+         </code></pre>\n"
+    );
+}
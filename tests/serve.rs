@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use rlox::{
+    compiler::Severity,
+    serve::{self, Request, Response},
+    vm::InterpretResult,
+};
+
+fn responses(requests: &[Vec<u8>]) -> Vec<Response> {
+    let mut input = vec![];
+    for request in requests {
+        serve::write_frame(&mut input, request).unwrap();
+    }
+    let mut output = vec![];
+    serve::serve(&input[..], &mut output).unwrap();
+
+    let mut output = &output[..];
+    let mut responses = vec![];
+    while let Some(frame) = serve::read_frame(&mut output).unwrap() {
+        responses.push(Response::decode(frame).unwrap());
+    }
+    responses
+}
+
+#[test]
+fn each_request_gets_a_response_from_a_fresh_vm() {
+    let request = |source: &str| Request {
+        source: source.to_string(),
+        ..Default::default()
+    };
+    let responses = responses(&[
+        request("var a = 1; print a;").encode(),
+        request("print a;").encode(),
+        request("if (true) print 1 +;").encode(),
+        vec![0],
+        Request {
+            source: "print 1000000 * 1000000; while (true) {}".to_string(),
+            clox_compat: true,
+            timeout: Some(Duration::from_millis(10)),
+        }
+        .encode(),
+    ]);
+
+    assert_eq!(responses.len(), 5);
+    assert_eq!(responses[0].result, InterpretResult::Ok);
+    assert_eq!(responses[0].stdout, "1\n");
+    assert_eq!(responses[1].result, InterpretResult::RuntimeError);
+    assert_eq!(
+        responses[1].stderr,
+        "Undefined variable 'a'.\nprint a;\n      ^\n[line 1] in script\n"
+    );
+    assert_eq!(responses[2].result, InterpretResult::CompileError);
+    assert_eq!(
+        responses[2].diagnostics,
+        [(
+            Severity::Error,
+            "[line 1] Error at ';': Expect expression.".to_string()
+        )]
+    );
+    assert_eq!(responses[3].result, InterpretResult::CompileError);
+    assert_eq!(responses[4].stdout, "1e+12\n");
+    assert!(responses[4].stderr.starts_with("Execution timed out."));
+}
@@ -0,0 +1,38 @@
+use std::{env, fs, process};
+
+use rlox::source::{self, InvalidUtf8, Options};
+
+#[test]
+fn invalid_utf8_is_located_and_decoded_lossily() {
+    assert_eq!(source::decode(b"print 1;".to_vec()).unwrap(), "print 1;");
+
+    let (position, lossy) =
+        source::decode(b"print 1;\nprint \"\xc3\xa9\xff\";".to_vec()).unwrap_err();
+    assert_eq!(
+        position,
+        InvalidUtf8 {
+            line: 2,
+            column: 9,
+            offset: 18,
+        }
+    );
+    assert_eq!(position.to_string(), "line 2, column 9 (byte 18)");
+    assert_eq!(lossy, "print 1;\nprint \"é\u{fffd}\";");
+}
+
+#[test]
+fn files_over_the_size_limit_are_refused() {
+    let path = env::temp_dir().join(format!("rlox-source-test-{}.lox", process::id()));
+    fs::write(&path, "print 1;").unwrap();
+    let options = |max_size| Options {
+        max_size,
+        ..Default::default()
+    };
+    assert_eq!(source::read(&path, options(8)).unwrap(), b"print 1;");
+    let error = source::read(&path, options(7)).unwrap_err();
+    assert!(error
+        .to_string()
+        .ends_with("is larger than the limit of 7 bytes."));
+    assert_eq!(source::read(&path, options(u64::MAX)).unwrap(), b"print 1;");
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,101 @@
+use std::io;
+
+use rlox::vm::{Config, InterpretResult, VM};
+
+// Runs `source` under `config`, returning how it finished and what it printed
+// to stdout and stderr
+fn run(config: Config, source: &str) -> (InterpretResult, String, String) {
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let result = VM::with_output(config, &mut stdout, &mut stderr).interpret(source, None);
+    (
+        result,
+        String::from_utf8(stdout).unwrap(),
+        String::from_utf8(stderr).unwrap(),
+    )
+}
+
+#[test]
+fn recursion_within_the_limits_runs() {
+    let source = "
+        fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+        print fib(6);
+    ";
+    let (result, stdout, _) = run(VM::stress_options(), source);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(stdout, "8\n");
+}
+
+#[test]
+fn unbounded_recursion_overflows_the_frames() {
+    let (result, _, stderr) = run(VM::stress_options(), "fun f() { f(); }\nf();");
+    assert_eq!(result, InterpretResult::RuntimeError);
+    let mut lines = stderr.lines();
+    assert_eq!(lines.next(), Some("Stack overflow."));
+    assert_eq!(lines.filter(|l| *l == "[line 1] in f()").count(), 7);
+}
+
+#[test]
+fn frame_limits_can_be_forced() {
+    let config = Config {
+        max_frames: 3,
+        ..Default::default()
+    };
+    let source = "fun depth(n) { if (n > 1) depth(n - 1); } depth(2);";
+    assert_eq!(run(config, source).0, InterpretResult::Ok);
+    let source = "fun depth(n) { if (n > 1) depth(n - 1); } depth(3);";
+    assert_eq!(run(config, source).0, InterpretResult::RuntimeError);
+}
+
+#[test]
+fn too_many_values_overflow_a_tiny_stack() {
+    let locals: String = (0..64).map(|i| format!("var a{i} = {i};")).collect();
+    let (result, _, stderr) = run(VM::stress_options(), &format!("{{ {locals} }}"));
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert!(stderr.starts_with("Stack overflow.\n"));
+
+    let args = vec!["1"; 70].join(", ");
+    let source = format!("fun f() {{}} print f({args});");
+    let (result, _, stderr) = run(VM::stress_options(), &source);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert!(stderr.starts_with("Stack overflow.\n"));
+}
+
+#[test]
+fn cycles_made_while_recursing_are_collected() {
+    let source = "
+        class Node {}
+        fun chain(n) {
+          var node = Node();
+          node.self = node;
+          if (n > 0) node.next = chain(n - 1);
+          return node;
+        }
+        for (var i = 0; i < 20; i = i + 1) chain(5);
+        var kept = chain(5);
+        print kept.next.next.self == kept.next.next;
+    ";
+    let mut stdout = vec![];
+    let mut vm = VM::with_output(VM::stress_options(), &mut stdout, io::sink());
+    assert_eq!(vm.interpret(source, None), InterpretResult::Ok);
+    assert_eq!(vm.collect_garbage(), 0);
+    drop(vm);
+    assert_eq!(stdout, b"true\n");
+}
+
+#[test]
+fn the_stack_grows_up_to_its_configured_limit() {
+    let source =
+        "fun depth(n) { if (n == 0) return 0; return depth(n - 1) + 1; } print depth(5000);";
+    let (result, _, stderr) = run(Config::default(), source);
+    assert_eq!(result, InterpretResult::RuntimeError);
+    assert!(stderr.starts_with("Stack overflow.\n"));
+
+    let config = Config {
+        max_frames: 10_000,
+        max_stack: 100_000,
+        ..Default::default()
+    };
+    let (result, stdout, _) = run(config, source);
+    assert_eq!(result, InterpretResult::Ok);
+    assert_eq!(stdout, "5000\n");
+}
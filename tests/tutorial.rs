@@ -0,0 +1,34 @@
+use std::io;
+
+use rlox::tutorial::{self, Outcome};
+
+#[test]
+fn every_lesson_solution_is_correct() {
+    for lesson in tutorial::lessons() {
+        assert!(
+            !lesson.expected.is_empty(),
+            "{} expects no output",
+            lesson.title
+        );
+        let outcome = lesson.check(&lesson.solution, io::sink());
+        assert!(
+            matches!(outcome, Outcome::Correct),
+            "{} failed",
+            lesson.title
+        );
+    }
+}
+
+#[test]
+fn answers_must_print_the_expected_output() {
+    let lesson = &tutorial::lessons()[0];
+    assert_eq!(lesson.title, "Printing values");
+    let mut stdout = vec![];
+    let outcome = lesson.check("print 3; print \"Hello, Lox\";", &mut stdout);
+    assert!(matches!(outcome, Outcome::Incorrect));
+    assert_eq!(stdout, b"3\nHello, Lox\n");
+    assert!(matches!(
+        lesson.check("print -nil;", io::sink()),
+        Outcome::Failed
+    ));
+}